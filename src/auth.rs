@@ -1,26 +1,23 @@
-use crate::models::Session;
-use crate::db::DbPool;
+use crate::db::{self, DbPool};
+use crate::error::AppError;
+use crate::state::AppState;
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
     middleware::Next,
     response::Response,
 };
 use rand::Rng;
-use sqlx::Row;
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
 
-const TOKEN_LENGTH: usize = 32;
+// 256 bits of randomness, encoded as 43 URL-safe base64 characters
+const TOKEN_BYTES: usize = 32;
 
 pub fn generate_token() -> String {
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
-    let mut rng = rand::thread_rng();
-
-    (0..TOKEN_LENGTH)
-        .map(|_| {
-            let idx = rng.gen_range(0..CHARSET.len());
-            CHARSET[idx] as char
-        })
-        .collect()
+    use base64::Engine;
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
 }
 
 pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
@@ -31,38 +28,200 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::Bcryp
     bcrypt::verify(password, hash)
 }
 
-pub async fn get_user_id_from_token(pool: &DbPool, token: &str) -> Result<i64, sqlx::Error> {
-    let row = sqlx::query("SELECT user_id FROM sessions WHERE token = ?")
-        .bind(token)
+// Verified against when a login names an unknown user, so the response takes
+// as long as a wrong password and doesn't reveal which usernames exist
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("not a real password").unwrap_or_default());
+
+pub fn burn_password_check(password: &str) {
+    let _ = verify_password(password, &DUMMY_PASSWORD_HASH);
+}
+
+// SHA-256 hex digest used to store bearer secrets (session tokens, login
+// challenges) so a database leak doesn't hand out live credentials. Tokens
+// carry enough entropy that an unkeyed hash is sufficient.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Start a new session for `user_id`, returning its bearer token.
+pub async fn create_session<'e>(
+    executor: impl Into<db::Executor<'e>>,
+    user_id: i64,
+) -> Result<String, sqlx::Error> {
+    let token = generate_token();
+
+    db::query("INSERT INTO sessions (user_id, token_hash, created_at) VALUES (?, ?, ?)")
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(chrono::Utc::now())
+        .execute(executor)
+        .await?;
+
+    Ok(token)
+}
+
+/// The session a request was authenticated with. The auth middleware inserts
+/// it as a request extension alongside the plain `i64` user id.
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentSession {
+    pub id: i64,
+    pub user_id: i64,
+}
+
+/// The session `token` belongs to; fails with `RowNotFound` if none does.
+pub async fn get_session_from_token(
+    pool: &DbPool,
+    token: &str,
+) -> Result<CurrentSession, sqlx::Error> {
+    let row = db::query("SELECT id, user_id FROM sessions WHERE token_hash = ?")
+        .bind(hash_token(token))
         .fetch_one(pool.as_ref())
         .await?;
 
-    Ok(row.get("user_id"))
+    Ok(CurrentSession {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+    })
 }
 
-// Middleware to validate authentication token
-pub async fn auth_middleware(
-    State(pool): State<DbPool>,
-    mut request: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    let auth_header = request
+/// Permissions a personal access token can be granted. Sessions implicitly
+/// hold every scope; tokens only reach routes that name one of theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    ReadMessages,
+    SendMessages,
+    ManageKeys,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::ReadMessages, Scope::SendMessages, Scope::ManageKeys];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadMessages => "messages:read",
+            Scope::SendMessages => "messages:send",
+            Scope::ManageKeys => "keys:manage",
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("unknown scope: {}", s))
+    }
+}
+
+// Personal access tokens carry a prefix so they can be told apart from
+// session tokens without a second lookup
+pub const API_TOKEN_PREFIX: &str = "mcp_";
+
+pub fn generate_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, generate_token())
+}
+
+/// The personal access token a request was authenticated with, if any.
+#[derive(Debug, Clone)]
+pub struct CurrentApiToken {
+    pub user_id: i64,
+    pub scopes: Vec<Scope>,
+}
+
+async fn get_api_token(pool: &DbPool, token: &str) -> Result<CurrentApiToken, sqlx::Error> {
+    let row =
+        db::query("SELECT id, user_id, scopes, expires_at FROM api_tokens WHERE token_hash = ?")
+            .bind(hash_token(token))
+            .fetch_one(pool.as_ref())
+            .await?;
+
+    let expires_at: Option<chrono::DateTime<chrono::Utc>> = row.get("expires_at");
+    let expired = expires_at.is_some_and(|t| t < chrono::Utc::now());
+    if expired {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    let id: i64 = row.get("id");
+    db::query("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")
+        .bind(chrono::Utc::now())
+        .bind(id)
+        .execute(pool.as_ref())
+        .await?;
+
+    let scopes: String = row.get("scopes");
+    Ok(CurrentApiToken {
+        user_id: row.get("user_id"),
+        scopes: scopes.split(',').filter_map(|s| s.parse().ok()).collect(),
+    })
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
         .headers()
         .get("Authorization")
-        .and_then(|value| value.to_str().ok());
+        .and_then(|value| value.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+}
 
-    let token = match auth_header {
-        Some(header) if header.starts_with("Bearer ") => {
-            &header[7..]
-        }
-        _ => return Err(StatusCode::UNAUTHORIZED),
+// Authenticates the request and inserts the user id plus either the
+// CurrentSession or CurrentApiToken. Tokens are only accepted when the route
+// allows a scope they hold.
+async fn authenticate(
+    state: &AppState,
+    scope: Option<Scope>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let invalid_token =
+        || AppError::unauthorized("invalid_token", "Missing or invalid bearer token");
+    let Some(token) = bearer_token(&request) else {
+        return Err(invalid_token());
     };
 
-    match get_user_id_from_token(&pool, token).await {
-        Ok(user_id) => {
-            request.extensions_mut().insert(user_id);
+    if token.starts_with(API_TOKEN_PREFIX) {
+        let api_token = get_api_token(&state.pool, token)
+            .await
+            .map_err(|_| invalid_token())?;
+        if !scope.is_some_and(|scope| api_token.scopes.contains(&scope)) {
+            return Err(AppError::forbidden(
+                "insufficient_scope",
+                "This token can't be used for this request",
+            ));
+        }
+        request.extensions_mut().insert(api_token.user_id);
+        request.extensions_mut().insert(api_token);
+        return Ok(next.run(request).await);
+    }
+
+    match state.store.find_session(token).await {
+        Ok(Some(session)) => {
+            request.extensions_mut().insert(session.user_id);
+            request.extensions_mut().insert(session);
             Ok(next.run(request).await)
         }
-        Err(_) => Err(StatusCode::UNAUTHORIZED),
+        Ok(None) => Err(invalid_token()),
+        Err(e) => Err(e.into()),
     }
 }
+
+// Middleware to validate authentication token; only sessions are accepted
+pub async fn auth_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    authenticate(&state, None, request, next).await
+}
+
+// Middleware for routes personal access tokens may use when granted `scope`
+pub async fn scoped_auth_middleware(
+    State((state, scope)): State<(AppState, Scope)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    authenticate(&state, Some(scope), request, next).await
+}
@@ -0,0 +1,73 @@
+use image::{imageops::FilterType, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+// Upload limits, checked before and during decoding so a small compressed
+// file can't expand into a huge allocation.
+pub const MAX_AVATAR_BYTES: usize = 1024 * 1024;
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+
+// Stored sizes; both are square PNGs cropped to fill.
+pub const AVATAR_SIZE: u32 = 256;
+pub const THUMBNAIL_SIZE: u32 = 64;
+
+pub struct ProcessedAvatar {
+    pub image: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum AvatarError {
+    TooLarge,
+    InvalidImage(String),
+}
+
+impl std::fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AvatarError::TooLarge => write!(
+                f,
+                "Avatar must be at most {} bytes and {}x{} pixels",
+                MAX_AVATAR_BYTES, MAX_AVATAR_DIMENSION, MAX_AVATAR_DIMENSION
+            ),
+            AvatarError::InvalidImage(e) => write!(f, "Invalid avatar image: {}", e),
+        }
+    }
+}
+
+/// Decode an uploaded image and re-encode it as a PNG avatar plus thumbnail.
+/// Re-encoding also drops any metadata (EXIF, GPS) the original carried.
+pub fn process_avatar(bytes: &[u8]) -> Result<ProcessedAvatar, AvatarError> {
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(AvatarError::TooLarge);
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| AvatarError::InvalidImage(e.to_string()))?;
+    reader.limits(limits);
+
+    let decoded = reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(_) => AvatarError::TooLarge,
+        e => AvatarError::InvalidImage(e.to_string()),
+    })?;
+
+    let image = decoded.resize_to_fill(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+    let thumbnail = image.resize_to_fill(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Lanczos3);
+
+    Ok(ProcessedAvatar {
+        image: encode_png(&image)?,
+        thumbnail: encode_png(&thumbnail)?,
+    })
+}
+
+fn encode_png(image: &image::DynamicImage) -> Result<Vec<u8>, AvatarError> {
+    let mut buf = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+        .map_err(|e| AvatarError::InvalidImage(e.to_string()))?;
+    Ok(buf)
+}
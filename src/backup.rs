@@ -0,0 +1,453 @@
+// Backups of the SQLite database: snapshots taken on a schedule or with
+// `migchat-server backup`, and `migchat-server restore` to bring one back.
+// Snapshots use VACUUM INTO, which copies a consistent view of the database
+// from a read transaction, so the server keeps serving while one is taken.
+// PostgreSQL deployments are backed up with the database's own tools.
+use crate::config::Config;
+use crate::db::{self, Db, DbPool};
+use crate::shutdown::Shutdown;
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+type BackupError = Box<dyn std::error::Error + Send + Sync>;
+
+const SNAPSHOT_PREFIX: &str = "migchat-";
+const USAGE: &str =
+    "usage: migchat-server [backup [--compress | --no-compress] [DIR] | restore FILE]";
+
+/// Directory snapshots are written to: BACKUP_DIR, or `backups` in the data
+/// directory.
+pub fn backups_dir(config: &Config) -> PathBuf {
+    match &config.backup_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => db::data_dir().join("backups"),
+    }
+}
+
+/// Run the `backup` or `restore` command in `args` instead of the server,
+/// returning the process exit code.
+pub async fn run_command(args: &[String]) -> i32 {
+    let config = Config::from_env();
+    let result = match args {
+        [command, options @ ..] if command == "backup" => {
+            let mut compress = config.backup_compress;
+            let mut dir = backups_dir(&config);
+            for option in options {
+                match option.as_str() {
+                    "--compress" => compress = true,
+                    "--no-compress" => compress = false,
+                    flag if flag.starts_with('-') => return usage(),
+                    path => dir = PathBuf::from(path),
+                }
+            }
+            backup_command(&dir, compress).await
+        }
+        [command, file] if command == "restore" => restore_command(Path::new(file)).await,
+        _ => return usage(),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    2
+}
+
+async fn backup_command(dir: &Path, compress: bool) -> Result<(), BackupError> {
+    // Opened without migrating or applying a staged restore, since the
+    // server may be running on the same file
+    let options = sqlite_options()?.create_if_missing(false);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    let path = snapshot(&pool, dir, compress).await;
+    pool.close().await;
+
+    println!("Backed up database to {}", path?.display());
+    Ok(())
+}
+
+async fn restore_command(backup: &Path) -> Result<(), BackupError> {
+    let options = sqlite_options()?;
+    let target = options.get_filename();
+    let staged = stage_restore(backup, target).await?;
+
+    println!(
+        "Backup is valid and staged as {}. It replaces {} the next time the server starts; \
+         the current database is kept as {}.",
+        staged.display(),
+        target.display(),
+        with_suffix(target, ".pre-restore").display()
+    );
+    Ok(())
+}
+
+fn sqlite_options() -> Result<SqliteConnectOptions, BackupError> {
+    db::sqlite_options()?.ok_or_else(|| {
+        "Backups only cover SQLite; use pg_dump and pg_restore for PostgreSQL".into()
+    })
+}
+
+/// Start the task that snapshots the database every BACKUP_INTERVAL_HOURS
+/// and keeps the newest BACKUP_KEEP.
+pub fn spawn(pool: DbPool, config: Arc<Config>, shutdown: Shutdown) {
+    let Db::Sqlite(sqlite) = pool.as_ref() else {
+        return;
+    };
+    if config.backup_interval_hours == 0 {
+        return;
+    }
+
+    let sqlite = sqlite.clone();
+    let period = Duration::from_secs(config.backup_interval_hours * 3600);
+    shutdown.clone().spawn(async move {
+        let dir = backups_dir(&config);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.stopping() => return,
+            }
+
+            match snapshot(&sqlite, &dir, config.backup_compress).await {
+                Ok(path) => tracing::info!("Backed up database to {}", path.display()),
+                Err(e) => {
+                    tracing::error!("Failed to back up database: {}", e);
+                    continue;
+                }
+            }
+            if let Err(e) = prune(&dir, config.backup_keep).await {
+                tracing::error!("Failed to remove old backups: {}", e);
+            }
+        }
+    });
+}
+
+/// Write a snapshot of the database to `dir`, gzipped if `compress` is set.
+/// The file only appears under its final name once complete.
+pub async fn snapshot(
+    pool: &SqlitePool,
+    dir: &Path,
+    compress: bool,
+) -> Result<PathBuf, BackupError> {
+    tokio::fs::create_dir_all(dir).await?;
+    // Timestamped names sort oldest first
+    let name = format!(
+        "{}{}.db",
+        SNAPSHOT_PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    let partial = dir.join(format!("{}.partial", name));
+    let _ = tokio::fs::remove_file(&partial).await;
+
+    let result = async {
+        sqlx::query("VACUUM INTO ?")
+            .bind(partial.to_string_lossy().to_string())
+            .execute(pool)
+            .await?;
+
+        if !compress {
+            let path = dir.join(&name);
+            tokio::fs::rename(&partial, &path).await?;
+            return Ok(path);
+        }
+
+        let path = dir.join(format!("{}.gz", name));
+        let compressed = dir.join(format!("{}.gz.partial", name));
+        let (source, destination) = (partial.clone(), compressed.clone());
+        let written = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            let mut input = std::fs::File::open(source)?;
+            let mut output =
+                GzEncoder::new(std::fs::File::create(destination)?, Compression::default());
+            std::io::copy(&mut input, &mut output)?;
+            output.finish()?.sync_all()
+        })
+        .await?;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&compressed).await;
+            return Err(e.into());
+        }
+        tokio::fs::remove_file(&partial).await?;
+        tokio::fs::rename(&compressed, &path).await?;
+        Ok::<_, BackupError>(path)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    result
+}
+
+/// Delete all but the newest `keep` snapshots in `dir`. Keeping 0 keeps
+/// them all.
+pub async fn prune(dir: &Path, keep: usize) -> Result<(), BackupError> {
+    if keep == 0 {
+        return Ok(());
+    }
+
+    let mut snapshots = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(SNAPSHOT_PREFIX) && (name.ends_with(".db") || name.ends_with(".db.gz"))
+        {
+            snapshots.push(entry.path());
+        }
+    }
+    snapshots.sort();
+
+    let excess = snapshots.len().saturating_sub(keep);
+    for path in &snapshots[..excess] {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+/// Check `backup` and stage it to replace the database at `target` the next
+/// time the server starts. Backups that fail an integrity check or carry a
+/// schema version this build doesn't know are refused.
+pub async fn stage_restore(backup: &Path, target: &Path) -> Result<PathBuf, BackupError> {
+    let staged = with_suffix(target, ".restore");
+    let partial = with_suffix(target, ".restore.partial");
+
+    let (source, destination) = (backup.to_path_buf(), partial.clone());
+    tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+        let mut input = std::fs::File::open(&source)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", source.display(), e)))?;
+        let mut output = std::fs::File::create(destination)?;
+        if source
+            .extension()
+            .is_some_and(|extension| extension == "gz")
+        {
+            std::io::copy(&mut GzDecoder::new(input), &mut output)?;
+        } else {
+            std::io::copy(&mut input, &mut output)?;
+        }
+        output.sync_all()
+    })
+    .await??;
+
+    if let Err(e) = validate(&partial).await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&partial, &staged).await?;
+    Ok(staged)
+}
+
+async fn validate(path: &Path) -> Result<(), BackupError> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(false);
+    let backup = Db::Sqlite(
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?,
+    );
+
+    let result = async {
+        let check: String = db::query("PRAGMA quick_check")
+            .fetch_one(&backup)
+            .await
+            .map_err(|e| format!("Not a SQLite database: {}", e))?
+            .get(0);
+        if check != "ok" {
+            return Err(format!("Backup is corrupt: {}", check).into());
+        }
+
+        let version = db::schema_version(&backup).await?;
+        if version < 1 {
+            return Err("Backup is not a MigChat database".into());
+        }
+        if version > db::SCHEMA_VERSION {
+            return Err(format!(
+                "Backup has schema version {}, newer than the {} this server supports",
+                version,
+                db::SCHEMA_VERSION
+            )
+            .into());
+        }
+        Ok::<_, BackupError>(())
+    }
+    .await;
+
+    backup.close().await;
+    result
+}
+
+/// Swap in a backup staged by `stage_restore`, keeping the database it
+/// replaces (with its write-ahead log) beside it as `.pre-restore`. Does
+/// nothing when no restore is staged.
+pub fn apply_pending_restore(target: &Path) -> std::io::Result<()> {
+    let staged = with_suffix(target, ".restore");
+    if !staged.exists() {
+        return Ok(());
+    }
+
+    let kept = with_suffix(target, ".pre-restore");
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(with_suffix(&kept, suffix));
+        let current = with_suffix(target, suffix);
+        if current.exists() {
+            std::fs::rename(current, with_suffix(&kept, suffix))?;
+        }
+    }
+    std::fs::rename(&staged, target)?;
+
+    eprintln!(
+        "Restored database from backup; the previous one is kept as {}",
+        kept.display()
+    );
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory under the system temp dir, removed by the caller
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("migchat-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn open(path: &Path) -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn snapshots_restore_over_the_database() {
+        for compress in [false, true] {
+            let dir = temp_dir();
+            let target = dir.join("migchat.db");
+            let live = open(&target).await;
+            sqlx::query("PRAGMA journal_mode = WAL")
+                .execute(&live)
+                .await
+                .unwrap();
+            db::migrate(&Db::Sqlite(live.clone())).await.unwrap();
+            let insert = "INSERT INTO users (username, password_hash) VALUES (?, 'x')";
+            sqlx::query(insert)
+                .bind("alice")
+                .execute(&live)
+                .await
+                .unwrap();
+
+            let backup = snapshot(&live, &dir.join("backups"), compress)
+                .await
+                .unwrap();
+            assert_eq!(backup.extension().unwrap() == "gz", compress);
+
+            // The live database moves on after the snapshot
+            sqlx::query(insert)
+                .bind("bob")
+                .execute(&live)
+                .await
+                .unwrap();
+            live.close().await;
+
+            stage_restore(&backup, &target).await.unwrap();
+            apply_pending_restore(&target).unwrap();
+
+            let restored = open(&target).await;
+            let usernames: Vec<String> = sqlx::query_scalar("SELECT username FROM users")
+                .fetch_all(&restored)
+                .await
+                .unwrap();
+            assert_eq!(usernames, ["alice"]);
+            restored.close().await;
+            assert!(with_suffix(&target, ".pre-restore").exists());
+            assert!(!with_suffix(&target, ".restore").exists());
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_refuses_newer_and_foreign_databases() {
+        let dir = temp_dir();
+        let target = dir.join("migchat.db");
+
+        let newer = dir.join("newer.db");
+        let pool = open(&newer).await;
+        sqlx::query(&format!("PRAGMA user_version = {}", db::SCHEMA_VERSION + 1))
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        let error = stage_restore(&newer, &target).await.unwrap_err();
+        assert!(error.to_string().contains("newer"), "{}", error);
+
+        let foreign = dir.join("foreign.db");
+        std::fs::write(&foreign, "not a database").unwrap();
+        assert!(stage_restore(&foreign, &target).await.is_err());
+
+        // Nothing was staged, so starting up leaves the database alone
+        assert!(!with_suffix(&target, ".restore").exists());
+        assert!(!with_suffix(&target, ".restore.partial").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_the_newest_snapshots() {
+        let dir = temp_dir();
+        for name in [
+            "migchat-20240101T000000Z.db",
+            "migchat-20240102T000000Z.db.gz",
+            "migchat-20240103T000000Z.db",
+            "migchat-20240104T000000Z.db.partial",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+
+        prune(&dir, 2).await.unwrap();
+
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "migchat-20240102T000000Z.db.gz",
+                "migchat-20240103T000000Z.db",
+                "migchat-20240104T000000Z.db.partial",
+                "notes.txt"
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+// Outgoing webhooks for bot accounts: every message a bot receives is queued
+// for its webhook, if it has one, and sent signed and retried like any other
+// webhook delivery.
+use crate::db::{self, DbPool};
+use crate::events::{self, Event, EventHub};
+use crate::shutdown::Shutdown;
+use crate::webhooks::{self, DeliveryQueue};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Event of deliveries to a bot's webhook. A bot's webhook is the row in
+/// `webhooks` it owns with this as its only event.
+pub const EVENT_BOT_MESSAGE: &str = "bot.message";
+
+/// Point the bot's webhook at `url`, or remove it when `None`. Returns the
+/// new signing secret; deliveries still pending go to the new URL.
+pub async fn set_webhook(
+    conn: &mut db::Conn,
+    bot_id: i64,
+    url: Option<&str>,
+) -> Result<Option<String>, sqlx::Error> {
+    db::query("UPDATE users SET bot_webhook_url = ? WHERE id = ?")
+        .bind(url)
+        .bind(bot_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let Some(url) = url else {
+        db::query(
+            "DELETE FROM webhook_deliveries WHERE webhook_id IN (SELECT id FROM webhooks WHERE user_id = ? AND events = ?)",
+        )
+        .bind(bot_id)
+        .bind(EVENT_BOT_MESSAGE)
+        .execute(&mut *conn)
+        .await?;
+        db::query("DELETE FROM webhooks WHERE user_id = ? AND events = ?")
+            .bind(bot_id)
+            .bind(EVENT_BOT_MESSAGE)
+            .execute(&mut *conn)
+            .await?;
+        return Ok(None);
+    };
+
+    let secret = webhooks::generate_secret();
+    let updated =
+        db::query("UPDATE webhooks SET url = ?, secret = ? WHERE user_id = ? AND events = ?")
+            .bind(url)
+            .bind(&secret)
+            .bind(bot_id)
+            .bind(EVENT_BOT_MESSAGE)
+            .execute(&mut *conn)
+            .await?;
+    if updated.rows_affected() == 0 {
+        db::query(
+            "INSERT INTO webhooks (user_id, url, secret, events, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(bot_id)
+        .bind(url)
+        .bind(&secret)
+        .bind(EVENT_BOT_MESSAGE)
+        .bind(Utc::now())
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(Some(secret))
+}
+
+pub fn spawn_webhook_dispatcher(
+    pool: DbPool,
+    events: EventHub,
+    queue: DeliveryQueue,
+    shutdown: Shutdown,
+) {
+    let mut receiver = events.subscribe_local();
+
+    shutdown.clone().spawn(async move {
+        loop {
+            match events::next_event(&mut receiver, &shutdown).await {
+                Ok(Event::MessageCreated {
+                    message_id,
+                    to_user_id,
+                    ..
+                }) => match dispatch(&pool, message_id, to_user_id).await {
+                    Ok(true) => queue.wake(),
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to dispatch webhook for message {}: {}",
+                            message_id,
+                            e
+                        )
+                    }
+                },
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Bot webhook dispatcher skipped {} events", missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+// Queues the message for the recipient's webhook if it is a bot with one.
+// Returns whether a delivery was queued.
+async fn dispatch(pool: &DbPool, message_id: i64, to_user_id: i64) -> Result<bool, sqlx::Error> {
+    let webhook = db::query(
+        r#"
+        SELECT w.id
+        FROM webhooks w
+        JOIN users u ON u.id = w.user_id
+        WHERE w.user_id = ? AND w.events = ? AND u.is_bot = TRUE AND u.deleted_at IS NULL
+        "#,
+    )
+    .bind(to_user_id)
+    .bind(EVENT_BOT_MESSAGE)
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    let Some(webhook) = webhook else {
+        return Ok(false);
+    };
+
+    let row = db::query(
+        r#"
+        SELECT
+            m.id,
+            m.content,
+            m.encrypted,
+            m.via_integration,
+            m.created_at,
+            from_user.username as from_username,
+            from_user.display_name as from_display_name,
+            from_user.is_bot as from_is_bot,
+            to_user.username as to_username
+        FROM messages m
+        JOIN users from_user ON m.from_user_id = from_user.id
+        JOIN users to_user ON m.to_user_id = to_user.id
+        WHERE m.id = ?
+        "#,
+    )
+    .bind(message_id)
+    .fetch_one(pool.as_ref())
+    .await?;
+
+    let data = json!({
+        "message": {
+            "id": row.get::<i64, _>("id"),
+            "from_username": row.get::<String, _>("from_username"),
+            "from_display_name": row.get::<Option<String>, _>("from_display_name"),
+            "from_is_bot": row.get::<bool, _>("from_is_bot"),
+            "to_username": row.get::<String, _>("to_username"),
+            "content": row.get::<String, _>("content"),
+            "encrypted": row.get::<bool, _>("encrypted"),
+            "via_integration": row.get::<Option<String>, _>("via_integration"),
+            "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+        },
+    });
+    webhooks::queue_delivery(
+        pool,
+        webhook.get("id"),
+        EVENT_BOT_MESSAGE,
+        &data,
+        &Utc::now(),
+    )
+    .await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn insert_user(pool: &DbPool, username: &str, is_bot: bool) -> i64 {
+        db::query(
+            "INSERT INTO users (username, password_hash, is_bot, created_at) VALUES (?, 'x', ?, ?) RETURNING id",
+        )
+        .bind(username)
+        .bind(is_bot)
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id")
+    }
+
+    async fn send(pool: &DbPool, from: i64, to: i64) -> i64 {
+        db::query(
+            "INSERT INTO messages (from_user_id, to_user_id, content, created_at) VALUES (?, ?, 'deploy', ?) RETURNING id",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id")
+    }
+
+    #[tokio::test]
+    async fn messages_to_bots_are_queued_as_signed_deliveries() {
+        let pool = db::test_pool().await;
+        let alice = insert_user(&pool, "alice", false).await;
+        let bot = insert_user(&pool, "deploybot", true).await;
+
+        let mut conn = pool.acquire().await.unwrap();
+        let first = set_webhook(&mut conn, bot, Some("https://example.com/a"))
+            .await
+            .unwrap();
+        let second = set_webhook(&mut conn, bot, Some("https://example.com/b"))
+            .await
+            .unwrap();
+        drop(conn);
+        assert!(first.is_some() && second.is_some() && first != second);
+
+        let message_id = send(&pool, alice, bot).await;
+        assert!(dispatch(&pool, message_id, bot).await.unwrap());
+        // Only bots get deliveries
+        assert!(!dispatch(&pool, send(&pool, bot, alice).await, alice)
+            .await
+            .unwrap());
+
+        let rows = db::query(
+            "SELECT w.url, w.secret, d.event, d.payload FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id",
+        )
+        .fetch_all(pool.as_ref())
+        .await
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<String, _>("url"), "https://example.com/b");
+        assert_eq!(rows[0].get::<Option<String>, _>("secret"), second);
+        assert_eq!(rows[0].get::<String, _>("event"), EVENT_BOT_MESSAGE);
+        let payload: serde_json::Value =
+            serde_json::from_str(&rows[0].get::<String, _>("payload")).unwrap();
+        assert_eq!(payload["data"]["message"]["id"], message_id);
+        assert_eq!(payload["data"]["message"]["from_username"], "alice");
+        assert_eq!(payload["data"]["message"]["content"], "deploy");
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(set_webhook(&mut conn, bot, None).await.unwrap(), None);
+        drop(conn);
+        assert!(!dispatch(&pool, send(&pool, alice, bot).await, bot)
+            .await
+            .unwrap());
+        let remaining = db::query("SELECT COUNT(*) FROM webhook_deliveries")
+            .fetch_one(pool.as_ref())
+            .await
+            .unwrap()
+            .get::<i64, _>(0);
+        assert_eq!(remaining, 0);
+    }
+}
@@ -1,29 +1,124 @@
-use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+use sqlx::{
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Row as _, SqlitePool,
+};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 
-pub type DbPool = Arc<SqlitePool>;
+mod postgres;
+mod query;
 
-pub async fn init_db() -> Result<DbPool, sqlx::Error> {
-    // Use file-based SQLite for persistence across restarts
-    // Determine database path based on environment
-    let database_url = if std::path::Path::new("/data").exists() {
-        // Production: use /data mounted volume with create_if_missing option
-        "sqlite:/data/migchat.db?mode=rwc"
+pub use query::{query, Backend, Conn, Db, Executor, Row};
+
+pub type DbPool = Arc<Db>;
+
+/// Version of the schema `init_db` leaves behind, recorded in SQLite's
+/// `user_version` or Postgres's `schema_version` table. Bump it whenever a
+/// migration is added.
+pub const SCHEMA_VERSION: i64 = 5;
+
+pub fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|db_error| db_error.is_unique_violation())
+}
+
+/// Directory holding the database and other server-side files (backups).
+pub fn data_dir() -> PathBuf {
+    if Path::new("/data").exists() {
+        // Production: /data mounted volume
+        PathBuf::from("/data")
     } else {
-        // Local dev: use ./data directory
+        // Local dev: ./data directory
         std::fs::create_dir_all("./data").ok();
-        "sqlite:./data/migchat.db?mode=rwc"
+        PathBuf::from("./data")
+    }
+}
+
+/// Connect to the database named by `DATABASE_URL` and bring its schema up
+/// to date. `postgres://` and `postgresql://` URLs select PostgreSQL; anything
+/// else, or no URL at all, uses SQLite in the data directory.
+pub async fn init_db() -> Result<DbPool, sqlx::Error> {
+    let database_url = std::env::var("DATABASE_URL").ok();
+    let db = match database_url.as_deref() {
+        Some(url) if is_postgres_url(url) => {
+            // The URL may carry a password, so it isn't logged
+            eprintln!("Connecting to PostgreSQL database");
+            let pool = PgPoolOptions::new()
+                .max_connections(10)
+                .connect(url)
+                .await?;
+            Db::Postgres(pool)
+        }
+        url => Db::Sqlite(connect_sqlite(url).await?),
     };
 
+    eprintln!("Database connected successfully");
+
+    migrate(&db).await?;
+
+    Ok(Arc::new(db))
+}
+
+fn is_postgres_url(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+fn sqlite_url(database_url: Option<&str>) -> String {
+    // Use file-based SQLite for persistence across restarts,
+    // with create_if_missing so a fresh volume just works
+    database_url.map(str::to_string).unwrap_or_else(|| {
+        format!(
+            "sqlite:{}?mode=rwc",
+            data_dir().join("migchat.db").display()
+        )
+    })
+}
+
+/// The SQLite database the server runs on, or `None` when `DATABASE_URL`
+/// selects PostgreSQL.
+pub fn sqlite_options() -> Result<Option<SqliteConnectOptions>, sqlx::Error> {
+    match std::env::var("DATABASE_URL").ok().as_deref() {
+        Some(url) if is_postgres_url(url) => Ok(None),
+        url => SqliteConnectOptions::from_str(&sqlite_url(url)).map(Some),
+    }
+}
+
+async fn connect_sqlite(database_url: Option<&str>) -> Result<SqlitePool, sqlx::Error> {
+    let database_url = sqlite_url(database_url);
+
     eprintln!("Connecting to database: {}", database_url);
 
+    // A backup staged by `migchat-server restore` is swapped in before
+    // anything opens the database
+    let options = SqliteConnectOptions::from_str(&database_url)?;
+    crate::backup::apply_pending_restore(options.get_filename())?;
+
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(database_url)
+        .connect_with(options)
         .await?;
 
-    eprintln!("Database connected successfully");
+    // Write-ahead logging lets reads go ahead while a write is in progress.
+    // The mode is stored in the database file; `close` folds the log back in.
+    sqlx::query("PRAGMA journal_mode = WAL")
+        .execute(&pool)
+        .await?;
+
+    Ok(pool)
+}
+
+/// Bring the schema up to date. Safe to run on every start.
+pub async fn migrate(db: &Db) -> Result<(), sqlx::Error> {
+    match db {
+        Db::Sqlite(pool) => migrate_sqlite(pool).await,
+        Db::Postgres(pool) => postgres::migrate(pool).await,
+    }
+}
 
+// Create missing tables and indexes and apply column changes
+async fn migrate_sqlite(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     // Create tables
     sqlx::query(
         r#"
@@ -35,7 +130,7 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     sqlx::query(
@@ -43,15 +138,33 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
         CREATE TABLE IF NOT EXISTS sessions (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             user_id INTEGER NOT NULL,
-            token TEXT NOT NULL UNIQUE,
+            token_hash TEXT NOT NULL UNIQUE,
             created_at TEXT NOT NULL DEFAULT (datetime('now')),
             FOREIGN KEY (user_id) REFERENCES users(id)
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
+    // Session tokens are stored hashed: rename the old plaintext column and
+    // hash any tokens still in it (hex digests are 64 chars, old tokens 32)
+    sqlx::query("ALTER TABLE sessions RENAME COLUMN token TO token_hash")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column was already renamed
+
+    let plaintext = sqlx::query("SELECT id, token_hash FROM sessions WHERE length(token_hash) != 64")
+        .fetch_all(pool)
+        .await?;
+    for row in plaintext {
+        sqlx::query("UPDATE sessions SET token_hash = ? WHERE id = ?")
+            .bind(crate::auth::hash_token(row.get("token_hash")))
+            .bind(row.get::<i64, _>("id"))
+            .execute(pool)
+            .await?;
+    }
+
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS messages (
@@ -66,28 +179,52 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     // Add read_at column to existing tables (migration for existing databases)
     sqlx::query("ALTER TABLE messages ADD COLUMN read_at TEXT")
-        .execute(&pool)
+        .execute(pool)
         .await
         .ok(); // Ignore error if column already exists
 
     // Create indexes for better query performance
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_sessions_token ON sessions(token)")
-        .execute(&pool)
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_sessions_token ON sessions(token_hash)")
+        .execute(pool)
         .await?;
 
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_messages_to_user ON messages(to_user_id)")
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_messages_from_user ON messages(from_user_id)")
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
+    // Usernames are unique case-insensitively. This also serves NOCASE lookups.
+    // Databases from before this was enforced may hold case-only duplicates;
+    // those have to be renamed by hand, since either account could be the one
+    // other people know by that name.
+    let duplicates: Vec<String> = sqlx::query_scalar(
+        "SELECT group_concat(username, ', ') FROM users GROUP BY username COLLATE NOCASE HAVING COUNT(*) > 1",
+    )
+    .fetch_all(pool)
+    .await?;
+    if !duplicates.is_empty() {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "Usernames that differ only in case must be renamed before upgrading: {}",
+                duplicates.join("; ")
+            )
+            .into(),
+        ));
+    }
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_users_username_nocase ON users(username COLLATE NOCASE)",
+    )
+    .execute(pool)
+    .await?;
+
     // E2E Encryption: Create user_keys table
     sqlx::query(
         r#"
@@ -101,7 +238,7 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     // E2E Encryption: Create one_time_prekeys table
@@ -118,21 +255,548 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     // Create indexes for key tables
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_user_keys_user_id ON user_keys(user_id)")
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_one_time_prekeys_user_id ON one_time_prekeys(user_id)")
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_one_time_prekeys_used ON one_time_prekeys(used)")
-        .execute(&pool)
+        .execute(pool)
+        .await?;
+
+    // User profiles: add profile columns to existing users tables
+    sqlx::query("ALTER TABLE users ADD COLUMN display_name TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE users ADD COLUMN bio TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // User directory: accounts are discoverable in search unless they opt out
+    sqlx::query("ALTER TABLE users ADD COLUMN discoverable BOOLEAN NOT NULL DEFAULT TRUE")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Account deletion: deleted accounts stay as anonymized tombstones so
+    // messages referencing them remain valid
+    sqlx::query("ALTER TABLE users ADD COLUMN deleted_at TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Login lockout: consecutive failed attempts and the time the lock lifts
+    sqlx::query("ALTER TABLE users ADD COLUMN failed_login_count INTEGER NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE users ADD COLUMN locked_until TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Bot accounts: owned by a human user, authenticated only by API tokens,
+    // and optionally notified of incoming messages through a webhook
+    sqlx::query("ALTER TABLE users ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT FALSE")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE users ADD COLUMN bot_owner_id INTEGER REFERENCES users(id)")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE users ADD COLUMN bot_webhook_url TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_bot_owner_id ON users(bot_owner_id)")
+        .execute(pool)
         .await?;
 
-    Ok(Arc::new(pool))
+    // Avatars live in their own table so user lookups don't drag image blobs along
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_avatars (
+            user_id INTEGER PRIMARY KEY,
+            image BLOB NOT NULL,
+            thumbnail BLOB NOT NULL,
+            updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Username history: old names redirect to the renamed account for a while
+    // and are held back from other users to prevent squatting
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS username_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            old_username TEXT NOT NULL,
+            new_username TEXT NOT NULL,
+            changed_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_username_history_old_username ON username_history(old_username COLLATE NOCASE)")
+        .execute(pool)
+        .await?;
+
+    // Notifications: account events surfaced to other users (e.g. renames)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS notifications (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            read_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_notifications_user_id ON notifications(user_id)")
+        .execute(pool)
+        .await?;
+
+    // Two-factor authentication: TOTP secrets (enabled once confirmed),
+    // hashed recovery codes, and pending second-step login challenges
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_totp (
+            user_id INTEGER PRIMARY KEY,
+            secret TEXT NOT NULL,
+            enabled BOOLEAN NOT NULL DEFAULT FALSE,
+            last_used_step INTEGER,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            confirmed_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            code_hash TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            used_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_totp_recovery_codes_user_id ON totp_recovery_codes(user_id)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS login_challenges (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Personal access tokens: long-lived, scoped credentials for bots and
+    // integrations. Stored hashed like session tokens.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS api_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            description TEXT NOT NULL,
+            scopes TEXT NOT NULL,
+            expires_at TEXT,
+            last_used_at TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_api_tokens_user_id ON api_tokens(user_id)")
+        .execute(pool)
+        .await?;
+
+    // Outgoing webhooks and their durable delivery queue, which doubles as the
+    // delivery log. Webhooks without a user_id are global (admin-only).
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS webhooks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            url TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            events TEXT NOT NULL,
+            active BOOLEAN NOT NULL DEFAULT TRUE,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_webhooks_user_id ON webhooks(user_id)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            webhook_id INTEGER NOT NULL,
+            event TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at TEXT,
+            last_status_code INTEGER,
+            last_error TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            delivered_at TEXT,
+            FOREIGN KEY (webhook_id) REFERENCES webhooks(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due ON webhook_deliveries(status, next_attempt_at)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_webhook_id ON webhook_deliveries(webhook_id)")
+        .execute(pool)
+        .await?;
+
+    // Set while a machine is sending the delivery; once it has passed the
+    // delivery can be claimed again
+    sqlx::query("ALTER TABLE webhook_deliveries ADD COLUMN locked_until TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Bot webhooks are delivered through the webhooks table, owned by the
+    // bot. Bots set up before that get a row with a fresh secret; their
+    // owners see it by setting the URL again.
+    sqlx::query(
+        r#"
+        INSERT INTO webhooks (user_id, url, secret, events)
+        SELECT id, bot_webhook_url, 'whsec_' || lower(hex(randomblob(24))), 'bot.message'
+        FROM users u
+        WHERE is_bot = TRUE AND bot_webhook_url IS NOT NULL AND deleted_at IS NULL
+          AND NOT EXISTS (SELECT 1 FROM webhooks w WHERE w.user_id = u.id AND w.events = 'bot.message')
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Incoming webhooks post into the conversation between user_id and
+    // partner_id as user_id. The secret is only ever stored hashed.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS incoming_webhooks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            partner_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            last_used_at TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id),
+            FOREIGN KEY (partner_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_incoming_webhooks_user_id ON incoming_webhooks(user_id)")
+        .execute(pool)
+        .await?;
+
+    // Name of the incoming webhook a message was posted through
+    sqlx::query("ALTER TABLE messages ADD COLUMN via_integration TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Whether a message's content is end-to-end encrypted; integration
+    // messages are not, and only those may appear in push notifications
+    sqlx::query("ALTER TABLE messages ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT TRUE")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Devices that receive push notifications. An endpoint belongs to the
+    // account that registered it last.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS push_devices (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            endpoint TEXT NOT NULL UNIQUE,
+            p256dh TEXT,
+            auth TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_push_devices_user_id ON push_devices(user_id)")
+        .execute(pool)
+        .await?;
+
+    // Conversations a user doesn't want push notifications for; a NULL
+    // muted_until mutes indefinitely
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS conversation_mutes (
+            user_id INTEGER NOT NULL,
+            partner_id INTEGER NOT NULL,
+            muted_until TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            PRIMARY KEY (user_id, partner_id),
+            FOREIGN KEY (user_id) REFERENCES users(id),
+            FOREIGN KEY (partner_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Optional email address. Only verified addresses receive digests and
+    // password resets, and a verified address belongs to one account.
+    sqlx::query("ALTER TABLE users ADD COLUMN email TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE users ADD COLUMN email_verified_at TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE users ADD COLUMN email_digests BOOLEAN NOT NULL DEFAULT TRUE")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Creation time of the newest message covered by the last digest
+    sqlx::query("ALTER TABLE users ADD COLUMN last_digest_at TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_users_verified_email ON users(email COLLATE NOCASE) WHERE email_verified_at IS NOT NULL",
+    )
+    .execute(pool)
+    .await?;
+
+    // Single-use email verification and password reset tokens, stored hashed
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS email_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            purpose TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            email TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_email_tokens_user_id ON email_tokens(user_id)")
+        .execute(pool)
+        .await?;
+
+    // Personal data exports, generated in the background
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS export_jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            file_path TEXT,
+            error TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            completed_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_export_jobs_user_id ON export_jobs(user_id)")
+        .execute(pool)
+        .await?;
+
+    // Set while a machine is building the export, as for webhook deliveries
+    sqlx::query("ALTER TABLE export_jobs ADD COLUMN locked_until TEXT")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // The finished zip. Archives used to be written to the data directory
+    // and named by file_path; those are removed on startup.
+    sqlx::query("ALTER TABLE export_jobs ADD COLUMN archive BLOB")
+        .execute(pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // At most one export in progress per user. Requests that raced before
+    // this index existed may have queued more; all but the newest are failed.
+    sqlx::query(
+        r#"
+        UPDATE export_jobs SET status = 'failed', error = 'Superseded by a newer request'
+        WHERE status IN ('pending', 'running')
+          AND id NOT IN (
+            SELECT MAX(id) FROM export_jobs WHERE status IN ('pending', 'running') GROUP BY user_id
+          )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_export_jobs_in_progress ON export_jobs(user_id) WHERE status IN ('pending', 'running')",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The schema version recorded in the database by the last `init_db`.
+pub async fn schema_version<'e>(executor: impl Into<Executor<'e>>) -> Result<i64, sqlx::Error> {
+    let executor = executor.into();
+    let sql = match executor.backend() {
+        Backend::Sqlite => "PRAGMA user_version",
+        Backend::Postgres => "SELECT version FROM schema_version",
+    };
+    Ok(query(sql).fetch_one(executor).await?.get(0))
+}
+
+/// Close every connection. SQLite first checkpoints the write-ahead log into
+/// the database file, leaving a self-contained database behind.
+pub async fn close(pool: &DbPool) {
+    if let Db::Sqlite(sqlite) = pool.as_ref() {
+        if let Err(e) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(sqlite)
+            .await
+        {
+            tracing::error!("Failed to checkpoint the database: {}", e);
+        }
+    }
+    pool.close().await;
+}
+
+/// A private in-memory database with the full schema, for tests.
+#[cfg(test)]
+pub async fn test_pool() -> DbPool {
+    // Every connection to :memory: opens a separate database, so the pool
+    // keeps exactly one open for good
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to open in-memory database");
+    let db = Db::Sqlite(pool);
+    migrate(&db).await.expect("Failed to create schema");
+    Arc::new(db)
+}
+
+/// A fresh schema in the Postgres database named by `TEST_DATABASE_URL`, or
+/// `None` when it isn't set. Each call gets its own schema so tests can run
+/// in parallel; they are left behind for inspection.
+#[cfg(test)]
+pub async fn test_postgres_pool() -> Option<DbPool> {
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+    let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+
+    // Event bus listeners each hold a connection, so leave room for them
+    let pool = PgPoolOptions::new()
+        .max_connections(4)
+        .after_connect({
+            let schema = schema.clone();
+            move |conn, _| {
+                let schema = schema.clone();
+                Box::pin(async move {
+                    sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {schema}"))
+                        .execute(&mut *conn)
+                        .await?;
+                    sqlx::query(&format!("SET search_path TO {schema}"))
+                        .execute(&mut *conn)
+                        .await?;
+                    Ok(())
+                })
+            }
+        })
+        .connect(&url)
+        .await
+        .expect("Failed to connect to TEST_DATABASE_URL");
+    let db = Db::Postgres(pool);
+    migrate(&db).await.expect("Failed to create schema");
+    Some(Arc::new(db))
 }
@@ -0,0 +1,276 @@
+// PostgreSQL schema. It matches what the SQLite migrations build up to, with
+// native types: BIGINT ids, TIMESTAMPTZ times and BYTEA images and archives. Usernames and
+// emails compare case-insensitively through the `nocase` collation, so the
+// shared queries can say `COLLATE NOCASE` on both backends.
+use super::SCHEMA_VERSION;
+use sqlx::PgPool;
+
+const SCHEMA: &[&str] = &[
+    "CREATE COLLATION IF NOT EXISTS nocase (provider = icu, locale = 'und-u-ks-level2', deterministic = false)",
+    r#"
+    CREATE TABLE IF NOT EXISTS users (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        display_name TEXT,
+        bio TEXT,
+        discoverable BOOLEAN NOT NULL DEFAULT TRUE,
+        deleted_at TIMESTAMPTZ,
+        failed_login_count BIGINT NOT NULL DEFAULT 0,
+        locked_until TIMESTAMPTZ,
+        is_bot BOOLEAN NOT NULL DEFAULT FALSE,
+        bot_owner_id BIGINT REFERENCES users(id),
+        bot_webhook_url TEXT,
+        email TEXT,
+        email_verified_at TIMESTAMPTZ,
+        email_digests BOOLEAN NOT NULL DEFAULT TRUE,
+        last_digest_at TIMESTAMPTZ
+    )
+    "#,
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_users_username_nocase ON users(username COLLATE nocase)",
+    "CREATE INDEX IF NOT EXISTS idx_users_bot_owner_id ON users(bot_owner_id)",
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_users_verified_email ON users(email COLLATE nocase) WHERE email_verified_at IS NOT NULL",
+    r#"
+    CREATE TABLE IF NOT EXISTS sessions (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        token_hash TEXT NOT NULL UNIQUE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS messages (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        from_user_id BIGINT NOT NULL REFERENCES users(id),
+        to_user_id BIGINT NOT NULL REFERENCES users(id),
+        content TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        read_at TIMESTAMPTZ,
+        via_integration TEXT,
+        encrypted BOOLEAN NOT NULL DEFAULT TRUE
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_messages_to_user ON messages(to_user_id)",
+    "CREATE INDEX IF NOT EXISTS idx_messages_from_user ON messages(from_user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS user_keys (
+        user_id BIGINT PRIMARY KEY REFERENCES users(id),
+        identity_key TEXT NOT NULL,
+        signed_prekey TEXT NOT NULL,
+        signed_prekey_signature TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS one_time_prekeys (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        key_id BIGINT NOT NULL,
+        public_key TEXT NOT NULL,
+        used BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_one_time_prekeys_user_id ON one_time_prekeys(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS user_avatars (
+        user_id BIGINT PRIMARY KEY REFERENCES users(id),
+        image BYTEA NOT NULL,
+        thumbnail BYTEA NOT NULL,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS username_history (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        old_username TEXT NOT NULL,
+        new_username TEXT NOT NULL,
+        changed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_username_history_old_username ON username_history(old_username COLLATE nocase)",
+    r#"
+    CREATE TABLE IF NOT EXISTS notifications (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        kind TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        read_at TIMESTAMPTZ
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_notifications_user_id ON notifications(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS user_totp (
+        user_id BIGINT PRIMARY KEY REFERENCES users(id),
+        secret TEXT NOT NULL,
+        enabled BOOLEAN NOT NULL DEFAULT FALSE,
+        last_used_step BIGINT,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        confirmed_at TIMESTAMPTZ
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        code_hash TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        used_at TIMESTAMPTZ
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_totp_recovery_codes_user_id ON totp_recovery_codes(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS login_challenges (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        token_hash TEXT NOT NULL UNIQUE,
+        attempts BIGINT NOT NULL DEFAULT 0,
+        expires_at TIMESTAMPTZ NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS api_tokens (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        token_hash TEXT NOT NULL UNIQUE,
+        description TEXT NOT NULL,
+        scopes TEXT NOT NULL,
+        expires_at TIMESTAMPTZ,
+        last_used_at TIMESTAMPTZ,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_api_tokens_user_id ON api_tokens(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS webhooks (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT REFERENCES users(id),
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        events TEXT NOT NULL,
+        active BOOLEAN NOT NULL DEFAULT TRUE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_webhooks_user_id ON webhooks(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS webhook_deliveries (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        webhook_id BIGINT NOT NULL REFERENCES webhooks(id),
+        event TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts BIGINT NOT NULL DEFAULT 0,
+        next_attempt_at TIMESTAMPTZ,
+        last_status_code BIGINT,
+        last_error TEXT,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        delivered_at TIMESTAMPTZ
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due ON webhook_deliveries(status, next_attempt_at)",
+    "CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_webhook_id ON webhook_deliveries(webhook_id)",
+    "ALTER TABLE webhook_deliveries ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ",
+    r#"
+    INSERT INTO webhooks (user_id, url, secret, events)
+    SELECT id, bot_webhook_url, 'whsec_' || replace(gen_random_uuid()::text, '-', ''), 'bot.message'
+    FROM users u
+    WHERE is_bot = TRUE AND bot_webhook_url IS NOT NULL AND deleted_at IS NULL
+      AND NOT EXISTS (SELECT 1 FROM webhooks w WHERE w.user_id = u.id AND w.events = 'bot.message')
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS incoming_webhooks (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        partner_id BIGINT NOT NULL REFERENCES users(id),
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        last_used_at TIMESTAMPTZ,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_incoming_webhooks_user_id ON incoming_webhooks(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS push_devices (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        kind TEXT NOT NULL,
+        endpoint TEXT NOT NULL UNIQUE,
+        p256dh TEXT,
+        auth TEXT,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_push_devices_user_id ON push_devices(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS conversation_mutes (
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        partner_id BIGINT NOT NULL REFERENCES users(id),
+        muted_until TIMESTAMPTZ,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (user_id, partner_id)
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS email_tokens (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        purpose TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        email TEXT NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_email_tokens_user_id ON email_tokens(user_id)",
+    r#"
+    CREATE TABLE IF NOT EXISTS export_jobs (
+        id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        status TEXT NOT NULL DEFAULT 'pending',
+        file_path TEXT,
+        error TEXT,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        completed_at TIMESTAMPTZ
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_export_jobs_user_id ON export_jobs(user_id)",
+    "ALTER TABLE export_jobs ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ",
+    "ALTER TABLE export_jobs ADD COLUMN IF NOT EXISTS archive BYTEA",
+    r#"
+    UPDATE export_jobs SET status = 'failed', error = 'Superseded by a newer request'
+    WHERE status IN ('pending', 'running')
+      AND id NOT IN (
+        SELECT MAX(id) FROM export_jobs WHERE status IN ('pending', 'running') GROUP BY user_id
+      )
+    "#,
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_export_jobs_in_progress ON export_jobs(user_id) WHERE status IN ('pending', 'running')",
+    "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL)",
+];
+
+pub async fn migrate(pool: &PgPool) -> Result<(), sqlx::Error> {
+    // Instances starting together would otherwise race to create the same
+    // objects; the lock is released when the transaction ends
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('migchat_migrate'))")
+        .execute(&mut *tx)
+        .await?;
+
+    for statement in SCHEMA {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+
+    sqlx::query("DELETE FROM schema_version")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO schema_version (version) VALUES ($1)")
+        .bind(SCHEMA_VERSION)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
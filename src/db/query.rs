@@ -0,0 +1,439 @@
+// Queries that run on either backend. SQL is written once, with `?`
+// placeholders, in the subset both SQLite and PostgreSQL understand; values
+// are bound through `Value` so timestamps become TEXT in SQLite and
+// TIMESTAMPTZ in Postgres.
+use chrono::{DateTime, Utc};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgPool, PgRow, Postgres};
+use sqlx::sqlite::{Sqlite, SqlitePool, SqliteRow};
+use sqlx::{ColumnIndex, Row as _};
+use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+}
+
+/// Connection pool for whichever database the server was started with.
+#[derive(Debug, Clone)]
+pub enum Db {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+impl Db {
+    pub fn backend(&self) -> Backend {
+        match self {
+            Db::Sqlite(_) => Backend::Sqlite,
+            Db::Postgres(_) => Backend::Postgres,
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<Conn, sqlx::Error> {
+        Ok(match self {
+            Db::Sqlite(pool) => Conn::Sqlite(pool.acquire().await?),
+            Db::Postgres(pool) => Conn::Postgres(pool.acquire().await?),
+        })
+    }
+
+    pub async fn begin(&self) -> Result<Transaction, sqlx::Error> {
+        Ok(Transaction(match self {
+            Db::Sqlite(pool) => Conn::SqliteTx(pool.begin().await?),
+            Db::Postgres(pool) => Conn::PostgresTx(pool.begin().await?),
+        }))
+    }
+
+    /// A transaction that holds the write lock from the start. SQLite
+    /// otherwise only takes it at the first write; Postgres locks rows as
+    /// they are written either way.
+    pub async fn begin_immediate(&self) -> Result<Transaction, sqlx::Error> {
+        Ok(Transaction(match self {
+            Db::Sqlite(pool) => Conn::SqliteTx(pool.begin_with("BEGIN IMMEDIATE").await?),
+            Db::Postgres(pool) => Conn::PostgresTx(pool.begin().await?),
+        }))
+    }
+
+    /// Open connections, idle or in use.
+    pub fn size(&self) -> u32 {
+        match self {
+            Db::Sqlite(pool) => pool.size(),
+            Db::Postgres(pool) => pool.size(),
+        }
+    }
+
+    pub fn num_idle(&self) -> usize {
+        match self {
+            Db::Sqlite(pool) => pool.num_idle(),
+            Db::Postgres(pool) => pool.num_idle(),
+        }
+    }
+
+    pub fn max_connections(&self) -> u32 {
+        match self {
+            Db::Sqlite(pool) => pool.options().get_max_connections(),
+            Db::Postgres(pool) => pool.options().get_max_connections(),
+        }
+    }
+
+    pub async fn close(&self) {
+        match self {
+            Db::Sqlite(pool) => pool.close().await,
+            Db::Postgres(pool) => pool.close().await,
+        }
+    }
+}
+
+/// A connection taken from the pool, or the one a transaction runs on.
+pub enum Conn {
+    Sqlite(PoolConnection<Sqlite>),
+    SqliteTx(sqlx::Transaction<'static, Sqlite>),
+    Postgres(PoolConnection<Postgres>),
+    PostgresTx(sqlx::Transaction<'static, Postgres>),
+}
+
+impl Conn {
+    pub fn backend(&self) -> Backend {
+        match self {
+            Conn::Sqlite(_) | Conn::SqliteTx(_) => Backend::Sqlite,
+            Conn::Postgres(_) | Conn::PostgresTx(_) => Backend::Postgres,
+        }
+    }
+}
+
+/// Rolled back when dropped without `commit`. Derefs to the connection it
+/// runs on, so `&mut *tx` can be passed wherever a query runs.
+pub struct Transaction(Conn);
+
+impl Transaction {
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        match self.0 {
+            Conn::SqliteTx(tx) => tx.commit().await,
+            Conn::PostgresTx(tx) => tx.commit().await,
+            Conn::Sqlite(_) | Conn::Postgres(_) => unreachable!("not a transaction"),
+        }
+    }
+
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        match self.0 {
+            Conn::SqliteTx(tx) => tx.rollback().await,
+            Conn::PostgresTx(tx) => tx.rollback().await,
+            Conn::Sqlite(_) | Conn::Postgres(_) => unreachable!("not a transaction"),
+        }
+    }
+}
+
+impl Deref for Transaction {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        &self.0
+    }
+}
+
+impl DerefMut for Transaction {
+    fn deref_mut(&mut self) -> &mut Conn {
+        &mut self.0
+    }
+}
+
+/// Where a query runs: any connection from the pool, or a specific one.
+pub enum Executor<'a> {
+    Pool(&'a Db),
+    Conn(&'a mut Conn),
+}
+
+impl Executor<'_> {
+    pub fn backend(&self) -> Backend {
+        match self {
+            Executor::Pool(db) => db.backend(),
+            Executor::Conn(conn) => conn.backend(),
+        }
+    }
+}
+
+impl<'a> From<&'a Db> for Executor<'a> {
+    fn from(db: &'a Db) -> Self {
+        Executor::Pool(db)
+    }
+}
+
+impl<'a> From<&'a mut Conn> for Executor<'a> {
+    fn from(conn: &'a mut Conn) -> Self {
+        Executor::Conn(conn)
+    }
+}
+
+impl<'a> From<&'a mut Transaction> for Executor<'a> {
+    fn from(tx: &'a mut Transaction) -> Self {
+        Executor::Conn(&mut tx.0)
+    }
+}
+
+/// A value bound to a query parameter. `None`s keep their type so Postgres
+/// can tell what kind of NULL it is.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(Option<i64>),
+    Float(Option<f64>),
+    Bool(Option<bool>),
+    Text(Option<String>),
+    Bytes(Option<Vec<u8>>),
+    Time(Option<DateTime<Utc>>),
+}
+
+macro_rules! value_from {
+    ($variant:ident: $($ty:ty => $convert:expr),+ $(,)?) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    let convert: fn($ty) -> _ = $convert;
+                    Value::$variant(Some(convert(value)))
+                }
+            }
+
+            impl From<Option<$ty>> for Value {
+                fn from(value: Option<$ty>) -> Self {
+                    let convert: fn($ty) -> _ = $convert;
+                    Value::$variant(value.map(convert))
+                }
+            }
+        )+
+    };
+}
+
+value_from!(Int: i64 => |v| v, &i64 => |v| *v, i32 => i64::from, u32 => i64::from);
+value_from!(Float: f64 => |v| v);
+value_from!(Bool: bool => |v| v);
+value_from!(Text: String => |v| v, &String => |v| v.clone(), &str => |v| v.to_string());
+value_from!(Bytes: Vec<u8> => |v| v, &Vec<u8> => |v| v.clone(), &[u8] => |v| v.to_vec());
+value_from!(Time: DateTime<Utc> => |v| v, &DateTime<Utc> => |v| *v);
+
+impl From<&Option<String>> for Value {
+    fn from(value: &Option<String>) -> Self {
+        Value::Text(value.clone())
+    }
+}
+
+/// Start a query; see the module comment for the SQL it may use.
+pub fn query<'q>(sql: impl Into<Cow<'q, str>>) -> Query<'q> {
+    Query {
+        sql: sql.into(),
+        args: Vec::new(),
+    }
+}
+
+#[must_use = "queries do nothing unless executed"]
+pub struct Query<'q> {
+    sql: Cow<'q, str>,
+    args: Vec<Value>,
+}
+
+/// Outcome of a statement that returns no rows.
+pub struct QueryResult {
+    rows_affected: u64,
+}
+
+impl QueryResult {
+    pub fn rows_affected(&self) -> u64 {
+        self.rows_affected
+    }
+}
+
+// Runs `$body` with `$query` bound to the sqlx query for the executor's
+// backend and `$ex` to the sqlx executor
+macro_rules! dispatch {
+    ($self:ident, $executor:expr, |$query:ident, $ex:ident| $body:expr) => {{
+        let executor: Executor<'_> = $executor.into();
+        match executor {
+            Executor::Pool(Db::Sqlite(pool)) => {
+                let $query = $self.sqlite();
+                let $ex = pool;
+                $body
+            }
+            Executor::Pool(Db::Postgres(pool)) => {
+                let sql = postgres_placeholders(&$self.sql);
+                let $query = $self.postgres(&sql);
+                let $ex = pool;
+                $body
+            }
+            Executor::Conn(Conn::Sqlite(conn)) => {
+                let $query = $self.sqlite();
+                let $ex = &mut **conn;
+                $body
+            }
+            Executor::Conn(Conn::SqliteTx(tx)) => {
+                let $query = $self.sqlite();
+                let $ex = &mut **tx;
+                $body
+            }
+            Executor::Conn(Conn::Postgres(conn)) => {
+                let sql = postgres_placeholders(&$self.sql);
+                let $query = $self.postgres(&sql);
+                let $ex = &mut **conn;
+                $body
+            }
+            Executor::Conn(Conn::PostgresTx(tx)) => {
+                let sql = postgres_placeholders(&$self.sql);
+                let $query = $self.postgres(&sql);
+                let $ex = &mut **tx;
+                $body
+            }
+        }
+    }};
+}
+
+impl<'q> Query<'q> {
+    pub fn bind(mut self, value: impl Into<Value>) -> Self {
+        self.args.push(value.into());
+        self
+    }
+
+    pub async fn execute<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<QueryResult, sqlx::Error> {
+        dispatch!(self, executor, |query, ex| {
+            let result = query.execute(ex).await?;
+            Ok(QueryResult {
+                rows_affected: result.rows_affected(),
+            })
+        })
+    }
+
+    pub async fn fetch_one<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Row, sqlx::Error> {
+        dispatch!(self, executor, |query, ex| query
+            .fetch_one(ex)
+            .await
+            .map(Row::from))
+    }
+
+    pub async fn fetch_optional<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Option<Row>, sqlx::Error> {
+        dispatch!(self, executor, |query, ex| Ok(query
+            .fetch_optional(ex)
+            .await?
+            .map(Row::from)))
+    }
+
+    pub async fn fetch_all<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Vec<Row>, sqlx::Error> {
+        dispatch!(self, executor, |query, ex| Ok(query
+            .fetch_all(ex)
+            .await?
+            .into_iter()
+            .map(Row::from)
+            .collect()))
+    }
+
+    fn sqlite(&self) -> sqlx::query::Query<'_, Sqlite, sqlx::sqlite::SqliteArguments<'_>> {
+        let mut query = sqlx::query(&self.sql);
+        for arg in &self.args {
+            query = match arg.clone() {
+                Value::Int(v) => query.bind(v),
+                Value::Float(v) => query.bind(v),
+                Value::Bool(v) => query.bind(v),
+                Value::Text(v) => query.bind(v),
+                Value::Bytes(v) => query.bind(v),
+                Value::Time(v) => query.bind(v),
+            };
+        }
+        query
+    }
+
+    fn postgres<'s>(
+        &self,
+        sql: &'s str,
+    ) -> sqlx::query::Query<'s, Postgres, sqlx::postgres::PgArguments> {
+        let mut query = sqlx::query(sql);
+        for arg in &self.args {
+            query = match arg.clone() {
+                Value::Int(v) => query.bind(v),
+                Value::Float(v) => query.bind(v),
+                Value::Bool(v) => query.bind(v),
+                Value::Text(v) => query.bind(v),
+                Value::Bytes(v) => query.bind(v),
+                Value::Time(v) => query.bind(v),
+            };
+        }
+        query
+    }
+}
+
+// Postgres numbers its placeholders; `?`s inside string literals are left alone
+fn postgres_placeholders(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len() + 16);
+    let mut in_literal = false;
+    let mut n = 0;
+    for c in sql.chars() {
+        match c {
+            '\'' => {
+                in_literal = !in_literal;
+                out.push(c);
+            }
+            '?' if !in_literal => {
+                n += 1;
+                out.push('$');
+                out.push_str(&n.to_string());
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Types that can be read from a column on either backend.
+pub trait Decode:
+    for<'r> sqlx::Decode<'r, Sqlite>
+    + sqlx::Type<Sqlite>
+    + for<'r> sqlx::Decode<'r, Postgres>
+    + sqlx::Type<Postgres>
+{
+}
+
+impl<T> Decode for T where
+    T: for<'r> sqlx::Decode<'r, Sqlite>
+        + sqlx::Type<Sqlite>
+        + for<'r> sqlx::Decode<'r, Postgres>
+        + sqlx::Type<Postgres>
+{
+}
+
+pub enum Row {
+    Sqlite(SqliteRow),
+    Postgres(PgRow),
+}
+
+impl From<SqliteRow> for Row {
+    fn from(row: SqliteRow) -> Self {
+        Row::Sqlite(row)
+    }
+}
+
+impl From<PgRow> for Row {
+    fn from(row: PgRow) -> Self {
+        Row::Postgres(row)
+    }
+}
+
+impl Row {
+    /// Read a column, panicking if it is missing or of the wrong type.
+    pub fn get<T, I>(&self, index: I) -> T
+    where
+        T: Decode,
+        I: ColumnIndex<SqliteRow> + ColumnIndex<PgRow>,
+    {
+        match self {
+            Row::Sqlite(row) => row.get(index),
+            Row::Postgres(row) => row.get(index),
+        }
+    }
+}
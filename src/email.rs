@@ -0,0 +1,434 @@
+// Outgoing email: address verification, password resets and digests of
+// unread messages, sent over SMTP when SMTP_URL is set.
+use crate::auth::{generate_token, hash_token};
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::shutdown::Shutdown;
+use chrono::{DateTime, Utc};
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const PURPOSE_VERIFY: &str = "verify";
+pub const PURPOSE_RESET: &str = "reset";
+
+const VERIFY_TOKEN_HOURS: i64 = 24;
+const RESET_TOKEN_MINUTES: i64 = 60;
+const DIGEST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Sends email through the configured SMTP server, or nowhere when there is
+/// none. Cloning shares the connection pool.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let from = config
+            .email_from
+            .parse()
+            .map_err(|e| format!("Invalid EMAIL_FROM: {}", e))?;
+        let transport = config
+            .smtp_url
+            .as_deref()
+            .map(|url| {
+                AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+                    .map(|builder| builder.build())
+                    .map_err(|e| format!("Invalid SMTP_URL: {}", e))
+            })
+            .transpose()?;
+        Ok(Mailer { transport, from })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), String> {
+        let Some(transport) = &self.transport else {
+            return Err("email is not configured".to_string());
+        };
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse().map_err(|e| format!("invalid address: {}", e))?)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| e.to_string())?;
+        transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Email addresses must parse as a single plain address.
+pub fn validate_address(email: &str) -> Result<String, String> {
+    let email = email.trim();
+    email
+        .parse::<lettre::Address>()
+        .map(|_| email.to_string())
+        .map_err(|_| "Invalid email address".to_string())
+}
+
+/// Create a token for `purpose`, replacing any earlier one for the user.
+pub async fn issue_token(
+    pool: &DbPool,
+    user_id: i64,
+    purpose: &str,
+    email: &str,
+) -> Result<String, sqlx::Error> {
+    let token = generate_token();
+    let expires_at = if purpose == PURPOSE_RESET {
+        Utc::now() + chrono::Duration::minutes(RESET_TOKEN_MINUTES)
+    } else {
+        Utc::now() + chrono::Duration::hours(VERIFY_TOKEN_HOURS)
+    };
+
+    let mut tx = pool.begin().await?;
+    db::query("DELETE FROM email_tokens WHERE user_id = ? AND purpose = ?")
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await?;
+    db::query(
+        "INSERT INTO email_tokens (user_id, purpose, token_hash, email, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(purpose)
+    .bind(hash_token(&token))
+    .bind(email)
+    .bind(expires_at)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(token)
+}
+
+/// The user id and address an unexpired token was issued for. The token is
+/// not consumed.
+pub async fn find_token(
+    pool: &DbPool,
+    purpose: &str,
+    token: &str,
+) -> Result<Option<(i64, String)>, sqlx::Error> {
+    let row = db::query(
+        "SELECT user_id, email FROM email_tokens WHERE token_hash = ? AND purpose = ? AND expires_at > ?",
+    )
+    .bind(hash_token(token))
+    .bind(purpose)
+    .bind(Utc::now())
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    Ok(row.map(|row| (row.get("user_id"), row.get("email"))))
+}
+
+pub async fn send_verification(
+    mailer: &Mailer,
+    config: &Config,
+    email: &str,
+    token: &str,
+) -> Result<(), String> {
+    let link = format!("{}/api/account/email/verify?token={}", config.public_url, token);
+    let body = format!(
+        "Confirm this address for your MigChat account by opening:\n\n{}\n\nThe link expires in {} hours. If you didn't ask for this, ignore this email.\n",
+        link, VERIFY_TOKEN_HOURS
+    );
+    mailer.send(email, "Confirm your email address", body).await
+}
+
+pub async fn send_password_reset(
+    mailer: &Mailer,
+    config: &Config,
+    email: &str,
+    username: &str,
+    token: &str,
+) -> Result<(), String> {
+    let link = format!("{}/reset-password?token={}", config.public_url, token);
+    let body = format!(
+        "Someone asked to reset the password of the MigChat account {}. To choose a new password, open:\n\n{}\n\nThe link expires in {} minutes. If you didn't ask for this, ignore this email; your password stays the same.\n",
+        username, link, RESET_TOKEN_MINUTES
+    );
+    mailer.send(email, "Reset your MigChat password", body).await
+}
+
+/// Start the task that emails users about messages left unread for
+/// EMAIL_DIGEST_AFTER_MINUTES. Each message is only ever included once.
+pub fn spawn_digests(pool: DbPool, config: Arc<Config>, mailer: Mailer, shutdown: Shutdown) {
+    if !mailer.is_enabled() {
+        return;
+    }
+
+    shutdown.clone().spawn(async move {
+        let mut interval = tokio::time::interval(DIGEST_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.stopping() => return,
+            }
+            if let Err(e) = send_digests(&pool, &config, &mailer).await {
+                tracing::error!("Failed to send email digests: {}", e);
+            }
+        }
+    });
+}
+
+// Messages eligible for a digest: unread past the threshold, newer than the
+// user's last digest and not in a muted conversation. Binds the cutoff and
+// the current time.
+const DIGEST_MESSAGES: &str = r#"
+    FROM messages m
+    JOIN users u ON u.id = m.to_user_id
+    JOIN users sender ON sender.id = m.from_user_id
+    WHERE u.email_verified_at IS NOT NULL
+      AND u.email_digests = TRUE
+      AND u.deleted_at IS NULL
+      AND m.read_at IS NULL
+      AND m.created_at <= ?
+      AND (u.last_digest_at IS NULL OR m.created_at > u.last_digest_at)
+      AND NOT EXISTS (
+          SELECT 1 FROM conversation_mutes cm
+          WHERE cm.user_id = m.to_user_id
+            AND cm.partner_id = m.from_user_id
+            AND (cm.muted_until IS NULL OR cm.muted_until > ?)
+      )
+"#;
+
+async fn send_digests(pool: &DbPool, config: &Config, mailer: &Mailer) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let cutoff = now - chrono::Duration::minutes(config.email_digest_after_minutes);
+
+    let recipients = db::query(format!(
+        "SELECT u.id, u.email, MAX(m.created_at) as newest {} GROUP BY u.id",
+        DIGEST_MESSAGES
+    ))
+    .bind(cutoff)
+    .bind(now)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    for recipient in recipients {
+        let user_id: i64 = recipient.get("id");
+        let email: String = recipient.get("email");
+        let newest: DateTime<Utc> = recipient.get("newest");
+
+        let senders = db::query(format!(
+            "SELECT sender.username, COUNT(*) as count {} AND u.id = ? AND m.created_at <= ? GROUP BY sender.id ORDER BY count DESC",
+            DIGEST_MESSAGES
+        ))
+        .bind(cutoff)
+        .bind(now)
+        .bind(user_id)
+        .bind(newest)
+        .fetch_all(pool.as_ref())
+        .await?;
+
+        let total: i64 = senders.iter().map(|row| row.get::<i64, _>("count")).sum();
+        let lines: Vec<String> = senders
+            .iter()
+            .map(|row| format!("  {}: {}", row.get::<String, _>("username"), row.get::<i64, _>("count")))
+            .collect();
+        let body = format!(
+            "You have {} unread message{} on MigChat:\n\n{}\n\nOpen MigChat at {} to read them.\n\nTo stop these emails, turn off email digests in your account settings.\n",
+            total,
+            if total == 1 { "" } else { "s" },
+            lines.join("\n"),
+            config.public_url
+        );
+
+        // Recorded first so a failing mail server doesn't lead to repeats
+        // every interval. Only the machine that moves last_digest_at forward
+        // sends, so machines sharing the database never both mail a digest.
+        let claimed = db::query(
+            "UPDATE users SET last_digest_at = ? WHERE id = ? AND (last_digest_at IS NULL OR last_digest_at < ?)",
+        )
+        .bind(newest)
+        .bind(user_id)
+        .bind(newest)
+        .execute(pool.as_ref())
+        .await?;
+        if claimed.rows_affected() != 1 {
+            continue;
+        }
+
+        if let Err(e) = mailer.send(&email, "Unread messages on MigChat", body).await {
+            tracing::warn!("Failed to send digest to user {}: {}", user_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::mpsc;
+
+    // An SMTP server on a local port that accepts every message and passes on
+    // its recipients and data
+    async fn smtp_server() -> (String, mpsc::UnboundedReceiver<(Vec<String>, String)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    let mut recipients = Vec::new();
+                    writer.write_all(b"220 localhost\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let command = line.to_ascii_uppercase();
+                        let reply: &[u8] = if command.starts_with("EHLO") {
+                            b"250-localhost\r\n250 8BITMIME\r\n"
+                        } else if command.starts_with("RCPT TO:") {
+                            recipients
+                                .push(line[8..].trim_matches(|c| c == '<' || c == '>').to_string());
+                            b"250 OK\r\n"
+                        } else if command == "DATA" {
+                            writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                // Undo the quoted-printable soft line breaks
+                                // lettre uses for long lines
+                                match line.strip_suffix('=') {
+                                    Some(line) => data.push_str(line),
+                                    None => {
+                                        data.push_str(&line);
+                                        data.push('\n');
+                                    }
+                                }
+                            }
+                            let data = data.replace("=3D", "=");
+                            sender
+                                .send((std::mem::take(&mut recipients), data))
+                                .unwrap();
+                            b"250 OK\r\n"
+                        } else if command == "QUIT" {
+                            writer.write_all(b"221 Bye\r\n").await.unwrap();
+                            return;
+                        } else {
+                            b"250 OK\r\n"
+                        };
+                        writer.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, receiver)
+    }
+
+    fn config(smtp_url: String) -> Config {
+        Config {
+            smtp_url: Some(smtp_url),
+            email_from: "MigChat <noreply@example.com>".to_string(),
+            public_url: "https://chat.example.com".to_string(),
+            email_digest_after_minutes: 60,
+            ..Config::from_env()
+        }
+    }
+
+    async fn insert_user(pool: &DbPool, username: &str, email: Option<&str>) -> i64 {
+        db::query(
+            "INSERT INTO users (username, password_hash, email, email_verified_at, created_at) VALUES (?, 'x', ?, ?, ?) RETURNING id",
+        )
+        .bind(username)
+        .bind(email)
+        .bind(email.map(|_| Utc::now()))
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id")
+    }
+
+    async fn last_digest_at(pool: &DbPool, user_id: i64) -> Option<DateTime<Utc>> {
+        db::query("SELECT last_digest_at FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(pool.as_ref())
+            .await
+            .unwrap()
+            .get("last_digest_at")
+    }
+
+    #[tokio::test]
+    async fn verification_mail_links_to_a_token_for_the_address() {
+        let pool = db::test_pool().await;
+        let (url, mut mails) = smtp_server().await;
+        let config = config(url);
+        let mailer = Mailer::from_config(&config).unwrap();
+        let alice = insert_user(&pool, "alice", None).await;
+
+        let token = issue_token(&pool, alice, PURPOSE_VERIFY, "alice@example.com")
+            .await
+            .unwrap();
+        send_verification(&mailer, &config, "alice@example.com", &token)
+            .await
+            .unwrap();
+
+        let (recipients, data) = mails.recv().await.unwrap();
+        assert_eq!(recipients, ["alice@example.com"]);
+        assert!(data.contains("Subject: Confirm your email address"));
+        assert!(data.contains(&format!(
+            "https://chat.example.com/api/account/email/verify?token={}",
+            token
+        )));
+        assert_eq!(
+            find_token(&pool, PURPOSE_VERIFY, &token).await.unwrap(),
+            Some((alice, "alice@example.com".to_string()))
+        );
+        assert_eq!(
+            find_token(&pool, PURPOSE_RESET, &token).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn digests_cover_each_unread_message_once() {
+        let pool = db::test_pool().await;
+        let (url, mut mails) = smtp_server().await;
+        let config = config(url);
+        let mailer = Mailer::from_config(&config).unwrap();
+        let alice = insert_user(&pool, "alice", Some("alice@example.com")).await;
+        let bob = insert_user(&pool, "bob", None).await;
+
+        let sent_at = Utc::now() - chrono::Duration::hours(2);
+        for created_at in [sent_at - chrono::Duration::minutes(1), sent_at, Utc::now()] {
+            db::query(
+                "INSERT INTO messages (from_user_id, to_user_id, content, created_at) VALUES (?, ?, 'hi', ?)",
+            )
+            .bind(bob)
+            .bind(alice)
+            .bind(created_at)
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+        }
+
+        send_digests(&pool, &config, &mailer).await.unwrap();
+        let (recipients, data) = mails.recv().await.unwrap();
+        assert_eq!(recipients, ["alice@example.com"]);
+        // The message newer than the threshold waits for a later digest
+        assert!(data.contains("You have 2 unread messages"));
+        assert!(data.contains("bob: 2"));
+        assert_eq!(last_digest_at(&pool, alice).await, Some(sent_at));
+        assert_eq!(last_digest_at(&pool, bob).await, None);
+
+        send_digests(&pool, &config, &mailer).await.unwrap();
+        assert!(mails.try_recv().is_err());
+        assert_eq!(last_digest_at(&pool, alice).await, Some(sent_at));
+    }
+}
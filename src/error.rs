@@ -0,0 +1,314 @@
+// Errors returned by handlers and middleware. Each one becomes a JSON body
+// with a message, a stable `code` clients can match on and the id of the
+// request. Server-side causes are logged, never sent to the client.
+use crate::models::ErrorResponse;
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::fmt::Display;
+use std::time::Duration;
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longest client-supplied request id that is passed through
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// Longest extractor rejection message that is passed on
+const MAX_REJECTION_LEN: usize = 4096;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    /// The request is malformed or fails validation (400)
+    BadRequest { code: &'static str, message: String },
+    /// Missing or wrong credentials (401)
+    Unauthorized { code: &'static str, message: String },
+    /// Authenticated, but not allowed to do this (403)
+    Forbidden { code: &'static str, message: String },
+    /// The resource doesn't exist or isn't visible to the caller (404)
+    NotFound { code: &'static str, message: String },
+    /// Clashes with existing state, such as a taken username (409)
+    Conflict { code: &'static str, message: String },
+    /// The body, query or path couldn't be parsed into what the handler
+    /// takes. Keeps the status axum's extractor chose (400, 413, 415 or 422).
+    Rejected { status: StatusCode, message: String },
+    /// Over a rate limit or locked out; retry after the given time (429)
+    TooManyRequests {
+        code: &'static str,
+        retry_after: Duration,
+    },
+    /// A service the request depends on, such as the mail server, failed (502)
+    BadGateway {
+        code: &'static str,
+        message: String,
+        cause: String,
+    },
+    /// A query failed (500)
+    Database(sqlx::Error),
+    /// Any other server-side failure (500). `context` says what was being done.
+    Internal {
+        context: &'static str,
+        cause: String,
+    },
+}
+
+impl AppError {
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::BadRequest {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn unauthorized(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::Unauthorized {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::Forbidden {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::NotFound {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::Conflict {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        AppError::TooManyRequests {
+            code: "rate_limited",
+            retry_after,
+        }
+    }
+
+    pub fn internal(context: &'static str, cause: impl Display) -> Self {
+        AppError::Internal {
+            context,
+            cause: cause.to_string(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            AppError::NotFound { .. } => StatusCode::NOT_FOUND,
+            AppError::Conflict { .. } => StatusCode::CONFLICT,
+            AppError::Rejected { status, .. } => *status,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::BadGateway { .. } => StatusCode::BAD_GATEWAY,
+            AppError::Database(_) | AppError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest { code, .. }
+            | AppError::Unauthorized { code, .. }
+            | AppError::Forbidden { code, .. }
+            | AppError::NotFound { code, .. }
+            | AppError::Conflict { code, .. }
+            | AppError::TooManyRequests { code, .. }
+            | AppError::BadGateway { code, .. } => code,
+            AppError::Rejected { status, .. } => match *status {
+                StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+                StatusCode::UNPROCESSABLE_ENTITY => "invalid_body",
+                _ => "invalid_request",
+            },
+            AppError::Database(_) | AppError::Internal { .. } => "internal_error",
+        }
+    }
+
+    // What the client is told
+    fn message(&self) -> String {
+        match self {
+            AppError::BadRequest { message, .. }
+            | AppError::Unauthorized { message, .. }
+            | AppError::Forbidden { message, .. }
+            | AppError::NotFound { message, .. }
+            | AppError::Conflict { message, .. }
+            | AppError::Rejected { message, .. }
+            | AppError::BadGateway { message, .. } => message.clone(),
+            AppError::TooManyRequests { .. } => "Too many requests".to_string(),
+            AppError::Database(_) | AppError::Internal { .. } => {
+                "Internal server error".to_string()
+            }
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+
+        match &self {
+            AppError::Database(e) => tracing::error!("Database error: {}", e),
+            AppError::Internal { context, cause } => tracing::error!("{}: {}", context, cause),
+            AppError::BadGateway { message, cause, .. } => tracing::warn!("{}: {}", message, cause),
+            _ => {}
+        }
+
+        let body = Json(ErrorResponse {
+            error: self.message(),
+            code: self.code().to_string(),
+            request_id,
+        });
+        let mut response = (self.status(), body).into_response();
+
+        if let AppError::TooManyRequests { retry_after, .. } = self {
+            // Round up so clients never retry a moment too early
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        response
+    }
+}
+
+/// Gives every request an id, taken from a well-formed `X-Request-Id` header
+/// or generated. It is echoed in the response header, included in error
+/// bodies and attached to everything logged while the request is handled.
+pub async fn assign_request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!("request", id = %id);
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(request))
+        .instrument(span)
+        .await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Turns the plain-text responses axum's `Json`, `Query` and `Path`
+/// extractors reject requests with into the usual JSON error body.
+pub async fn rejections_as_json(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let is_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"text/plain"));
+    if !response.status().is_client_error() || !is_text {
+        return response;
+    }
+
+    let status = response.status();
+    let message = match axum::body::to_bytes(response.into_body(), MAX_REJECTION_LEN).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(_) => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+    AppError::Rejected { status, message }.into_response()
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use std::future::IntoFuture;
+
+    #[derive(serde::Deserialize)]
+    struct Body {
+        #[allow(dead_code)]
+        content: String,
+    }
+
+    #[tokio::test]
+    async fn extractor_rejections_are_json_errors() {
+        let app = axum::Router::new()
+            .route(
+                "/items/:id",
+                post(|_: axum::extract::Path<i64>, _: Json<Body>| async { "ok" }),
+            )
+            .layer(axum::middleware::from_fn(rejections_as_json))
+            .layer(axum::middleware::from_fn(assign_request_id));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, app).into_future());
+        let client = reqwest::Client::new();
+
+        let cases = [
+            ("/items/1", Some(r#"{"content": 1}"#), 422, "invalid_body"),
+            ("/items/1", Some(r#"{"content":"#), 400, "invalid_request"),
+            ("/items/1", None, 415, "unsupported_media_type"),
+            (
+                "/items/x",
+                Some(r#"{"content": ""}"#),
+                400,
+                "invalid_request",
+            ),
+        ];
+        for (path, body, status, code) in cases {
+            let mut request = client
+                .post(format!("{base}{path}"))
+                .header(REQUEST_ID_HEADER, "req-1");
+            if let Some(body) = body {
+                request = request
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body);
+            }
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status().as_u16(), status, "{path} {body:?}");
+            let json: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(json["code"], code, "{path} {body:?}");
+            assert_eq!(json["request_id"], "req-1");
+            assert!(!json["error"].as_str().unwrap().is_empty());
+        }
+
+        let response = client
+            .post(format!("{base}/items/1"))
+            .json(&serde_json::json!({ "content": "hi" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+}
@@ -0,0 +1,215 @@
+use crate::shutdown::Shutdown;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+#[cfg(test)]
+mod local;
+mod postgres;
+
+#[cfg(test)]
+pub use local::LocalBus;
+pub use postgres::PostgresBus;
+
+// Events are only buffered briefly; subscribers that fall further behind
+// than this skip ahead and must catch up from the database.
+const EVENT_BUFFER: usize = 1024;
+
+pub type BusError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A message was stored and can be delivered to its recipient
+    MessageCreated {
+        message_id: i64,
+        from_user_id: i64,
+        to_user_id: i64,
+    },
+    /// `reader_id` marked `count` messages from `sender_id` as read
+    MessagesRead {
+        reader_id: i64,
+        sender_id: i64,
+        count: u64,
+    },
+    /// A user uploaded a new key bundle
+    KeysUpdated { user_id: i64 },
+}
+
+/// An event on its way between server instances, tagged with the instance
+/// that published it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub node: Uuid,
+    pub event: Event,
+}
+
+/// Carries events between instances of the server sharing a database, so a
+/// message sent through one wakes long polls waiting on another. Delivery is
+/// best effort: events sent while a node is disconnected are lost, and its
+/// long polls only notice at their timeout.
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    /// Send `envelope` to every node listening on the bus, this one included.
+    async fn send(&self, envelope: &Envelope) -> Result<(), BusError>;
+
+    /// Start receiving what any node sends from now on. Dropping the
+    /// receiver or shutting down stops listening.
+    async fn listen(&self, shutdown: &Shutdown) -> Result<mpsc::Receiver<Envelope>, BusError>;
+}
+
+/// Fan-out from request handlers to long-polling requests and background
+/// tasks. Cloning shares the same channels.
+///
+/// Once connected to an `EventBus`, events published on other nodes reach
+/// `subscribe` too, but never `subscribe_local`: background work such as
+/// push notifications and webhooks runs on the node an event started on.
+#[derive(Clone)]
+pub struct EventHub {
+    node: Uuid,
+    // Events published on this node
+    local: broadcast::Sender<Event>,
+    // Events published on any node
+    all: broadcast::Sender<Event>,
+    outgoing: Option<mpsc::UnboundedSender<Event>>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHub {
+    /// A hub for a server running on its own.
+    pub fn new() -> Self {
+        let (local, _) = broadcast::channel(EVENT_BUFFER);
+        let (all, _) = broadcast::channel(EVENT_BUFFER);
+        EventHub {
+            node: Uuid::new_v4(),
+            local,
+            all,
+            outgoing: None,
+        }
+    }
+
+    /// A hub that shares events with the other nodes on `bus`. Relaying runs
+    /// in the background until shutdown; events published before then are
+    /// still sent on.
+    pub async fn connect(bus: Arc<dyn EventBus>, shutdown: &Shutdown) -> Result<Self, BusError> {
+        let mut incoming = bus.listen(shutdown).await?;
+        let (outgoing, mut to_send) = mpsc::unbounded_channel();
+        let hub = EventHub {
+            outgoing: Some(outgoing),
+            ..EventHub::new()
+        };
+
+        let receiving = hub.clone();
+        let receive_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            loop {
+                tokio::select! {
+                    received = incoming.recv() => match received {
+                        // Our own events come back too, and were delivered
+                        // locally when published
+                        Some(envelope) if envelope.node == receiving.node => {}
+                        Some(envelope) => {
+                            let _ = receiving.all.send(envelope.event);
+                        }
+                        None => {
+                            tracing::error!("Event bus stopped; events from other nodes will no longer arrive");
+                            break;
+                        }
+                    },
+                    _ = receive_shutdown.stopping() => break,
+                }
+            }
+        });
+
+        let node = hub.node;
+        let send_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            loop {
+                let event = if send_shutdown.is_stopping() {
+                    match to_send.try_recv() {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    }
+                } else {
+                    tokio::select! {
+                        biased;
+                        event = to_send.recv() => match event {
+                            Some(event) => event,
+                            None => break,
+                        },
+                        _ = send_shutdown.stopping() => continue,
+                    }
+                };
+
+                if let Err(e) = bus.send(&Envelope { node, event }).await {
+                    tracing::error!("Failed to send event to other nodes: {}", e);
+                }
+            }
+        });
+
+        Ok(hub)
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Some(outgoing) = &self.outgoing {
+            let _ = outgoing.send(event.clone());
+        }
+        // Having no subscribers is normal, so send errors are ignored
+        let _ = self.local.send(event.clone());
+        let _ = self.all.send(event);
+    }
+
+    /// Events published on any node.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.all.subscribe()
+    }
+
+    /// Events published on this node only, for work that must happen once
+    /// per event.
+    pub fn subscribe_local(&self) -> broadcast::Receiver<Event> {
+        self.local.subscribe()
+    }
+}
+
+/// Wait until a message addressed to `user_id` is published. Also returns
+/// when events were missed, since one of them may have been for this user.
+pub async fn wait_for_message(receiver: &mut broadcast::Receiver<Event>, user_id: i64) {
+    loop {
+        match receiver.recv().await {
+            Ok(Event::MessageCreated { to_user_id, .. }) if to_user_id == user_id => return,
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => return,
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+/// Receive the next event for a background task. Once the task is told to
+/// stop, events already buffered are still returned, then the channel is
+/// reported closed so the task can exit.
+pub async fn next_event(
+    receiver: &mut broadcast::Receiver<Event>,
+    shutdown: &Shutdown,
+) -> Result<Event, RecvError> {
+    if !shutdown.is_stopping() {
+        tokio::select! {
+            biased;
+            received = receiver.recv() => return received,
+            _ = shutdown.stopping() => {}
+        }
+    }
+
+    match receiver.try_recv() {
+        Ok(event) => Ok(event),
+        Err(TryRecvError::Lagged(missed)) => Err(RecvError::Lagged(missed)),
+        Err(TryRecvError::Empty | TryRecvError::Closed) => Err(RecvError::Closed),
+    }
+}
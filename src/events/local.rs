@@ -0,0 +1,39 @@
+use super::{BusError, Envelope, EventBus, EVENT_BUFFER};
+use crate::shutdown::Shutdown;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// An `EventBus` within one process, for running several server instances
+/// side by side in tests. Cloning shares the same bus.
+#[derive(Clone, Default)]
+pub struct LocalBus {
+    listeners: Arc<Mutex<Vec<mpsc::Sender<Envelope>>>>,
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EventBus for LocalBus {
+    async fn send(&self, envelope: &Envelope) -> Result<(), BusError> {
+        // A listener that has fallen behind misses the event, as it would
+        // over a real connection
+        self.listeners.lock().unwrap().retain(|listener| {
+            !matches!(
+                listener.try_send(envelope.clone()),
+                Err(TrySendError::Closed(_))
+            )
+        });
+        Ok(())
+    }
+
+    async fn listen(&self, _shutdown: &Shutdown) -> Result<mpsc::Receiver<Envelope>, BusError> {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        self.listeners.lock().unwrap().push(sender);
+        Ok(receiver)
+    }
+}
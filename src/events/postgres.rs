@@ -0,0 +1,96 @@
+use super::{BusError, Envelope, EventBus, EVENT_BUFFER};
+use crate::shutdown::Shutdown;
+use async_trait::async_trait;
+use sqlx::postgres::{PgListener, PgPool};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Channel the server's events are sent on
+const CHANNEL: &str = "migchat_events";
+
+// How long to wait before listening again after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// An `EventBus` over PostgreSQL's LISTEN/NOTIFY, connecting every instance
+/// that shares the database. Notifications are limited to 8000 bytes, which
+/// events stay well under since they only carry ids. Listening holds one of
+/// the pool's connections.
+pub struct PostgresBus {
+    pool: PgPool,
+    channel: String,
+}
+
+impl PostgresBus {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresBus {
+            pool,
+            channel: CHANNEL.to_string(),
+        }
+    }
+
+    /// A bus on its own channel, so tests sharing a database don't hear each
+    /// other.
+    #[cfg(test)]
+    pub fn on_channel(pool: PgPool, channel: &str) -> Self {
+        PostgresBus {
+            pool,
+            channel: channel.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventBus for PostgresBus {
+    async fn send(&self, envelope: &Envelope) -> Result<(), BusError> {
+        let payload = serde_json::to_string(envelope)?;
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(&self.channel)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn listen(&self, shutdown: &Shutdown) -> Result<mpsc::Receiver<Envelope>, BusError> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(&self.channel).await?;
+
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        let pool = self.pool.clone();
+        let listen_shutdown = shutdown.clone();
+        // Stops before the pool is closed, which would otherwise wait for the
+        // listener's connection
+        shutdown.spawn(async move {
+            loop {
+                // The listener reconnects by itself; notifications sent while
+                // it was down are lost
+                let received = tokio::select! {
+                    received = listener.recv() => received,
+                    _ = sender.closed() => break,
+                    _ = listen_shutdown.stopping() => break,
+                };
+                match received {
+                    Ok(notification) => match serde_json::from_str(notification.payload()) {
+                        Ok(envelope) => {
+                            if sender.send(envelope).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => tracing::warn!("Ignoring malformed event notification: {}", e),
+                    },
+                    // The server is shutting down
+                    Err(_) if pool.is_closed() => break,
+                    Err(e) => {
+                        tracing::error!("Event bus connection failed: {}", e);
+                        tokio::select! {
+                            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                            _ = listen_shutdown.stopping() => break,
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+}
@@ -0,0 +1,643 @@
+// Personal data export: builds a zip archive of everything stored about a
+// user in the background and records progress on the export_jobs row. The
+// archive is stored on the row too, so any machine can serve the download.
+use crate::db::{self, DbPool};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::time::Duration;
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_COMPLETE: &str = "complete";
+pub const STATUS_FAILED: &str = "failed";
+
+// A job still running after this long was claimed by a machine that stopped,
+// and may be resumed elsewhere. The machine building it renews the lease
+// well before then.
+const LEASE_MINUTES: i64 = 10;
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+type ExportError = Box<dyn std::error::Error + Send + Sync>;
+
+pub fn spawn_export(pool: DbPool, job_id: i64, user_id: i64) {
+    tokio::spawn(run_export(pool, job_id, user_id));
+}
+
+/// Restart jobs that were queued, or running on a machine that has since
+/// stopped. Each job is only run where `claim` succeeds.
+pub async fn resume_pending(pool: &DbPool) -> Result<(), sqlx::Error> {
+    let rows = db::query("SELECT id, user_id FROM export_jobs WHERE status IN (?, ?)")
+        .bind(STATUS_PENDING)
+        .bind(STATUS_RUNNING)
+        .fetch_all(pool.as_ref())
+        .await?;
+
+    for row in rows {
+        spawn_export(pool.clone(), row.get("id"), row.get("user_id"));
+    }
+
+    Ok(())
+}
+
+// Archives used to be written to the data directory and named by file_path.
+// Those files are removed along with their jobs, so every archive left is in
+// the database and goes away in the same transaction as its account.
+pub async fn remove_legacy_archives(pool: &DbPool) -> Result<(), sqlx::Error> {
+    let rows = db::query("SELECT id, file_path FROM export_jobs WHERE file_path IS NOT NULL")
+        .fetch_all(pool.as_ref())
+        .await?;
+
+    for row in rows {
+        let file_path: String = row.get("file_path");
+        if let Err(e) = tokio::fs::remove_file(&file_path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove export {}: {}", file_path, e);
+                continue;
+            }
+        }
+        db::query("DELETE FROM export_jobs WHERE id = ?")
+            .bind(row.get::<i64, _>("id"))
+            .execute(pool.as_ref())
+            .await?;
+    }
+
+    Ok(())
+}
+
+// The lease expiry recorded when claiming or renewing. Postgres keeps
+// microseconds, so it is rounded to them to compare equal once stored.
+fn lease_from(now: DateTime<Utc>) -> DateTime<Utc> {
+    (now + chrono::Duration::minutes(LEASE_MINUTES)).trunc_subsecs(6)
+}
+
+// Marks the job running unless another machine already is, in one statement
+// so two machines resuming at once can't both claim it. Returns the lease the
+// claim holds.
+async fn claim(
+    pool: &DbPool,
+    job_id: i64,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let lease = lease_from(now);
+    let claimed = db::query(
+        r#"
+        UPDATE export_jobs SET status = ?, locked_until = ?
+        WHERE id = ?
+          AND (status = ? OR (status = ? AND (locked_until IS NULL OR locked_until <= ?)))
+        "#,
+    )
+    .bind(STATUS_RUNNING)
+    .bind(lease)
+    .bind(job_id)
+    .bind(STATUS_PENDING)
+    .bind(STATUS_RUNNING)
+    .bind(now)
+    .execute(pool.as_ref())
+    .await?;
+    Ok((claimed.rows_affected() == 1).then_some(lease))
+}
+
+// Extends a lease that is still held, returning the new one. `None` means the
+// claim was lost: the job was deleted, or taken over after the lease ran out.
+async fn renew(
+    pool: &DbPool,
+    job_id: i64,
+    lease: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let renewed_lease = lease_from(now);
+    let renewed = db::query(
+        "UPDATE export_jobs SET locked_until = ? WHERE id = ? AND status = ? AND locked_until = ?",
+    )
+    .bind(renewed_lease)
+    .bind(job_id)
+    .bind(STATUS_RUNNING)
+    .bind(lease)
+    .execute(pool.as_ref())
+    .await?;
+    Ok((renewed.rows_affected() == 1).then_some(renewed_lease))
+}
+
+// Records how the job ended, but only while `lease` is still held, so a job
+// taken over by another machine is never finished twice. Returns whether it
+// was recorded.
+async fn finish(
+    pool: &DbPool,
+    job_id: i64,
+    lease: DateTime<Utc>,
+    archive: Result<Vec<u8>, ExportError>,
+) -> Result<bool, sqlx::Error> {
+    let finished = match archive {
+        Ok(archive) => {
+            db::query(
+                "UPDATE export_jobs SET status = ?, archive = ?, completed_at = ? WHERE id = ? AND status = ? AND locked_until = ?",
+            )
+            .bind(STATUS_COMPLETE)
+            .bind(archive)
+        }
+        Err(e) => {
+            tracing::error!("Export job {} failed: {}", job_id, e);
+            db::query(
+                "UPDATE export_jobs SET status = ?, error = ?, completed_at = ? WHERE id = ? AND status = ? AND locked_until = ?",
+            )
+            .bind(STATUS_FAILED)
+            // The cause is logged above; the user only learns that it failed
+            .bind("The export could not be created; please request a new one")
+        }
+    }
+    .bind(Utc::now())
+    .bind(job_id)
+    .bind(STATUS_RUNNING)
+    .bind(lease)
+    .execute(pool.as_ref())
+    .await?;
+    Ok(finished.rows_affected() == 1)
+}
+
+async fn run_export(pool: DbPool, job_id: i64, user_id: i64) {
+    let mut lease = match claim(&pool, job_id, Utc::now()).await {
+        Ok(Some(lease)) => lease,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("Failed to claim export job {}: {}", job_id, e);
+            return;
+        }
+    };
+
+    let build = build_export(&pool, user_id);
+    tokio::pin!(build);
+    let mut renew_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + LEASE_RENEW_INTERVAL,
+        LEASE_RENEW_INTERVAL,
+    );
+    let archive = loop {
+        tokio::select! {
+            archive = &mut build => break archive,
+            _ = renew_timer.tick() => match renew(&pool, job_id, lease, Utc::now()).await {
+                Ok(Some(renewed)) => lease = renewed,
+                Ok(None) => {
+                    tracing::warn!("Export job {} was taken over or deleted; stopping", job_id);
+                    return;
+                }
+                // Tried again next tick; the lease has minutes left
+                Err(e) => tracing::warn!("Failed to renew export job {}: {}", job_id, e),
+            },
+        }
+    };
+
+    // A job deleted along with its account, or taken over after the lease
+    // ran out, matches no row, so the archive is dropped
+    if let Err(e) = finish(&pool, job_id, lease, archive).await {
+        tracing::error!("Failed to record outcome of export job {}: {}", job_id, e);
+    }
+}
+
+async fn build_export(pool: &DbPool, user_id: i64) -> Result<Vec<u8>, ExportError> {
+    let mut files: Vec<(&'static str, Vec<u8>)> = vec![
+        ("profile.json", to_json(&profile(pool, user_id).await?)?),
+        ("sessions.json", to_json(&sessions(pool, user_id).await?)?),
+        ("api_tokens.json", to_json(&api_tokens(pool, user_id).await?)?),
+        ("contacts.json", to_json(&contacts(pool, user_id).await?)?),
+        ("messages.json", to_json(&messages(pool, user_id).await?)?),
+        ("notifications.json", to_json(&notifications(pool, user_id).await?)?),
+        ("push.json", to_json(&push(pool, user_id).await?)?),
+        ("keys.json", to_json(&keys(pool, user_id).await?)?),
+    ];
+
+    let avatar = db::query("SELECT image FROM user_avatars WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(pool.as_ref())
+        .await?;
+    if let Some(avatar) = avatar {
+        files.push(("attachments/avatar.png", avatar.get("image")));
+    }
+
+    tokio::task::spawn_blocking(move || write_archive(files)).await?
+}
+
+fn write_archive(files: Vec<(&'static str, Vec<u8>)>) -> Result<Vec<u8>, ExportError> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    for (name, contents) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&contents)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+fn to_json(value: &Value) -> Result<Vec<u8>, ExportError> {
+    Ok(serde_json::to_vec_pretty(value)?)
+}
+
+async fn profile(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let user = db::query(
+        r#"
+        SELECT
+            u.id,
+            u.username,
+            u.display_name,
+            u.bio,
+            u.discoverable,
+            u.email,
+            u.email_verified_at,
+            u.email_digests,
+            u.created_at,
+            EXISTS(SELECT 1 FROM user_totp t WHERE t.user_id = u.id AND t.enabled = TRUE) as two_factor_enabled
+        FROM users u
+        WHERE u.id = ?
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await?;
+
+    let history = db::query(
+        "SELECT old_username, new_username, changed_at FROM username_history WHERE user_id = ? ORDER BY changed_at",
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(json!({
+        "user_id": user.get::<i64, _>("id"),
+        "username": user.get::<String, _>("username"),
+        "display_name": user.get::<Option<String>, _>("display_name"),
+        "bio": user.get::<Option<String>, _>("bio"),
+        "discoverable": user.get::<bool, _>("discoverable"),
+        "email": user.get::<Option<String>, _>("email"),
+        "email_verified_at": user.get::<Option<DateTime<Utc>>, _>("email_verified_at"),
+        "email_digests": user.get::<bool, _>("email_digests"),
+        "two_factor_enabled": user.get::<bool, _>("two_factor_enabled"),
+        "created_at": user.get::<DateTime<Utc>, _>("created_at"),
+        "username_history": history
+            .iter()
+            .map(|row| json!({
+                "old_username": row.get::<String, _>("old_username"),
+                "new_username": row.get::<String, _>("new_username"),
+                "changed_at": row.get::<DateTime<Utc>, _>("changed_at"),
+            }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+// Session metadata only; tokens are never exported
+async fn sessions(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let rows = db::query("SELECT id, created_at FROM sessions WHERE user_id = ? ORDER BY id")
+        .bind(user_id)
+        .fetch_all(pool.as_ref())
+        .await?;
+
+    Ok(Value::Array(
+        rows.iter()
+            .map(|row| {
+                json!({
+                    "id": row.get::<i64, _>("id"),
+                    "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+                })
+            })
+            .collect(),
+    ))
+}
+
+async fn api_tokens(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let rows = db::query(
+        "SELECT id, description, scopes, expires_at, last_used_at, created_at FROM api_tokens WHERE user_id = ? ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(Value::Array(
+        rows.iter()
+            .map(|row| {
+                json!({
+                    "id": row.get::<i64, _>("id"),
+                    "description": row.get::<String, _>("description"),
+                    "scopes": row.get::<String, _>("scopes").split(',').collect::<Vec<_>>(),
+                    "expires_at": row.get::<Option<DateTime<Utc>>, _>("expires_at"),
+                    "last_used_at": row.get::<Option<DateTime<Utc>>, _>("last_used_at"),
+                    "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+                })
+            })
+            .collect(),
+    ))
+}
+
+async fn contacts(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let rows = db::query(
+        r#"
+        SELECT
+            u.username,
+            u.display_name,
+            COUNT(*) as message_count,
+            MAX(m.created_at) as last_message_at
+        FROM messages m
+        JOIN users u ON u.id = CASE WHEN m.from_user_id = ? THEN m.to_user_id ELSE m.from_user_id END
+        WHERE m.from_user_id = ? OR m.to_user_id = ?
+        GROUP BY u.id
+        ORDER BY last_message_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(Value::Array(
+        rows.iter()
+            .map(|row| {
+                json!({
+                    "username": row.get::<String, _>("username"),
+                    "display_name": row.get::<Option<String>, _>("display_name"),
+                    "message_count": row.get::<i64, _>("message_count"),
+                    "last_message_at": row.get::<DateTime<Utc>, _>("last_message_at"),
+                })
+            })
+            .collect(),
+    ))
+}
+
+async fn messages(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let rows = db::query(
+        r#"
+        SELECT
+            m.id,
+            m.content,
+            m.encrypted,
+            m.via_integration,
+            m.created_at,
+            m.read_at,
+            from_user.username as from_username,
+            to_user.username as to_username
+        FROM messages m
+        JOIN users from_user ON m.from_user_id = from_user.id
+        JOIN users to_user ON m.to_user_id = to_user.id
+        WHERE m.to_user_id = ? OR m.from_user_id = ?
+        ORDER BY m.id
+        "#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(Value::Array(
+        rows.iter()
+            .map(|row| {
+                json!({
+                    "id": row.get::<i64, _>("id"),
+                    "from_username": row.get::<String, _>("from_username"),
+                    "to_username": row.get::<String, _>("to_username"),
+                    "content": row.get::<String, _>("content"),
+                    "encrypted": row.get::<bool, _>("encrypted"),
+                    "via_integration": row.get::<Option<String>, _>("via_integration"),
+                    "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+                    "read_at": row.get::<Option<DateTime<Utc>>, _>("read_at"),
+                })
+            })
+            .collect(),
+    ))
+}
+
+async fn notifications(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let rows = db::query(
+        "SELECT kind, payload, created_at, read_at FROM notifications WHERE user_id = ? ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(Value::Array(
+        rows.iter()
+            .map(|row| {
+                let payload: String = row.get("payload");
+                json!({
+                    "kind": row.get::<String, _>("kind"),
+                    "payload": serde_json::from_str::<Value>(&payload).unwrap_or(Value::Null),
+                    "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+                    "read_at": row.get::<Option<DateTime<Utc>>, _>("read_at"),
+                })
+            })
+            .collect(),
+    ))
+}
+
+// Device endpoints are bearer capabilities, so only their metadata is exported
+async fn push(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let devices =
+        db::query("SELECT id, kind, created_at FROM push_devices WHERE user_id = ? ORDER BY id")
+            .bind(user_id)
+            .fetch_all(pool.as_ref())
+            .await?;
+
+    let mutes = db::query(
+        r#"
+        SELECT u.username, cm.muted_until, cm.created_at
+        FROM conversation_mutes cm
+        JOIN users u ON u.id = cm.partner_id
+        WHERE cm.user_id = ?
+        ORDER BY cm.created_at
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(json!({
+        "devices": devices
+            .iter()
+            .map(|row| json!({
+                "id": row.get::<i64, _>("id"),
+                "kind": row.get::<String, _>("kind"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+            }))
+            .collect::<Vec<_>>(),
+        "muted_conversations": mutes
+            .iter()
+            .map(|row| json!({
+                "username": row.get::<String, _>("username"),
+                "muted_until": row.get::<Option<DateTime<Utc>>, _>("muted_until"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+            }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+// Public key material only; private keys never leave the client
+async fn keys(pool: &DbPool, user_id: i64) -> Result<Value, sqlx::Error> {
+    let keys = db::query(
+        "SELECT identity_key, signed_prekey, signed_prekey_signature, created_at FROM user_keys WHERE user_id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    let prekeys = db::query(
+        "SELECT key_id, public_key, used, created_at FROM one_time_prekeys WHERE user_id = ? ORDER BY key_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(json!({
+        "identity": keys.map(|row| json!({
+            "identity_key": row.get::<String, _>("identity_key"),
+            "signed_prekey": row.get::<String, _>("signed_prekey"),
+            "signed_prekey_signature": row.get::<String, _>("signed_prekey_signature"),
+            "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+        })),
+        "one_time_prekeys": prekeys
+            .iter()
+            .map(|row| json!({
+                "key_id": row.get::<i64, _>("key_id"),
+                "public_key": row.get::<String, _>("public_key"),
+                "used": row.get::<bool, _>("used"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+            }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn archive_holds_every_section_but_no_secrets() {
+        let pool = db::test_pool().await;
+        let user_id: i64 = db::query(
+            "INSERT INTO users (username, password_hash, created_at) VALUES ('alice', 'password-hash', ?) RETURNING id",
+        )
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id");
+        for (sql, secret) in [
+            (
+                "INSERT INTO sessions (user_id, token_hash, created_at) VALUES (?, ?, ?)",
+                "session-token-hash",
+            ),
+            (
+                "INSERT INTO push_devices (user_id, kind, endpoint, created_at) VALUES (?, 'webpush', ?, ?)",
+                "https://push.example/device-endpoint",
+            ),
+        ] {
+            db::query(sql)
+                .bind(user_id)
+                .bind(secret)
+                .bind(Utc::now())
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+        }
+        db::query(
+            "INSERT INTO user_avatars (user_id, image, thumbnail, updated_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(b"png".to_vec())
+        .bind(b"thumbnail".to_vec())
+        .bind(Utc::now())
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+
+        let archive = build_export(&pool, user_id).await.unwrap();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "api_tokens.json",
+                "attachments/avatar.png",
+                "contacts.json",
+                "keys.json",
+                "messages.json",
+                "notifications.json",
+                "profile.json",
+                "push.json",
+                "sessions.json",
+            ]
+        );
+
+        let mut contents = String::new();
+        for name in names.iter().filter(|name| name.ends_with(".json")) {
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+        }
+        assert!(contents.contains("\"alice\""));
+        assert!(contents.contains("\"webpush\""));
+        for secret in ["session-token-hash", "device-endpoint", "password-hash"] {
+            assert!(!contents.contains(secret), "{secret} was exported");
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_are_held_by_one_machine_while_it_renews_the_lease() {
+        let pool = db::test_pool().await;
+        let user_id: i64 = db::query(
+            "INSERT INTO users (username, password_hash, created_at) VALUES ('alice', 'password-hash', ?) RETURNING id",
+        )
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id");
+        let job_id: i64 =
+            db::query("INSERT INTO export_jobs (user_id, status) VALUES (?, ?) RETURNING id")
+                .bind(user_id)
+                .bind(STATUS_PENDING)
+                .fetch_one(pool.as_ref())
+                .await
+                .unwrap()
+                .get("id");
+
+        let now = Utc::now();
+        let lease = claim(&pool, job_id, now).await.unwrap().unwrap();
+        assert!(claim(&pool, job_id, now).await.unwrap().is_none());
+
+        // Renewed while the archive is built, so it outlasts the first lease
+        let renewed_at = now + chrono::Duration::minutes(LEASE_MINUTES / 2);
+        let renewed = renew(&pool, job_id, lease, renewed_at)
+            .await
+            .unwrap()
+            .unwrap();
+        let after_first_lease = now + chrono::Duration::minutes(LEASE_MINUTES + 1);
+        assert!(claim(&pool, job_id, after_first_lease)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The machine running it stopped without finishing
+        let after_renewal = renewed + chrono::Duration::minutes(1);
+        let taken_over = claim(&pool, job_id, after_renewal).await.unwrap().unwrap();
+
+        // The first machine lost its claim and can neither renew nor finish
+        assert!(renew(&pool, job_id, renewed, after_renewal)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!finish(&pool, job_id, renewed, Ok(b"stale".to_vec()))
+            .await
+            .unwrap());
+        assert!(finish(&pool, job_id, taken_over, Ok(b"zip".to_vec()))
+            .await
+            .unwrap());
+
+        let job = db::query("SELECT status, archive FROM export_jobs WHERE id = ?")
+            .bind(job_id)
+            .fetch_one(pool.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(job.get::<String, _>("status"), STATUS_COMPLETE);
+        assert_eq!(job.get::<Vec<u8>, _>("archive"), b"zip");
+    }
+}
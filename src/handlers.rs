@@ -1,749 +1,3716 @@
-use crate::auth::{generate_token, hash_password};
-use crate::db::DbPool;
+use crate::auth::{
+    burn_password_check, create_session, generate_api_token, generate_token, hash_password,
+    hash_token, verify_password, CurrentSession, Scope,
+};
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::email;
+use crate::error::AppError;
+use crate::events::{Event, EventHub};
+use crate::export;
 use crate::models::*;
+use crate::push;
+use crate::shutdown::Shutdown;
+use crate::store::{NewMessage, Rename, Store};
+use crate::users::{
+    claim_totp_step, clear_failed_logins, erase_account, is_username_held, record_failed_login,
+    revoke_credentials,
+};
+use crate::validation::{normalize_username, validate_password, validate_username};
+use crate::webhooks;
 use axum::{
     extract::{Extension, State},
     http::StatusCode,
     Json,
 };
-use chrono::Utc;
-use sqlx::Row;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
 
 pub async fn health_check() -> &'static str {
     "OK"
 }
 
 pub async fn create_account(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
     Json(payload): Json<CreateAccountRequest>,
-) -> Result<Json<CreateAccountResponse>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<CreateAccountResponse>, AppError> {
     // Validate username
-    if payload.username.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Username cannot be empty".to_string(),
-            }),
-        ));
-    }
+    let username = validate_username(&payload.username, &config.reserved_usernames)
+        .map_err(|e| AppError::bad_request("invalid_username", e.to_string()))?;
 
-    if payload.password.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Password cannot be empty".to_string(),
-            }),
-        ));
-    }
+    validate_password(
+        &payload.password,
+        &username,
+        config.password_min_length,
+        config.password_check_common,
+    )
+    .map_err(|e| AppError::bad_request("invalid_password", e.to_string()))?;
+
+    let username_taken = || AppError::conflict("username_taken", "Username already exists");
 
     // Check if username already exists
-    let existing_user = sqlx::query("SELECT id FROM users WHERE username = ?")
-        .bind(&payload.username)
-        .fetch_optional(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
+    if store.username_exists(&username).await? {
+        return Err(username_taken());
+    }
 
-    if existing_user.is_some() {
-        return Err((
-            StatusCode::CONFLICT,
-            Json(ErrorResponse {
-                error: "Username already exists".to_string(),
-            }),
+    if store.is_username_held(&config, &username, None).await? {
+        return Err(AppError::conflict(
+            "username_held",
+            "Username was recently released and is not yet available",
         ));
     }
 
     // Hash password
-    let password_hash = hash_password(&payload.password).map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Password hashing error: {}", e),
-            }),
-        )
-    })?;
+    let password_hash = hash_password(&payload.password)
+        .map_err(|e| AppError::internal("Password hashing error", e))?;
 
-    // Create user
-    let result = sqlx::query(
-        "INSERT INTO users (username, password_hash, created_at) VALUES (?, ?, ?)",
-    )
-    .bind(&payload.username)
-    .bind(&password_hash)
-    .bind(Utc::now().to_rfc3339())
-    .execute(pool.as_ref())
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to create user: {}", e),
-            }),
-        )
-    })?;
+    let (user_id, token) = store
+        .create_user(&username, &password_hash)
+        .await?
+        .ok_or_else(username_taken)?;
+
+    Ok(Json(CreateAccountResponse {
+        token,
+        user_id,
+        username,
+    }))
+}
 
-    let user_id = result.last_insert_rowid();
+pub async fn login(
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let invalid_credentials =
+        || AppError::unauthorized("invalid_credentials", "Invalid username or password");
 
-    // Generate token
-    let token = generate_token();
+    let Some(user) = store.find_login(&payload.username).await? else {
+        burn_password_check(&payload.password);
+        return Err(invalid_credentials());
+    };
 
-    // Create session
-    sqlx::query("INSERT INTO sessions (user_id, token, created_at) VALUES (?, ?, ?)")
-        .bind(user_id)
-        .bind(&token)
-        .bind(Utc::now().to_rfc3339())
-        .execute(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to create session: {}", e),
-                }),
-            )
-        })?;
+    let user_id = user.user_id;
 
-    Ok(Json(CreateAccountResponse {
-        token,
+    // A locked account rejects every attempt, right or wrong, until the lock
+    // lifts. It answers like a wrong password, so the lock neither tells
+    // whoever tripped it that the username exists nor confirms a guess.
+    if lockout_remaining(user.locked_until).is_some() {
+        burn_password_check(&payload.password);
+        return Err(invalid_credentials());
+    }
+
+    if !verify_password(&payload.password, &user.password_hash).unwrap_or(false) {
+        store.record_failed_login(&config, user_id).await?;
+        return Err(invalid_credentials());
+    }
+
+    let username = user.username;
+
+    // With two-factor enabled the password alone only earns a short-lived
+    // challenge, which is exchanged for a session at /api/account/login/2fa.
+    // The failure count is only reset once the whole login has succeeded.
+    if store.totp(user_id).await?.is_some() {
+        let expires_at = Utc::now() + chrono::Duration::minutes(LOGIN_CHALLENGE_MINUTES);
+        let challenge_token = store.create_login_challenge(user_id, expires_at).await?;
+
+        return Ok(Json(LoginResponse {
+            token: None,
+            user_id,
+            username,
+            two_factor_required: true,
+            challenge_token: Some(challenge_token),
+        }));
+    }
+
+    let token = store.sign_in(user_id).await?;
+
+    Ok(Json(LoginResponse {
+        token: Some(token),
         user_id,
-        username: payload.username,
+        username,
+        two_factor_required: false,
+        challenge_token: None,
     }))
 }
 
+// Time left on an account lock, if it is still in force
+fn lockout_remaining(locked_until: Option<DateTime<Utc>>) -> Option<std::time::Duration> {
+    (locked_until? - Utc::now()).to_std().ok()
+}
+
 pub async fn send_message(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    State(events): State<EventHub>,
     Extension(user_id): Extension<i64>,
     Json(payload): Json<SendMessageRequest>,
-) -> Result<Json<SendMessageResponse>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<SendMessageResponse>, AppError> {
     if payload.content.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Message content cannot be empty".to_string(),
-            }),
+        return Err(AppError::bad_request(
+            "empty_message",
+            "Message content cannot be empty",
         ));
     }
 
     // Find recipient user by username
-    let recipient = sqlx::query("SELECT id FROM users WHERE username = ?")
-        .bind(&payload.to_username)
-        .fetch_optional(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
-
-    let recipient_id: i64 = match recipient {
-        Some(row) => row.get("id"),
-        None => {
-            return Err((
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: "Recipient user not found".to_string(),
-                }),
-            ))
-        }
-    };
+    let recipient_id = store
+        .resolve_user_id(&config, &payload.to_username)
+        .await?
+        .ok_or_else(|| AppError::not_found("user_not_found", "Recipient user not found"))?;
 
-    // Insert message
-    let created_at = Utc::now();
-    let result = sqlx::query(
-        "INSERT INTO messages (from_user_id, to_user_id, content, created_at) VALUES (?, ?, ?, ?)",
+    let encrypted = payload.encrypted.unwrap_or(true);
+    let response = deliver_message(
+        store.as_ref(),
+        &events,
+        user_id,
+        recipient_id,
+        &payload.content,
+        encrypted,
+        None,
     )
-    .bind(user_id)
-    .bind(recipient_id)
-    .bind(&payload.content)
-    .bind(created_at.to_rfc3339())
-    .execute(pool.as_ref())
     .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to send message: {}", e),
-            }),
-        )
-    })?;
+    .map_err(|e| AppError::internal("Failed to send message", e))?;
+
+    Ok(Json(response))
+}
+
+/// Store a message and announce it to long-polls, bots, webhooks and push
+/// notifications. Every way of sending a message goes through here;
+/// `via_integration` names the incoming webhook that posted it, if any.
+pub async fn deliver_message(
+    store: &dyn Store,
+    events: &EventHub,
+    from_user_id: i64,
+    to_user_id: i64,
+    content: &str,
+    encrypted: bool,
+    via_integration: Option<&str>,
+) -> Result<SendMessageResponse, sqlx::Error> {
+    let response = store
+        .insert_message(NewMessage {
+            from_user_id,
+            to_user_id,
+            content,
+            encrypted,
+            via_integration,
+        })
+        .await?;
 
-    let message_id = result.last_insert_rowid();
+    crate::metrics::message_sent(via_integration.is_some());
 
-    Ok(Json(SendMessageResponse {
-        message_id,
-        created_at,
-    }))
+    events.publish(Event::MessageCreated {
+        message_id: response.message_id,
+        from_user_id,
+        to_user_id,
+    });
+
+    Ok(response)
+}
+
+/// The account `username` refers to, or a 404.
+async fn find_user_id(store: &dyn Store, config: &Config, username: &str) -> Result<i64, AppError> {
+    store
+        .resolve_user_id(config, username)
+        .await?
+        .ok_or_else(|| AppError::not_found("user_not_found", "User not found"))
 }
 
 pub async fn get_messages(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
     Extension(user_id): Extension<i64>,
-) -> Result<Json<Vec<MessageResponse>>, (StatusCode, Json<ErrorResponse>)> {
-    let rows = sqlx::query(
-        r#"
-        SELECT
-            m.id,
-            m.content,
-            m.created_at,
-            from_user.username as from_username,
-            to_user.username as to_username
-        FROM messages m
-        JOIN users from_user ON m.from_user_id = from_user.id
-        JOIN users to_user ON m.to_user_id = to_user.id
-        WHERE m.to_user_id = ? OR m.from_user_id = ?
-        ORDER BY m.created_at DESC
-        "#,
-    )
-    .bind(user_id)
-    .bind(user_id)
-    .fetch_all(pool.as_ref())
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Database error: {}", e),
-            }),
-        )
-    })?;
-
-    let messages: Vec<MessageResponse> = rows
-        .iter()
-        .map(|row| {
-            let created_at_str: String = row.get("created_at");
-            MessageResponse {
-                id: row.get("id"),
-                from_username: row.get("from_username"),
-                to_username: row.get("to_username"),
-                content: row.get("content"),
-                created_at: created_at_str.parse().unwrap_or(Utc::now()),
-            }
-        })
-        .collect();
-
-    Ok(Json(messages))
+) -> Result<Json<Vec<MessageResponse>>, AppError> {
+    Ok(Json(store.messages_for(user_id).await?))
 }
 
 pub async fn get_conversations(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
     Extension(user_id): Extension<i64>,
-) -> Result<Json<Vec<ConversationResponse>>, (StatusCode, Json<ErrorResponse>)> {
-    // Get all conversations with latest message info
-    let rows = sqlx::query(
-        r#"
-        SELECT
-            CASE
-                WHEN m.from_user_id = ? THEN to_user.username
-                ELSE from_user.username
-            END as other_username,
-            m.content as last_message,
-            m.created_at as last_message_time,
-            COUNT(CASE WHEN m.to_user_id = ? AND m.from_user_id != ? AND m.read_at IS NULL THEN 1 END) as unread_count
-        FROM messages m
-        JOIN users from_user ON m.from_user_id = from_user.id
-        JOIN users to_user ON m.to_user_id = to_user.id
-        WHERE m.from_user_id = ? OR m.to_user_id = ?
-        GROUP BY other_username
-        ORDER BY m.created_at DESC
-        "#,
-    )
-    .bind(user_id)
-    .bind(user_id)
-    .bind(user_id)
-    .bind(user_id)
-    .bind(user_id)
-    .fetch_all(pool.as_ref())
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Database error: {}", e),
-            }),
-        )
-    })?;
-
-    let conversations: Vec<ConversationResponse> = rows
-        .iter()
-        .map(|row| {
-            let last_message_time_str: String = row.get("last_message_time");
-            ConversationResponse {
-                username: row.get("other_username"),
-                last_message: row.get("last_message"),
-                last_message_time: last_message_time_str.parse().unwrap_or(Utc::now()),
-                unread_count: row.get("unread_count"),
-            }
-        })
-        .collect();
-
-    Ok(Json(conversations))
+) -> Result<Json<Vec<ConversationResponse>>, AppError> {
+    Ok(Json(store.conversations(user_id).await?))
 }
 
 pub async fn update_username(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
     Extension(user_id): Extension<i64>,
     Json(payload): Json<UpdateUsernameRequest>,
-) -> Result<Json<UpdateUsernameResponse>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<UpdateUsernameResponse>, AppError> {
     // Validate username
-    if payload.new_username.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Username cannot be empty".to_string(),
-            }),
+    let new_username = validate_username(&payload.new_username, &config.reserved_usernames)
+        .map_err(|e| AppError::bad_request("invalid_username", e.to_string()))?;
+
+    match store.rename_user(&config, user_id, &new_username).await? {
+        Rename::Renamed(updated_at) => Ok(Json(UpdateUsernameResponse {
+            username: new_username,
+            updated_at,
+        })),
+        Rename::Taken => Err(AppError::conflict(
+            "username_taken",
+            "Username already exists",
+        )),
+        Rename::Held => Err(AppError::conflict(
+            "username_held",
+            "Username was recently released and is not yet available",
+        )),
+    }
+}
+
+pub async fn change_password(
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    Extension(session): Extension<CurrentSession>,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<Json<ChangePasswordResponse>, AppError> {
+    let credentials = store.credentials(session.user_id).await?;
+
+    // Treat a malformed stored hash as a mismatch rather than a server error
+    if !verify_password(&payload.current_password, &credentials.password_hash).unwrap_or(false) {
+        return Err(AppError::unauthorized(
+            "incorrect_password",
+            "Current password is incorrect",
         ));
     }
 
-    // Check if username already exists (for a different user)
-    let existing_user = sqlx::query("SELECT id FROM users WHERE username = ? AND id != ?")
-        .bind(&payload.new_username)
-        .bind(user_id)
-        .fetch_optional(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
+    validate_password(
+        &payload.new_password,
+        &credentials.username,
+        config.password_min_length,
+        config.password_check_common,
+    )
+    .map_err(|e| AppError::bad_request("invalid_password", e.to_string()))?;
 
-    if existing_user.is_some() {
-        return Err((
-            StatusCode::CONFLICT,
-            Json(ErrorResponse {
-                error: "Username already exists".to_string(),
-            }),
+    let new_hash = hash_password(&payload.new_password)
+        .map_err(|e| AppError::internal("Password hashing error", e))?;
+
+    // Sign out everywhere except the session that made the change, tokens
+    // included
+    let revoked_sessions = store
+        .set_password(session.user_id, &new_hash, session.id)
+        .await?;
+
+    Ok(Json(ChangePasswordResponse { revoked_sessions }))
+}
+
+pub async fn delete_account(
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<DeleteAccountRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let credentials = store.credentials(user_id).await?;
+    if !verify_password(&payload.password, &credentials.password_hash).unwrap_or(false) {
+        return Err(AppError::unauthorized(
+            "incorrect_password",
+            "Password is incorrect",
         ));
     }
 
-    // Update the username
-    let updated_at = Utc::now();
-    sqlx::query("UPDATE users SET username = ? WHERE id = ?")
-        .bind(&payload.new_username)
-        .bind(user_id)
-        .execute(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to update username: {}", e),
-                }),
+    let invalid_code =
+        || AppError::unauthorized("invalid_two_factor_code", "Invalid two-factor code");
+    if let Some(totp) = store.totp(user_id).await? {
+        let step = payload.code.as_deref().and_then(|code| {
+            crate::totp::verify(
+                &totp.secret,
+                code,
+                Utc::now().timestamp() as u64,
+                totp.last_used_step,
             )
-        })?;
+        });
+        let Some(step) = step else {
+            return Err(invalid_code());
+        };
+        if !store.claim_totp_step(user_id, step).await? {
+            return Err(invalid_code());
+        }
+    }
 
-    Ok(Json(UpdateUsernameResponse {
-        username: payload.new_username,
-        updated_at,
-    }))
+    store.delete_user(&config, user_id).await?;
+
+    Ok(Json(serde_json::json!({ "deleted": true })))
 }
 
 pub async fn get_filtered_messages(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
     Extension(user_id): Extension<i64>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Result<Json<Vec<MessageResponse>>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<Vec<MessageResponse>>, AppError> {
     let with_user = params.get("with_user");
 
     if let Some(username) = with_user {
-        // Get the other user's ID
-        let other_user = sqlx::query("SELECT id FROM users WHERE username = ?")
-            .bind(username)
-            .fetch_optional(pool.as_ref())
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Database error: {}", e),
-                    }),
-                )
-            })?;
-
-        let other_user_id: i64 = match other_user {
-            Some(row) => row.get("id"),
-            None => {
-                return Err((
-                    StatusCode::NOT_FOUND,
-                    Json(ErrorResponse {
-                        error: "User not found".to_string(),
-                    }),
-                ))
-            }
-        };
-
         // Get messages between the two users
-        let rows = sqlx::query(
-            r#"
-            SELECT
-                m.id,
-                m.content,
-                m.created_at,
-                from_user.username as from_username,
-                to_user.username as to_username
-            FROM messages m
-            JOIN users from_user ON m.from_user_id = from_user.id
-            JOIN users to_user ON m.to_user_id = to_user.id
-            WHERE (m.from_user_id = ? AND m.to_user_id = ?)
-               OR (m.from_user_id = ? AND m.to_user_id = ?)
-            ORDER BY m.created_at DESC
-            "#,
-        )
-        .bind(user_id)
-        .bind(other_user_id)
-        .bind(other_user_id)
-        .bind(user_id)
-        .fetch_all(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
-
-        let messages: Vec<MessageResponse> = rows
-            .iter()
-            .map(|row| {
-                let created_at_str: String = row.get("created_at");
-                MessageResponse {
-                    id: row.get("id"),
-                    from_username: row.get("from_username"),
-                    to_username: row.get("to_username"),
-                    content: row.get("content"),
-                    created_at: created_at_str.parse().unwrap_or(Utc::now()),
-                }
-            })
-            .collect();
-
-        Ok(Json(messages))
+        let other_user_id = find_user_id(store.as_ref(), &config, username).await?;
+        Ok(Json(store.messages_between(user_id, other_user_id).await?))
     } else {
         // No filter, return all messages (same as get_messages)
-        let rows = sqlx::query(
-            r#"
-            SELECT
-                m.id,
-                m.content,
-                m.created_at,
-                from_user.username as from_username,
-                to_user.username as to_username
-            FROM messages m
-            JOIN users from_user ON m.from_user_id = from_user.id
-            JOIN users to_user ON m.to_user_id = to_user.id
-            WHERE m.to_user_id = ? OR m.from_user_id = ?
-            ORDER BY m.created_at DESC
-            "#,
-        )
-        .bind(user_id)
-        .bind(user_id)
-        .fetch_all(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
-
-        let messages: Vec<MessageResponse> = rows
-            .iter()
-            .map(|row| {
-                let created_at_str: String = row.get("created_at");
-                MessageResponse {
-                    id: row.get("id"),
-                    from_username: row.get("from_username"),
-                    to_username: row.get("to_username"),
-                    content: row.get("content"),
-                    created_at: created_at_str.parse().unwrap_or(Utc::now()),
-                }
-            })
-            .collect();
-
-        Ok(Json(messages))
+        Ok(Json(store.messages_for(user_id).await?))
     }
 }
 
 pub async fn mark_messages_read(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    State(events): State<EventHub>,
     Extension(user_id): Extension<i64>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Result<Json<serde_json::Value>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<serde_json::Value>, AppError> {
     let with_user = params.get("with_user");
 
     if let Some(username) = with_user {
-        // Get the other user's ID
-        let other_user = sqlx::query("SELECT id FROM users WHERE username = ?")
-            .bind(username)
-            .fetch_optional(pool.as_ref())
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Database error: {}", e),
-                    }),
-                )
-            })?;
-
-        let other_user_id: i64 = match other_user {
-            Some(row) => row.get("id"),
-            None => {
-                return Err((
-                    StatusCode::NOT_FOUND,
-                    Json(ErrorResponse {
-                        error: "User not found".to_string(),
-                    }),
-                ))
-            }
-        };
+        let other_user_id = find_user_id(store.as_ref(), &config, username).await?;
 
         // Mark all messages from other_user to current user as read
-        let read_at = Utc::now();
-        let result = sqlx::query(
-            "UPDATE messages SET read_at = ? WHERE from_user_id = ? AND to_user_id = ? AND read_at IS NULL"
-        )
-        .bind(read_at.to_rfc3339())
-        .bind(other_user_id)
-        .bind(user_id)
-        .execute(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to mark messages as read: {}", e),
-                }),
-            )
-        })?;
+        let marked = store
+            .mark_read(user_id, other_user_id)
+            .await
+            .map_err(|e| AppError::internal("Failed to mark messages as read", e))?;
+
+        if marked > 0 {
+            events.publish(Event::MessagesRead {
+                reader_id: user_id,
+                sender_id: other_user_id,
+                count: marked,
+            });
+        }
 
         Ok(Json(serde_json::json!({
-            "marked_read": result.rows_affected()
+            "marked_read": marked
         })))
     } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "with_user parameter is required".to_string(),
-            }),
+        Err(AppError::bad_request(
+            "missing_parameter",
+            "with_user parameter is required",
         ))
     }
 }
 
 // E2E Encryption endpoints
 pub async fn upload_keys(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(events): State<EventHub>,
     Extension(user_id): Extension<i64>,
     Json(payload): Json<UploadKeysRequest>,
-) -> Result<Json<UploadKeysResponse>, (StatusCode, Json<ErrorResponse>)> {
-    // Check if user already has keys
-    let existing_keys = sqlx::query("SELECT user_id FROM user_keys WHERE user_id = ?")
-        .bind(user_id)
-        .fetch_optional(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
-
-    if existing_keys.is_some() {
-        // Update existing keys
-        sqlx::query(
-            "UPDATE user_keys SET identity_key = ?, signed_prekey = ?, signed_prekey_signature = ? WHERE user_id = ?",
-        )
-        .bind(&payload.key_bundle.identity_key)
-        .bind(&payload.key_bundle.signed_prekey)
-        .bind(&payload.key_bundle.signed_prekey_signature)
-        .bind(user_id)
-        .execute(pool.as_ref())
+) -> Result<Json<UploadKeysResponse>, AppError> {
+    store
+        .replace_key_bundle(user_id, &payload.key_bundle)
         .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to update keys: {}", e),
-                }),
-            )
-        })?;
-
-        // Delete old one-time prekeys
-        sqlx::query("DELETE FROM one_time_prekeys WHERE user_id = ?")
-            .bind(user_id)
-            .execute(pool.as_ref())
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Failed to delete old prekeys: {}", e),
-                    }),
-                )
-            })?;
-    } else {
-        // Insert new keys
-        sqlx::query(
-            "INSERT INTO user_keys (user_id, identity_key, signed_prekey, signed_prekey_signature, created_at) VALUES (?, ?, ?, ?, ?)",
-        )
-        .bind(user_id)
-        .bind(&payload.key_bundle.identity_key)
-        .bind(&payload.key_bundle.signed_prekey)
-        .bind(&payload.key_bundle.signed_prekey_signature)
-        .bind(Utc::now().to_rfc3339())
-        .execute(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to insert keys: {}", e),
-                }),
-            )
-        })?;
-    }
+        .map_err(|e| AppError::internal("Failed to store keys", e))?;
 
-    // Insert one-time prekeys
-    for (i, prekey) in payload.key_bundle.one_time_prekeys.iter().enumerate() {
-        sqlx::query(
-            "INSERT INTO one_time_prekeys (user_id, key_id, public_key, used, created_at) VALUES (?, ?, ?, ?, ?)",
-        )
-        .bind(user_id)
-        .bind(i as i64)
-        .bind(prekey)
-        .bind(false)
-        .bind(Utc::now().to_rfc3339())
-        .execute(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to insert one-time prekey: {}", e),
-                }),
-            )
-        })?;
-    }
+    events.publish(Event::KeysUpdated { user_id });
 
     Ok(Json(UploadKeysResponse { success: true }))
 }
 
+// One-time prekeys handed out with a bundle
+const PREKEYS_PER_BUNDLE: usize = 10;
+
 pub async fn get_keys(
-    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
     axum::extract::Path(username): axum::extract::Path<String>,
-) -> Result<Json<GetKeysResponse>, (StatusCode, Json<ErrorResponse>)> {
-    // Get user ID from username
-    let user = sqlx::query("SELECT id FROM users WHERE username = ?")
-        .bind(&username)
-        .fetch_optional(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
+) -> Result<Json<GetKeysResponse>, AppError> {
+    let user_id = find_user_id(store.as_ref(), &config, &username).await?;
 
-    let user_id: i64 = match user {
-        Some(row) => row.get("id"),
-        None => {
-            return Err((
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: "User not found".to_string(),
-                }),
+    let key_bundle = store
+        .claim_key_bundle(user_id, PREKEYS_PER_BUNDLE)
+        .await?
+        .ok_or_else(|| AppError::not_found("keys_not_found", "Keys not found for this user"))?;
+
+    Ok(Json(GetKeysResponse { key_bundle }))
+}
+
+// Profile endpoints
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+const MAX_BIO_CHARS: usize = 500;
+
+pub async fn get_user_profile(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<Json<UserProfileResponse>, AppError> {
+    let user_id = find_user_id(store.as_ref(), &config, &username).await?;
+
+    fetch_profile(&pool, user_id).await.map(Json)
+}
+
+async fn fetch_profile(pool: &DbPool, user_id: i64) -> Result<UserProfileResponse, AppError> {
+    let row = db::query(
+        r#"
+        SELECT
+            u.username,
+            u.display_name,
+            u.bio,
+            u.created_at,
+            a.user_id IS NOT NULL as has_avatar
+        FROM users u
+        LEFT JOIN user_avatars a ON a.user_id = u.id
+        WHERE u.id = ?
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await?;
+
+    Ok(UserProfileResponse {
+        username: row.get("username"),
+        display_name: row.get("display_name"),
+        bio: row.get("bio"),
+        has_avatar: row.get("has_avatar"),
+        created_at: row.get("created_at"),
+    })
+}
+
+pub async fn get_user_avatar(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    fetch_avatar(&pool, store.as_ref(), &config, &username, "image").await
+}
+
+pub async fn get_user_avatar_thumbnail(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    fetch_avatar(&pool, store.as_ref(), &config, &username, "thumbnail").await
+}
+
+async fn fetch_avatar(
+    pool: &DbPool,
+    store: &dyn Store,
+    config: &Config,
+    username: &str,
+    column: &'static str,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let row = match store.resolve_user_id(config, username).await? {
+        Some(user_id) => {
+            db::query(format!(
+                "SELECT {} as data FROM user_avatars WHERE user_id = ?",
+                column
             ))
+            .bind(user_id)
+            .fetch_optional(pool.as_ref())
+            .await?
         }
+        None => None,
     };
 
-    // Get user keys
-    let keys_row = sqlx::query("SELECT user_id, identity_key, signed_prekey, signed_prekey_signature, created_at FROM user_keys WHERE user_id = ?")
-        .bind(user_id)
-        .fetch_optional(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", e),
-                }),
-            )
-        })?;
+    match row {
+        Some(row) => {
+            let data: Vec<u8> = row.get("data");
+            Ok((
+                [
+                    (axum::http::header::CONTENT_TYPE, "image/png"),
+                    (axum::http::header::CACHE_CONTROL, "public, max-age=300"),
+                ],
+                data,
+            ))
+        }
+        None => Err(AppError::not_found("avatar_not_found", "Avatar not found")),
+    }
+}
 
-    let keys = match keys_row {
-        Some(row) => UserKey {
-            user_id: row.get("user_id"),
-            identity_key: row.get("identity_key"),
-            signed_prekey: row.get("signed_prekey"),
-            signed_prekey_signature: row.get("signed_prekey_signature"),
-            created_at: row.get("created_at"),
-        },
-        None => {
-            return Err((
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: "Keys not found for this user".to_string(),
-                }),
+// Trims a display name for storage; an empty name means none
+fn validate_display_name(name: &str) -> Result<Option<String>, String> {
+    let name = name.trim();
+    if name.chars().count() > MAX_DISPLAY_NAME_CHARS {
+        return Err(format!(
+            "Display name must be at most {} characters",
+            MAX_DISPLAY_NAME_CHARS
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("Display name cannot contain control characters".to_string());
+    }
+    Ok(Some(name.to_string()).filter(|n| !n.is_empty()))
+}
+
+pub async fn update_profile(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Result<Json<UserProfileResponse>, AppError> {
+    let display_name = payload
+        .display_name
+        .as_deref()
+        .map(validate_display_name)
+        .transpose()
+        .map_err(|e| AppError::bad_request("invalid_display_name", e))?;
+
+    let bio = match payload.bio.as_deref().map(str::trim) {
+        Some(bio) if bio.chars().count() > MAX_BIO_CHARS => {
+            return Err(AppError::bad_request(
+                "invalid_bio",
+                format!("Bio must be at most {} characters", MAX_BIO_CHARS),
+            ))
+        }
+        Some(bio) if bio.chars().any(|c| c.is_control() && c != '\n') => {
+            return Err(AppError::bad_request(
+                "invalid_bio",
+                "Bio cannot contain control characters".to_string(),
             ))
         }
+        other => other,
+    };
+
+    // Decode and thumbnail the avatar before touching the database
+    let avatar = match payload.avatar.as_deref() {
+        Some("") => Some(None),
+        Some(encoded) => {
+            use base64::Engine;
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|_| {
+                    AppError::bad_request("invalid_avatar", "Avatar must be base64-encoded")
+                })?;
+            // Decoding and resizing take long enough to stall other requests
+            // on this worker, so they run on the blocking pool
+            let processed =
+                tokio::task::spawn_blocking(move || crate::avatar::process_avatar(&bytes))
+                    .await
+                    .map_err(|e| AppError::internal("Avatar processing failed", e))?
+                    .map_err(|e| AppError::bad_request("invalid_avatar", e.to_string()))?;
+            Some(Some(processed))
+        }
+        None => None,
+    };
+
+    let mut tx = pool.begin().await?;
+
+    if let Some(name) = display_name {
+        db::query("UPDATE users SET display_name = ? WHERE id = ?")
+            .bind(name)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    if let Some(bio) = bio {
+        db::query("UPDATE users SET bio = ? WHERE id = ?")
+            .bind(Some(bio).filter(|b| !b.is_empty()))
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    match avatar {
+        Some(Some(processed)) => {
+            db::query(
+                r#"
+                INSERT INTO user_avatars (user_id, image, thumbnail, updated_at) VALUES (?, ?, ?, ?)
+                ON CONFLICT(user_id) DO UPDATE SET
+                    image = excluded.image,
+                    thumbnail = excluded.thumbnail,
+                    updated_at = excluded.updated_at
+                "#,
+            )
+            .bind(user_id)
+            .bind(&processed.image)
+            .bind(&processed.thumbnail)
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await?;
+        }
+        Some(None) => {
+            db::query("DELETE FROM user_avatars WHERE user_id = ?")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        None => {}
+    }
+
+    tx.commit().await?;
+
+    fetch_profile(&pool, user_id).await.map(Json)
+}
+
+// User directory endpoints
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 50;
+// Matches kept per search, which bounds how far results page
+const MAX_SEARCH_RESULTS: usize = 500;
+// Candidates read from the database at a time
+const SEARCH_BATCH: i64 = 500;
+
+pub async fn search_users(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Query(params): axum::extract::Query<UserSearchQuery>,
+) -> Result<Json<UserSearchResponse>, AppError> {
+    let query = params.q.trim();
+    if query.is_empty() {
+        return Err(AppError::bad_request(
+            "invalid_query",
+            "q parameter cannot be empty",
+        ));
+    }
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    // The database narrows the directory down to accounts that could match,
+    // read in batches of SEARCH_BATCH, and ranking happens in Rust, keeping
+    // the best MAX_SEARCH_RESULTS so far. Postgres refuses LIKE under the
+    // nondeterministic nocase collation, hence the lowercased "C" copies.
+    let (username, display_name) = match pool.backend() {
+        db::Backend::Sqlite => ("LOWER(u.username)", "LOWER(u.display_name)"),
+        db::Backend::Postgres => (
+            "LOWER(u.username) COLLATE \"C\"",
+            "LOWER(u.display_name) COLLATE \"C\"",
+        ),
     };
+    let patterns = crate::search::candidate_patterns(query);
+    let matches_any = patterns
+        .iter()
+        .map(|_| format!("{username} LIKE ? ESCAPE '\\' OR {display_name} LIKE ? ESCAPE '\\'"))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let sql = format!(
+        r#"
+        SELECT
+            u.id,
+            u.username,
+            u.display_name,
+            a.user_id IS NOT NULL as has_avatar
+        FROM users u
+        LEFT JOIN user_avatars a ON a.user_id = u.id
+        WHERE u.discoverable = TRUE AND u.id != ? AND u.id > ? AND ({matches_any})
+        ORDER BY u.id
+        LIMIT ?
+        "#
+    );
+
+    let mut ranked = Vec::new();
+    let mut after_id: i64 = 0;
+    loop {
+        let mut batch = db::query(sql.as_str()).bind(user_id).bind(after_id);
+        for pattern in &patterns {
+            batch = batch.bind(pattern).bind(pattern);
+        }
+        let rows = batch.bind(SEARCH_BATCH).fetch_all(pool.as_ref()).await?;
+
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after_id = last.get("id");
+        ranked.extend(rows.iter().map(|row| crate::search::Candidate {
+            username: row.get("username"),
+            display_name: row.get("display_name"),
+            has_avatar: row.get("has_avatar"),
+        }));
+        ranked = crate::search::rank(query, ranked);
+        ranked.truncate(MAX_SEARCH_RESULTS);
+
+        if (rows.len() as i64) < SEARCH_BATCH {
+            break;
+        }
+    }
+
+    let total = ranked.len() as i64;
+    let results: Vec<UserSearchResult> = ranked
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|c| UserSearchResult {
+            username: c.username,
+            display_name: c.display_name,
+            has_avatar: c.has_avatar,
+        })
+        .collect();
+
+    let next_offset = Some(offset + limit).filter(|&next| next < total);
+
+    Ok(Json(UserSearchResponse {
+        results,
+        next_offset,
+    }))
+}
+
+pub async fn get_account_settings(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<AccountSettingsResponse>, AppError> {
+    let row = db::query("SELECT discoverable, email_digests FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?;
+
+    Ok(Json(AccountSettingsResponse {
+        discoverable: row.get("discoverable"),
+        email_digests: row.get("email_digests"),
+    }))
+}
+
+pub async fn update_account_settings(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<UpdateAccountSettingsRequest>,
+) -> Result<Json<AccountSettingsResponse>, AppError> {
+    if let Some(discoverable) = payload.discoverable {
+        db::query("UPDATE users SET discoverable = ? WHERE id = ?")
+            .bind(discoverable)
+            .bind(user_id)
+            .execute(pool.as_ref())
+            .await
+            .map_err(|e| AppError::internal("Failed to update settings", e))?;
+    }
+
+    if let Some(email_digests) = payload.email_digests {
+        db::query("UPDATE users SET email_digests = ? WHERE id = ?")
+            .bind(email_digests)
+            .bind(user_id)
+            .execute(pool.as_ref())
+            .await
+            .map_err(|e| AppError::internal("Failed to update settings", e))?;
+    }
+
+    get_account_settings(State(pool), Extension(user_id)).await
+}
+
+// Notification endpoints
+pub async fn get_notifications(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Result<Json<Vec<NotificationResponse>>, AppError> {
+    let unread_only = params.get("unread_only").is_some_and(|v| v == "true");
 
-    // Get unused one-time prekeys (limit to 10)
-    let prekeys_rows = sqlx::query(
-        "SELECT id, user_id, key_id, public_key, used, created_at FROM one_time_prekeys WHERE user_id = ? AND used = ? LIMIT 10",
+    let rows = db::query(
+        r#"
+        SELECT id, kind, payload, created_at, read_at
+        FROM notifications
+        WHERE user_id = ? AND (? = FALSE OR read_at IS NULL)
+        ORDER BY id DESC
+        LIMIT 100
+        "#,
     )
     .bind(user_id)
-    .bind(false)
+    .bind(unread_only)
     .fetch_all(pool.as_ref())
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Database error: {}", e),
-            }),
-        )
-    })?;
+    .await?;
 
-    let one_time_prekeys: Vec<String> = prekeys_rows
+    let notifications = rows
         .iter()
-        .map(|row| row.get::<String, _>("public_key"))
+        .map(|row| {
+            let payload: String = row.get("payload");
+            NotificationResponse {
+                id: row.get("id"),
+                kind: row.get("kind"),
+                payload: serde_json::from_str(&payload).unwrap_or(serde_json::Value::Null),
+                created_at: row.get("created_at"),
+                read_at: row.get("read_at"),
+            }
+        })
         .collect();
 
-    // Mark the first one-time prekey as used (X3DH protocol requirement)
-    if !prekeys_rows.is_empty() {
-        let first_prekey_id: i64 = prekeys_rows[0].get("id");
-        let _ = sqlx::query("UPDATE one_time_prekeys SET used = ? WHERE id = ?")
-            .bind(true)
-            .bind(first_prekey_id)
+    Ok(Json(notifications))
+}
+
+pub async fn mark_notifications_read(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let result =
+        db::query("UPDATE notifications SET read_at = ? WHERE user_id = ? AND read_at IS NULL")
+            .bind(Utc::now())
+            .bind(user_id)
             .execute(pool.as_ref())
-            .await;
-        // Note: We don't fail if this update fails, just log it
+            .await
+            .map_err(|e| AppError::internal("Failed to mark notifications as read", e))?;
+
+    Ok(Json(serde_json::json!({
+        "marked_read": result.rows_affected()
+    })))
+}
+
+// Personal access token endpoints
+const MAX_API_TOKEN_DESCRIPTION_CHARS: usize = 100;
+const MAX_API_TOKEN_EXPIRY_DAYS: i64 = 365;
+const MAX_API_TOKENS_PER_USER: i64 = 50;
+
+pub async fn create_api_token(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<CreateApiTokenRequest>,
+) -> Result<Json<CreateApiTokenResponse>, AppError> {
+    issue_api_token(&pool, user_id, payload).await.map(Json)
+}
+
+pub async fn list_api_tokens(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<Vec<ApiTokenResponse>>, AppError> {
+    fetch_api_tokens(&pool, user_id).await.map(Json)
+}
+
+pub async fn revoke_api_token(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(token_id): axum::extract::Path<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    delete_api_token(&pool, user_id, token_id).await.map(Json)
+}
+
+// Shared by the personal token endpoints and bot token management, where
+// `user_id` is the bot's
+async fn issue_api_token(
+    pool: &DbPool,
+    user_id: i64,
+    payload: CreateApiTokenRequest,
+) -> Result<CreateApiTokenResponse, AppError> {
+    let description = payload.description.trim().to_string();
+    if description.is_empty() || description.chars().count() > MAX_API_TOKEN_DESCRIPTION_CHARS {
+        return Err(AppError::bad_request(
+            "invalid_description",
+            format!(
+                "Description must be 1-{} characters",
+                MAX_API_TOKEN_DESCRIPTION_CHARS
+            ),
+        ));
+    }
+
+    let mut scopes = Vec::new();
+    for scope in &payload.scopes {
+        let scope: Scope = scope
+            .parse()
+            .map_err(|e| AppError::bad_request("invalid_scope", e))?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        return Err(AppError::bad_request(
+            "invalid_scope",
+            "At least one scope is required",
+        ));
+    }
+
+    let expires_at = match payload.expires_in_days {
+        Some(days) if !(1..=MAX_API_TOKEN_EXPIRY_DAYS).contains(&days) => {
+            return Err(AppError::bad_request(
+                "invalid_expiry",
+                format!(
+                    "expires_in_days must be between 1 and {}",
+                    MAX_API_TOKEN_EXPIRY_DAYS
+                ),
+            ));
+        }
+        Some(days) => Some(Utc::now() + chrono::Duration::days(days)),
+        None => None,
+    };
+
+    let existing: i64 = db::query("SELECT COUNT(*) as count FROM api_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("count");
+    if existing >= MAX_API_TOKENS_PER_USER {
+        return Err(AppError::bad_request(
+            "limit_reached",
+            format!(
+                "At most {} tokens are allowed; revoke one first",
+                MAX_API_TOKENS_PER_USER
+            ),
+        ));
+    }
+
+    let token = generate_api_token();
+    let now = Utc::now();
+    let scope_names: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+
+    let id = db::query(
+        "INSERT INTO api_tokens (user_id, token_hash, description, scopes, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(&description)
+    .bind(scope_names.join(","))
+    .bind(expires_at)
+    .bind(now)
+    .fetch_one(pool.as_ref())
+    .await?
+    .get::<i64, _>("id");
+
+    Ok(CreateApiTokenResponse {
+        token,
+        api_token: ApiTokenResponse {
+            id,
+            description,
+            scopes: scope_names,
+            expires_at,
+            last_used_at: None,
+            created_at: now,
+        },
+    })
+}
+
+async fn fetch_api_tokens(pool: &DbPool, user_id: i64) -> Result<Vec<ApiTokenResponse>, AppError> {
+    let rows = db::query(
+        r#"
+        SELECT id, description, scopes, expires_at, last_used_at, created_at
+        FROM api_tokens
+        WHERE user_id = ?
+        ORDER BY id DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    let tokens = rows
+        .iter()
+        .map(|row| {
+            let scopes: String = row.get("scopes");
+            ApiTokenResponse {
+                id: row.get("id"),
+                description: row.get("description"),
+                scopes: scopes.split(',').map(|s| s.to_string()).collect(),
+                expires_at: row.get("expires_at"),
+                last_used_at: row.get("last_used_at"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .collect();
+
+    Ok(tokens)
+}
+
+async fn delete_api_token(
+    pool: &DbPool,
+    user_id: i64,
+    token_id: i64,
+) -> Result<serde_json::Value, AppError> {
+    let result = db::query("DELETE FROM api_tokens WHERE id = ? AND user_id = ?")
+        .bind(token_id)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| AppError::internal("Failed to revoke token", e))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found("token_not_found", "Token not found"));
+    }
+
+    Ok(serde_json::json!({ "revoked": true }))
+}
+
+// Bot endpoints
+const MAX_BOTS_PER_USER: i64 = 10;
+
+pub async fn create_bot(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<CreateBotRequest>,
+) -> Result<Json<CreateBotResponse>, AppError> {
+    let username = validate_username(&payload.username, &config.reserved_usernames)
+        .map_err(|e| AppError::bad_request("invalid_username", e.to_string()))?;
+    let display_name = match payload.display_name.as_deref() {
+        Some(name) => validate_display_name(name)
+            .map_err(|e| AppError::bad_request("invalid_display_name", e))?,
+        None => None,
+    };
+    let webhook_url = payload.webhook_url.filter(|url| !url.trim().is_empty());
+    if let Some(url) = &webhook_url {
+        crate::outbound::validate_url(url, config.allow_private_endpoints)
+            .map_err(|e| AppError::bad_request("invalid_webhook_url", e))?;
+    }
+
+    let owned: i64 = db::query(
+        "SELECT COUNT(*) as count FROM users WHERE bot_owner_id = ? AND is_bot = TRUE AND deleted_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await?
+    .get("count");
+    if owned >= MAX_BOTS_PER_USER {
+        return Err(AppError::bad_request(
+            "limit_reached",
+            format!("At most {} bots are allowed per account", MAX_BOTS_PER_USER),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let existing = db::query("SELECT id FROM users WHERE username = ? COLLATE NOCASE")
+        .bind(&username)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if existing.is_some() {
+        return Err(AppError::conflict(
+            "username_taken",
+            "Username already exists",
+        ));
+    }
+    if is_username_held(&mut tx, &config, &username, None).await? {
+        return Err(AppError::conflict(
+            "username_held",
+            "Username was recently released and is not yet available",
+        ));
     }
 
-    Ok(Json(GetKeysResponse {
-        key_bundle: KeyBundle {
-            identity_key: keys.identity_key,
-            signed_prekey: keys.signed_prekey,
-            signed_prekey_signature: keys.signed_prekey_signature,
-            one_time_prekeys,
+    let now = Utc::now();
+    let bot_id = db::query(
+        r#"
+        INSERT INTO users (username, password_hash, display_name, is_bot, bot_owner_id, created_at)
+        VALUES (?, '', ?, TRUE, ?, ?)
+         RETURNING id"#,
+    )
+    .bind(&username)
+    .bind(&display_name)
+    .bind(user_id)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        if db::is_unique_violation(&e) {
+            return AppError::conflict("username_taken", "Username already exists");
+        }
+        AppError::from(e)
+    })?
+    .get::<i64, _>("id");
+
+    let webhook_secret = crate::bots::set_webhook(&mut tx, bot_id, webhook_url.as_deref()).await?;
+
+    tx.commit().await?;
+
+    let issued = issue_api_token(
+        &pool,
+        bot_id,
+        CreateApiTokenRequest {
+            description: "Created with the bot".to_string(),
+            scopes: vec![
+                Scope::ReadMessages.as_str().to_string(),
+                Scope::SendMessages.as_str().to_string(),
+            ],
+            expires_in_days: None,
+        },
+    )
+    .await?;
+
+    Ok(Json(CreateBotResponse {
+        bot: BotResponse {
+            username,
+            display_name,
+            webhook_url,
+            webhook_secret,
+            created_at: now,
         },
+        token: issued.token,
     }))
 }
+
+pub async fn list_bots(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<Vec<BotResponse>>, AppError> {
+    let rows = db::query(
+        r#"
+        SELECT username, display_name, bot_webhook_url, created_at
+        FROM users
+        WHERE bot_owner_id = ? AND is_bot = TRUE AND deleted_at IS NULL
+        ORDER BY username
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(Json(rows.iter().map(bot_from_row).collect()))
+}
+
+pub async fn update_bot(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+    Json(payload): Json<UpdateBotRequest>,
+) -> Result<Json<BotResponse>, AppError> {
+    let bot_id = owned_bot_id(&pool, user_id, &username).await?;
+
+    if let Some(name) = payload.display_name.as_deref() {
+        let display_name = validate_display_name(name)
+            .map_err(|e| AppError::bad_request("invalid_display_name", e))?;
+        db::query("UPDATE users SET display_name = ? WHERE id = ?")
+            .bind(display_name)
+            .bind(bot_id)
+            .execute(pool.as_ref())
+            .await?;
+    }
+
+    let mut webhook_secret = None;
+    if let Some(url) = payload.webhook_url {
+        let url = Some(url.trim().to_string()).filter(|url| !url.is_empty());
+        if let Some(url) = &url {
+            crate::outbound::validate_url(url, config.allow_private_endpoints)
+                .map_err(|e| AppError::bad_request("invalid_webhook_url", e))?;
+        }
+        let mut tx = pool.begin().await?;
+        webhook_secret = crate::bots::set_webhook(&mut tx, bot_id, url.as_deref()).await?;
+        tx.commit().await?;
+    }
+
+    let row = db::query(
+        "SELECT username, display_name, bot_webhook_url, created_at FROM users WHERE id = ?",
+    )
+    .bind(bot_id)
+    .fetch_one(pool.as_ref())
+    .await?;
+
+    Ok(Json(BotResponse {
+        webhook_secret,
+        ..bot_from_row(&row)
+    }))
+}
+
+pub async fn delete_bot(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let bot_id = owned_bot_id(&pool, user_id, &username).await?;
+    let bot_username: String = db::query("SELECT username FROM users WHERE id = ?")
+        .bind(bot_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("username");
+
+    let mut tx = pool.begin().await?;
+    erase_account(&mut tx, &config, bot_id, &bot_username, &Utc::now()).await?;
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "deleted": true })))
+}
+
+pub async fn create_bot_token(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+    Json(payload): Json<CreateApiTokenRequest>,
+) -> Result<Json<CreateApiTokenResponse>, AppError> {
+    let bot_id = owned_bot_id(&pool, user_id, &username).await?;
+    issue_api_token(&pool, bot_id, payload).await.map(Json)
+}
+
+pub async fn list_bot_tokens(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<Json<Vec<ApiTokenResponse>>, AppError> {
+    let bot_id = owned_bot_id(&pool, user_id, &username).await?;
+    fetch_api_tokens(&pool, bot_id).await.map(Json)
+}
+
+pub async fn revoke_bot_token(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path((username, token_id)): axum::extract::Path<(String, i64)>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let bot_id = owned_bot_id(&pool, user_id, &username).await?;
+    delete_api_token(&pool, bot_id, token_id).await.map(Json)
+}
+
+// The id of the caller's bot named `username`; other users' bots are reported
+// as missing
+async fn owned_bot_id(pool: &DbPool, owner_id: i64, username: &str) -> Result<i64, AppError> {
+    let row = db::query(
+        "SELECT id FROM users WHERE username = ? COLLATE NOCASE AND bot_owner_id = ? AND is_bot = TRUE AND deleted_at IS NULL",
+    )
+    .bind(normalize_username(username))
+    .bind(owner_id)
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    row.map(|row| row.get("id"))
+        .ok_or_else(|| AppError::not_found("bot_not_found", "Bot not found"))
+}
+
+fn bot_from_row(row: &db::Row) -> BotResponse {
+    BotResponse {
+        username: row.get("username"),
+        display_name: row.get("display_name"),
+        webhook_url: row.get("bot_webhook_url"),
+        webhook_secret: None,
+        created_at: row.get("created_at"),
+    }
+}
+
+// Long-poll endpoint
+const MAX_UPDATES: i64 = 100;
+const DEFAULT_UPDATES_TIMEOUT_SECS: u64 = 30;
+const MAX_UPDATES_TIMEOUT_SECS: u64 = 60;
+
+/// Messages received after `after`, waiting up to `timeout` seconds for one to
+/// arrive if there are none yet. Intended for bots without a webhook.
+pub async fn get_updates(
+    State(store): State<Arc<dyn Store>>,
+    State(events): State<EventHub>,
+    State(shutdown): State<Shutdown>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Query(params): axum::extract::Query<UpdatesQuery>,
+) -> Result<Json<UpdatesResponse>, AppError> {
+    let after = params.after.unwrap_or(0);
+    let timeout = std::time::Duration::from_secs(
+        params
+            .timeout
+            .unwrap_or(DEFAULT_UPDATES_TIMEOUT_SECS)
+            .min(MAX_UPDATES_TIMEOUT_SECS),
+    );
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    // Subscribe before the first query so a message stored in between still
+    // wakes us up
+    let mut receiver = events.subscribe();
+    let _connection = crate::metrics::ConnectionGuard::new();
+
+    loop {
+        let messages = store
+            .messages_received_after(user_id, after, MAX_UPDATES)
+            .await?;
+
+        if !messages.is_empty() {
+            let next_after = messages.last().map_or(after, |m| m.id);
+            return Ok(Json(UpdatesResponse {
+                messages,
+                next_after,
+            }));
+        }
+
+        // Shutting down answers like a timeout, so clients reconnect to
+        // another instance
+        let woke = tokio::select! {
+            woke = tokio::time::timeout_at(
+                deadline,
+                crate::events::wait_for_message(&mut receiver, user_id),
+            ) => woke.is_ok(),
+            _ = shutdown.requested() => false,
+        };
+        if !woke {
+            return Ok(Json(UpdatesResponse {
+                messages,
+                next_after: after,
+            }));
+        }
+    }
+}
+
+// Webhook endpoints
+const MAX_WEBHOOKS_PER_USER: i64 = 10;
+const MAX_DELIVERY_LOG: i64 = 100;
+
+pub async fn create_webhook(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<CreateWebhookRequest>,
+) -> Result<Json<CreateWebhookResponse>, AppError> {
+    let url = payload.url.trim().to_string();
+    crate::outbound::validate_url(&url, config.allow_private_endpoints)
+        .map_err(|e| AppError::bad_request("invalid_webhook_url", e))?;
+
+    let mut events = Vec::new();
+    for event in &payload.events {
+        if !webhooks::EVENT_TYPES.contains(&event.as_str()) {
+            return Err(AppError::bad_request(
+                "invalid_event",
+                format!("unknown event: {}", event),
+            ));
+        }
+        if !events.contains(event) {
+            events.push(event.clone());
+        }
+    }
+    if events.is_empty() {
+        return Err(AppError::bad_request(
+            "invalid_event",
+            "At least one event is required",
+        ));
+    }
+
+    let global = payload.global.unwrap_or(false);
+    if global && !is_admin(&config, user_id) {
+        return Err(AppError::forbidden(
+            "admin_required",
+            "Only admins can create global webhooks",
+        ));
+    }
+
+    let existing: i64 = db::query("SELECT COUNT(*) as count FROM webhooks WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("count");
+    if !global && existing >= MAX_WEBHOOKS_PER_USER {
+        return Err(AppError::bad_request(
+            "limit_reached",
+            format!(
+                "At most {} webhooks are allowed; delete one first",
+                MAX_WEBHOOKS_PER_USER
+            ),
+        ));
+    }
+
+    let secret = webhooks::generate_secret();
+    let now = Utc::now();
+    let id = db::query(
+        "INSERT INTO webhooks (user_id, url, secret, events, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id",
+    )
+    .bind((!global).then_some(user_id))
+    .bind(&url)
+    .bind(&secret)
+    .bind(events.join(","))
+    .bind(now)
+    .fetch_one(pool.as_ref())
+    .await?
+    .get::<i64, _>("id");
+
+    Ok(Json(CreateWebhookResponse {
+        secret,
+        webhook: WebhookResponse {
+            id,
+            url,
+            events,
+            global,
+            created_at: now,
+        },
+    }))
+}
+
+pub async fn list_webhooks(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<Vec<WebhookResponse>>, AppError> {
+    let admin = is_admin(&config, user_id);
+    let rows = db::query(
+        r#"
+        SELECT id, user_id, url, events, created_at
+        FROM webhooks
+        WHERE user_id = ? OR (user_id IS NULL AND ?)
+        ORDER BY id
+        "#,
+    )
+    .bind(user_id)
+    .bind(admin)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    let hooks = rows
+        .iter()
+        .map(|row| {
+            let events: String = row.get("events");
+            WebhookResponse {
+                id: row.get("id"),
+                url: row.get("url"),
+                events: events.split(',').map(|e| e.to_string()).collect(),
+                global: row.get::<Option<i64>, _>("user_id").is_none(),
+                created_at: row.get("created_at"),
+            }
+        })
+        .collect();
+
+    Ok(Json(hooks))
+}
+
+pub async fn delete_webhook(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(webhook_id): axum::extract::Path<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    accessible_webhook(&pool, &config, user_id, webhook_id).await?;
+
+    let mut tx = pool.begin().await?;
+    db::query("DELETE FROM webhook_deliveries WHERE webhook_id = ?")
+        .bind(webhook_id)
+        .execute(&mut *tx)
+        .await?;
+    db::query("DELETE FROM webhooks WHERE id = ?")
+        .bind(webhook_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "deleted": true })))
+}
+
+/// Queue a `ping` delivery to check that an endpoint is reachable and
+/// verifies signatures.
+pub async fn ping_webhook(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    State(queue): State<webhooks::DeliveryQueue>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(webhook_id): axum::extract::Path<i64>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    accessible_webhook(&pool, &config, user_id, webhook_id).await?;
+
+    let delivery_id = webhooks::queue_delivery(
+        &pool,
+        webhook_id,
+        webhooks::EVENT_PING,
+        &serde_json::json!({}),
+        &Utc::now(),
+    )
+    .await
+    .map_err(|e| AppError::internal("Failed to queue ping", e))?;
+    queue.wake();
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "delivery_id": delivery_id })),
+    ))
+}
+
+pub async fn get_webhook_deliveries(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(webhook_id): axum::extract::Path<i64>,
+) -> Result<Json<Vec<WebhookDeliveryResponse>>, AppError> {
+    accessible_webhook(&pool, &config, user_id, webhook_id).await?;
+
+    let rows = db::query(
+        r#"
+        SELECT id, event, status, attempts, last_status_code, last_error, next_attempt_at, created_at, delivered_at
+        FROM webhook_deliveries
+        WHERE webhook_id = ?
+        ORDER BY id DESC
+        LIMIT ?
+        "#,
+    )
+    .bind(webhook_id)
+    .bind(MAX_DELIVERY_LOG)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    let deliveries = rows
+        .iter()
+        .map(|row| WebhookDeliveryResponse {
+            id: row.get("id"),
+            event: row.get("event"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            last_status_code: row.get("last_status_code"),
+            last_error: row.get("last_error"),
+            next_attempt_at: row.get("next_attempt_at"),
+            created_at: row.get("created_at"),
+            delivered_at: row.get("delivered_at"),
+        })
+        .collect();
+
+    Ok(Json(deliveries))
+}
+
+// Checks the caller owns the webhook, or that it is global and they are an admin
+async fn accessible_webhook(
+    pool: &DbPool,
+    config: &Config,
+    user_id: i64,
+    webhook_id: i64,
+) -> Result<(), AppError> {
+    let owner = db::query("SELECT user_id FROM webhooks WHERE id = ?")
+        .bind(webhook_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .map(|row| row.get::<Option<i64>, _>("user_id"));
+
+    let allowed = match owner {
+        Some(Some(owner)) => owner == user_id,
+        Some(None) => is_admin(config, user_id),
+        None => false,
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(AppError::not_found(
+            "webhook_not_found",
+            "Webhook not found",
+        ))
+    }
+}
+
+fn is_admin(config: &Config, user_id: i64) -> bool {
+    config.admin_user_ids.contains(&user_id)
+}
+
+// Incoming webhook endpoints
+const MAX_INCOMING_WEBHOOKS_PER_USER: i64 = 20;
+const MAX_INTEGRATION_NAME_CHARS: usize = 50;
+
+fn incoming_webhook_url(token: &str) -> String {
+    format!("/api/hooks/{}", token)
+}
+
+pub async fn create_incoming_webhook(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<CreateIncomingWebhookRequest>,
+) -> Result<Json<CreateIncomingWebhookResponse>, AppError> {
+    let name = payload.name.trim().to_string();
+    if name.is_empty() {
+        return Err(AppError::bad_request(
+            "invalid_name",
+            "Name cannot be empty",
+        ));
+    }
+    if name.chars().count() > MAX_INTEGRATION_NAME_CHARS {
+        return Err(AppError::bad_request(
+            "invalid_name",
+            format!(
+                "Name must be at most {} characters",
+                MAX_INTEGRATION_NAME_CHARS
+            ),
+        ));
+    }
+
+    let partner_id = find_user_id(store.as_ref(), &config, &payload.with_username).await?;
+
+    let existing: i64 =
+        db::query("SELECT COUNT(*) as count FROM incoming_webhooks WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_one(pool.as_ref())
+            .await?
+            .get("count");
+    if existing >= MAX_INCOMING_WEBHOOKS_PER_USER {
+        return Err(AppError::bad_request(
+            "limit_reached",
+            format!(
+                "At most {} incoming webhooks are allowed; delete one first",
+                MAX_INCOMING_WEBHOOKS_PER_USER
+            ),
+        ));
+    }
+
+    let token = generate_token();
+    let now = Utc::now();
+    let id = db::query(
+        "INSERT INTO incoming_webhooks (user_id, partner_id, name, token_hash, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(partner_id)
+    .bind(&name)
+    .bind(hash_token(&token))
+    .bind(now)
+    .fetch_one(pool.as_ref())
+    .await?
+    .get::<i64, _>("id");
+
+    let webhook = fetch_incoming_webhooks(&pool, user_id, Some(id))
+        .await?
+        .pop()
+        .ok_or_else(|| AppError::from(sqlx::Error::RowNotFound))?;
+
+    Ok(Json(CreateIncomingWebhookResponse {
+        url: incoming_webhook_url(&token),
+        webhook,
+    }))
+}
+
+pub async fn list_incoming_webhooks(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<Vec<IncomingWebhookResponse>>, AppError> {
+    fetch_incoming_webhooks(&pool, user_id, None).await.map(Json)
+}
+
+/// Replace the secret in a hook's URL; the old URL stops working immediately.
+pub async fn regenerate_incoming_webhook(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(webhook_id): axum::extract::Path<i64>,
+) -> Result<Json<CreateIncomingWebhookResponse>, AppError> {
+    let token = generate_token();
+    db::query("UPDATE incoming_webhooks SET token_hash = ? WHERE id = ? AND user_id = ?")
+        .bind(hash_token(&token))
+        .bind(webhook_id)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| AppError::internal("Failed to regenerate incoming webhook", e))?;
+
+    let webhook = fetch_incoming_webhooks(&pool, user_id, Some(webhook_id))
+        .await?
+        .pop()
+        .ok_or_else(incoming_webhook_not_found)?;
+
+    Ok(Json(CreateIncomingWebhookResponse {
+        url: incoming_webhook_url(&token),
+        webhook,
+    }))
+}
+
+pub async fn delete_incoming_webhook(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(webhook_id): axum::extract::Path<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let result = db::query("DELETE FROM incoming_webhooks WHERE id = ? AND user_id = ?")
+        .bind(webhook_id)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| AppError::internal("Failed to delete incoming webhook", e))?;
+
+    if result.rows_affected() == 0 {
+        return Err(incoming_webhook_not_found());
+    }
+
+    Ok(Json(serde_json::json!({ "deleted": true })))
+}
+
+/// Post a message through an incoming webhook. Unauthenticated: the secret in
+/// the URL is the credential.
+pub async fn post_incoming_webhook(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(events): State<EventHub>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+    Json(payload): Json<IncomingWebhookPayload>,
+) -> Result<Json<SendMessageResponse>, AppError> {
+    if payload.text.trim().is_empty() {
+        return Err(AppError::bad_request(
+            "empty_message",
+            "Message text cannot be empty",
+        ));
+    }
+
+    let hook = db::query(
+        "SELECT id, user_id, partner_id, name FROM incoming_webhooks WHERE token_hash = ?",
+    )
+    .bind(hash_token(&token))
+    .fetch_optional(pool.as_ref())
+    .await?
+    .ok_or_else(incoming_webhook_not_found)?;
+
+    let now = Utc::now();
+    db::query("UPDATE incoming_webhooks SET last_used_at = ? WHERE id = ?")
+        .bind(now)
+        .bind(hook.get::<i64, _>("id"))
+        .execute(pool.as_ref())
+        .await?;
+
+    let name: String = hook.get("name");
+    let response = deliver_message(
+        store.as_ref(),
+        &events,
+        hook.get("user_id"),
+        hook.get("partner_id"),
+        &payload.text,
+        false,
+        Some(&name),
+    )
+    .await?;
+
+    Ok(Json(response))
+}
+
+// The caller's incoming webhooks, or just the one with `webhook_id`
+async fn fetch_incoming_webhooks(
+    pool: &DbPool,
+    user_id: i64,
+    webhook_id: Option<i64>,
+) -> Result<Vec<IncomingWebhookResponse>, AppError> {
+    let rows = db::query(
+        r#"
+        SELECT h.id, h.name, h.last_used_at, h.created_at, partner.username as with_username
+        FROM incoming_webhooks h
+        JOIN users partner ON partner.id = h.partner_id
+        WHERE h.user_id = ? AND (? IS NULL OR h.id = ?)
+        ORDER BY h.id
+        "#,
+    )
+    .bind(user_id)
+    .bind(webhook_id)
+    .bind(webhook_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| IncomingWebhookResponse {
+            id: row.get("id"),
+            name: row.get("name"),
+            with_username: row.get("with_username"),
+            last_used_at: row.get("last_used_at"),
+            created_at: row.get("created_at"),
+        })
+        .collect())
+}
+
+fn incoming_webhook_not_found() -> AppError {
+    AppError::not_found("incoming_webhook_not_found", "Incoming webhook not found")
+}
+
+// Push notification endpoints
+const MAX_PUSH_DEVICES_PER_USER: i64 = 20;
+// About ten years; longer mutes are capped to this
+const MAX_MUTE_MINUTES: i64 = 10 * 365 * 24 * 60;
+
+pub async fn register_push_device(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<RegisterPushDeviceRequest>,
+) -> Result<Json<PushDeviceResponse>, AppError> {
+    let endpoint = payload.endpoint.trim().to_string();
+    push::validate_device(
+        &payload.kind,
+        &endpoint,
+        payload.p256dh.as_deref(),
+        payload.auth.as_deref(),
+        config.allow_private_endpoints,
+    )
+    .map_err(|e| AppError::bad_request("invalid_device", e))?;
+
+    if payload.kind == push::KIND_WEB_PUSH
+        && config.push_gateway_url.is_none()
+        && config.vapid_private_key.is_none()
+    {
+        return Err(AppError::bad_request(
+            "web_push_not_configured",
+            "Web Push is not configured on this server".to_string(),
+        ));
+    }
+
+    let existing: i64 =
+        db::query("SELECT COUNT(*) as count FROM push_devices WHERE user_id = ? AND endpoint != ?")
+            .bind(user_id)
+            .bind(&endpoint)
+            .fetch_one(pool.as_ref())
+            .await?
+            .get("count");
+    if existing >= MAX_PUSH_DEVICES_PER_USER {
+        return Err(AppError::bad_request(
+            "limit_reached",
+            format!(
+                "At most {} devices are allowed; remove one first",
+                MAX_PUSH_DEVICES_PER_USER
+            ),
+        ));
+    }
+
+    // Re-registering an endpoint, possibly after switching accounts on the
+    // device, moves it to the caller
+    let now = Utc::now();
+    let id: i64 = db::query(
+        r#"
+        INSERT INTO push_devices (user_id, kind, endpoint, p256dh, auth, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT(endpoint) DO UPDATE SET
+            user_id = excluded.user_id,
+            kind = excluded.kind,
+            p256dh = excluded.p256dh,
+            auth = excluded.auth,
+            created_at = excluded.created_at
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(&payload.kind)
+    .bind(&endpoint)
+    .bind(&payload.p256dh)
+    .bind(&payload.auth)
+    .bind(now)
+    .fetch_one(pool.as_ref())
+    .await?
+    .get("id");
+
+    Ok(Json(PushDeviceResponse {
+        id,
+        kind: payload.kind,
+        endpoint,
+        created_at: now,
+    }))
+}
+
+pub async fn list_push_devices(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<Vec<PushDeviceResponse>>, AppError> {
+    let rows = db::query(
+        "SELECT id, kind, endpoint, created_at FROM push_devices WHERE user_id = ? ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    let devices = rows
+        .iter()
+        .map(|row| PushDeviceResponse {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            endpoint: row.get("endpoint"),
+            created_at: row.get("created_at"),
+        })
+        .collect();
+
+    Ok(Json(devices))
+}
+
+pub async fn remove_push_device(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(device_id): axum::extract::Path<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let result = db::query("DELETE FROM push_devices WHERE id = ? AND user_id = ?")
+        .bind(device_id)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| AppError::internal("Failed to remove device", e))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found("device_not_found", "Device not found"));
+    }
+
+    Ok(Json(serde_json::json!({ "deleted": true })))
+}
+
+/// The server's VAPID public key, for Web Push subscriptions.
+pub async fn get_vapid_key(
+    State(config): State<Arc<Config>>,
+) -> Result<Json<VapidKeyResponse>, AppError> {
+    match push::Vapid::from_config(&config) {
+        Some(Ok(vapid)) => Ok(Json(VapidKeyResponse {
+            public_key: vapid.public_key(),
+        })),
+        _ => Err(AppError::not_found(
+            "web_push_not_configured",
+            "Web Push is not configured on this server",
+        )),
+    }
+}
+
+pub async fn mute_conversation(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+    Json(payload): Json<MuteConversationRequest>,
+) -> Result<Json<MuteResponse>, AppError> {
+    let muted_until = match payload.duration_minutes {
+        Some(minutes) if minutes <= 0 => {
+            return Err(AppError::bad_request(
+                "invalid_duration",
+                "duration_minutes must be positive",
+            ))
+        }
+        Some(minutes) => Some(Utc::now() + chrono::Duration::minutes(minutes.min(MAX_MUTE_MINUTES))),
+        None => None,
+    };
+
+    let partner_id = find_user_id(store.as_ref(), &config, &username).await?;
+    db::query(
+        r#"
+        INSERT INTO conversation_mutes (user_id, partner_id, muted_until, created_at)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT(user_id, partner_id) DO UPDATE SET muted_until = excluded.muted_until
+        "#,
+    )
+    .bind(user_id)
+    .bind(partner_id)
+    .bind(muted_until)
+    .bind(Utc::now())
+    .execute(pool.as_ref())
+    .await?;
+
+    Ok(Json(MuteResponse {
+        username,
+        muted: true,
+        muted_until,
+    }))
+}
+
+pub async fn unmute_conversation(
+    State(pool): State<DbPool>,
+    State(store): State<Arc<dyn Store>>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<Json<MuteResponse>, AppError> {
+    let partner_id = find_user_id(store.as_ref(), &config, &username).await?;
+    db::query("DELETE FROM conversation_mutes WHERE user_id = ? AND partner_id = ?")
+        .bind(user_id)
+        .bind(partner_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| AppError::internal("Failed to unmute conversation", e))?;
+
+    Ok(Json(MuteResponse {
+        username,
+        muted: false,
+        muted_until: None,
+    }))
+}
+
+// Email endpoints
+pub async fn get_email(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<EmailResponse>, AppError> {
+    let row = db::query("SELECT email, email_verified_at FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?;
+
+    Ok(Json(EmailResponse {
+        email: row.get("email"),
+        verified: row
+            .get::<Option<DateTime<Utc>>, _>("email_verified_at")
+            .is_some(),
+    }))
+}
+
+/// Set or change the account's address and send it a verification link. The
+/// address is unverified until the link is opened.
+pub async fn set_email(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    State(mailer): State<email::Mailer>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<SetEmailRequest>,
+) -> Result<Json<EmailResponse>, AppError> {
+    if !mailer.is_enabled() {
+        return Err(AppError::bad_request(
+            "email_not_configured",
+            "Email is not configured on this server",
+        ));
+    }
+    let address = email::validate_address(&payload.email)
+        .map_err(|e| AppError::bad_request("invalid_email", e))?;
+
+    let password_hash: String = db::query("SELECT password_hash FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("password_hash");
+    if !verify_password(&payload.password, &password_hash).unwrap_or(false) {
+        return Err(AppError::unauthorized(
+            "incorrect_password",
+            "Password is incorrect",
+        ));
+    }
+
+    db::query("UPDATE users SET email = ?, email_verified_at = NULL WHERE id = ?")
+        .bind(&address)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await?;
+
+    let token = email::issue_token(&pool, user_id, email::PURPOSE_VERIFY, &address).await?;
+    email::send_verification(&mailer, &config, &address, &token)
+        .await
+        .map_err(|cause| AppError::BadGateway {
+            code: "email_failed",
+            message: "Failed to send verification email".to_string(),
+            cause,
+        })?;
+
+    Ok(Json(EmailResponse {
+        email: Some(address),
+        verified: false,
+    }))
+}
+
+pub async fn remove_email(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<EmailResponse>, AppError> {
+    let mut tx = pool.begin().await?;
+    db::query("UPDATE users SET email = NULL, email_verified_at = NULL WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    db::query("DELETE FROM email_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Json(EmailResponse {
+        email: None,
+        verified: false,
+    }))
+}
+
+/// Target of the link in verification emails. Unauthenticated: the token is
+/// the credential.
+pub async fn verify_email(
+    State(pool): State<DbPool>,
+    axum::extract::Query(query): axum::extract::Query<EmailTokenQuery>,
+) -> Result<Json<EmailResponse>, AppError> {
+    let invalid = || AppError::bad_request("invalid_token", "Invalid or expired verification link");
+
+    let (user_id, address) = email::find_token(&pool, email::PURPOSE_VERIFY, &query.token)
+        .await?
+        .ok_or_else(invalid)?;
+
+    // Links for an address that has since been replaced no longer apply
+    let result = db::query(
+        "UPDATE users SET email_verified_at = ? WHERE id = ? AND email = ? AND deleted_at IS NULL",
+    )
+    .bind(Utc::now())
+    .bind(user_id)
+    .bind(&address)
+    .execute(pool.as_ref())
+    .await;
+    match result {
+        Ok(result) if result.rows_affected() == 0 => return Err(invalid()),
+        Ok(_) => {}
+        Err(e) if db::is_unique_violation(&e) => {
+            return Err(AppError::conflict(
+                "email_taken",
+                "This email address is already in use by another account",
+            ))
+        }
+        Err(e) => return Err(AppError::from(e)),
+    }
+
+    db::query("DELETE FROM email_tokens WHERE user_id = ? AND purpose = ?")
+        .bind(user_id)
+        .bind(email::PURPOSE_VERIFY)
+        .execute(pool.as_ref())
+        .await?;
+
+    Ok(Json(EmailResponse {
+        email: Some(address),
+        verified: true,
+    }))
+}
+
+/// Email a password reset link to the account with this verified address.
+/// The response is the same whether or not there is one, so it can't be used
+/// to find out which addresses are registered.
+pub async fn request_password_reset(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    State(mailer): State<email::Mailer>,
+    State(shutdown): State<Shutdown>,
+    Json(payload): Json<PasswordResetRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    if !mailer.is_enabled() {
+        return Err(AppError::bad_request(
+            "email_not_configured",
+            "Email is not configured on this server",
+        ));
+    }
+
+    let user = db::query(
+        r#"
+        SELECT id, username, email FROM users
+        WHERE email = ? COLLATE NOCASE AND email_verified_at IS NOT NULL
+          AND deleted_at IS NULL AND is_bot = FALSE
+        "#,
+    )
+    .bind(payload.email.trim())
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    // Sent in the background so the response time doesn't give it away either
+    if let Some(user) = user {
+        shutdown.spawn(async move {
+            let user_id: i64 = user.get("id");
+            let username: String = user.get("username");
+            let address: String = user.get("email");
+            let sent = match email::issue_token(&pool, user_id, email::PURPOSE_RESET, &address).await {
+                Ok(token) => {
+                    email::send_password_reset(&mailer, &config, &address, &username, &token).await
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = sent {
+                tracing::error!("Failed to send password reset to user {}: {}", user_id, e);
+            }
+        });
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "requested": true })),
+    ))
+}
+
+/// Set a new password with the token from a reset email. Signs the account
+/// out everywhere.
+pub async fn confirm_password_reset(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Json(payload): Json<PasswordResetConfirmRequest>,
+) -> Result<Json<ChangePasswordResponse>, AppError> {
+    let (user_id, address) = email::find_token(&pool, email::PURPOSE_RESET, &payload.token)
+        .await?
+        .ok_or_else(|| AppError::bad_request("invalid_token", "Invalid or expired reset token"))?;
+
+    let username: String = db::query("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("username");
+
+    validate_password(
+        &payload.new_password,
+        &username,
+        config.password_min_length,
+        config.password_check_common,
+    )
+    .map_err(|e| AppError::bad_request("invalid_password", e.to_string()))?;
+
+    let new_hash = hash_password(&payload.new_password)
+        .map_err(|e| AppError::internal("Password hashing error", e))?;
+
+    let mut tx = pool.begin().await?;
+
+    // The reset only counts if the address it was sent to is still verified
+    let updated = db::query(
+        "UPDATE users SET password_hash = ? WHERE id = ? AND email = ? AND email_verified_at IS NOT NULL AND deleted_at IS NULL",
+    )
+    .bind(&new_hash)
+    .bind(user_id)
+    .bind(&address)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::bad_request(
+            "invalid_token",
+            "Invalid or expired reset token",
+        ));
+    }
+
+    clear_failed_logins(&mut tx, user_id).await?;
+    db::query("DELETE FROM email_tokens WHERE user_id = ? AND purpose = ?")
+        .bind(user_id)
+        .bind(email::PURPOSE_RESET)
+        .execute(&mut *tx)
+        .await?;
+    let revoked_sessions = revoke_credentials(&mut tx, user_id, None).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ChangePasswordResponse { revoked_sessions }))
+}
+
+// Personal data export endpoints
+pub async fn request_export(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<(StatusCode, Json<ExportJobResponse>), AppError> {
+    // Asking again while an export is in progress returns the same job
+    let job_id = match in_progress_export(&pool, user_id).await? {
+        Some(job_id) => job_id,
+        None => {
+            // Only the latest archive is kept
+            remove_exports(&pool, user_id).await?;
+
+            let inserted = db::query(
+                "INSERT INTO export_jobs (user_id, status, created_at) VALUES (?, ?, ?) RETURNING id",
+            )
+            .bind(user_id)
+            .bind(export::STATUS_PENDING)
+            .bind(Utc::now())
+            .fetch_one(pool.as_ref())
+            .await;
+
+            match inserted {
+                Ok(row) => {
+                    let job_id = row.get("id");
+                    export::spawn_export(pool.clone(), job_id, user_id);
+                    job_id
+                }
+                // A unique index allows one job in progress per user, so a
+                // concurrent request got there first; return its job
+                Err(e) if db::is_unique_violation(&e) => in_progress_export(&pool, user_id)
+                    .await?
+                    .ok_or_else(|| AppError::from(sqlx::Error::RowNotFound))?,
+                Err(e) => return Err(AppError::from(e)),
+            }
+        }
+    };
+
+    let job = fetch_export_job(&pool, user_id, job_id)
+        .await?
+        .ok_or_else(|| AppError::from(sqlx::Error::RowNotFound))?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+pub async fn get_export_status(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(job_id): axum::extract::Path<i64>,
+) -> Result<Json<ExportJobResponse>, AppError> {
+    let job = fetch_export_job(&pool, user_id, job_id).await?;
+
+    job.map(Json)
+        .ok_or_else(|| AppError::not_found("export_not_found", "Export not found"))
+}
+
+pub async fn download_export(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    axum::extract::Path(job_id): axum::extract::Path<i64>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let not_found = || AppError::not_found("export_not_ready", "Export not found or not ready");
+
+    let row =
+        db::query("SELECT archive FROM export_jobs WHERE id = ? AND user_id = ? AND status = ?")
+            .bind(job_id)
+            .bind(user_id)
+            .bind(export::STATUS_COMPLETE)
+            .fetch_optional(pool.as_ref())
+            .await?
+            .ok_or_else(not_found)?;
+
+    let data: Vec<u8> = row
+        .get::<Option<Vec<u8>>, _>("archive")
+        .ok_or_else(not_found)?;
+
+    Ok((
+        [
+            (axum::http::header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"migchat-export-{}.zip\"", job_id),
+            ),
+        ],
+        data,
+    ))
+}
+
+async fn fetch_export_job(
+    pool: &DbPool,
+    user_id: i64,
+    job_id: i64,
+) -> Result<Option<ExportJobResponse>, sqlx::Error> {
+    let row = db::query(
+        "SELECT id, status, error, created_at, completed_at FROM export_jobs WHERE id = ? AND user_id = ?",
+    )
+    .bind(job_id)
+    .bind(user_id)
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    Ok(row.map(|row| {
+        let id: i64 = row.get("id");
+        let status: String = row.get("status");
+        ExportJobResponse {
+            id,
+            download_url: (status == export::STATUS_COMPLETE)
+                .then(|| format!("/api/account/export/{}/download", id)),
+            status,
+            error: row.get("error"),
+            created_at: row.get("created_at"),
+            completed_at: row.get("completed_at"),
+        }
+    }))
+}
+
+async fn in_progress_export(pool: &DbPool, user_id: i64) -> Result<Option<i64>, sqlx::Error> {
+    let row = db::query("SELECT id FROM export_jobs WHERE user_id = ? AND status IN (?, ?)")
+        .bind(user_id)
+        .bind(export::STATUS_PENDING)
+        .bind(export::STATUS_RUNNING)
+        .fetch_optional(pool.as_ref())
+        .await?;
+    Ok(row.map(|row| row.get("id")))
+}
+
+// Deletes a user's finished export jobs, along with their archives
+async fn remove_exports(pool: &DbPool, user_id: i64) -> Result<(), sqlx::Error> {
+    db::query("DELETE FROM export_jobs WHERE user_id = ? AND status NOT IN (?, ?)")
+        .bind(user_id)
+        .bind(export::STATUS_PENDING)
+        .bind(export::STATUS_RUNNING)
+        .execute(pool.as_ref())
+        .await?;
+
+    Ok(())
+}
+
+// Two-factor authentication endpoints
+const LOGIN_CHALLENGE_MINUTES: i64 = 5;
+const MAX_LOGIN_CHALLENGE_ATTEMPTS: i64 = 5;
+
+pub async fn complete_two_factor_login(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Json(payload): Json<TwoFactorLoginRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    if payload.code.is_none() && payload.recovery_code.is_none() {
+        return Err(AppError::bad_request(
+            "two_factor_code_required",
+            "Either code or recovery_code is required",
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let challenge = db::query(
+        r#"
+        SELECT c.id, c.user_id, c.expires_at, c.attempts, u.locked_until
+        FROM login_challenges c
+        JOIN users u ON u.id = c.user_id
+        WHERE c.token_hash = ?
+        "#,
+    )
+    .bind(hash_token(&payload.challenge_token))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(challenge) = challenge else {
+        return Err(AppError::unauthorized(
+            "invalid_challenge",
+            "Login challenge is invalid or expired",
+        ));
+    };
+
+    let challenge_id: i64 = challenge.get("id");
+    let user_id: i64 = challenge.get("user_id");
+    let attempts: i64 = challenge.get("attempts");
+    let expires_at: DateTime<Utc> = challenge.get("expires_at");
+
+    if expires_at < Utc::now() {
+        db::query("DELETE FROM login_challenges WHERE id = ?")
+            .bind(challenge_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        return Err(AppError::unauthorized(
+            "invalid_challenge",
+            "Login challenge is invalid or expired",
+        ));
+    }
+
+    // Wrong codes count towards the same lockout as wrong passwords
+    if let Some(retry_after) = lockout_remaining(challenge.get("locked_until")) {
+        return Err(AppError::TooManyRequests {
+            code: "account_locked",
+            retry_after,
+        });
+    }
+
+    let verified = match (&payload.code, &payload.recovery_code) {
+        (Some(code), _) => {
+            let totp = db::query(
+                "SELECT secret, last_used_step FROM user_totp WHERE user_id = ? AND enabled = TRUE",
+            )
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            match totp {
+                Some(totp) => {
+                    let secret: String = totp.get("secret");
+                    let last_used_step: Option<i64> = totp.get("last_used_step");
+                    match crate::totp::verify(
+                        &secret,
+                        code,
+                        Utc::now().timestamp() as u64,
+                        last_used_step.map(|s| s as u64),
+                    ) {
+                        Some(step) => claim_totp_step(&mut tx, user_id, step).await?,
+                        None => false,
+                    }
+                }
+                None => false,
+            }
+        }
+        (None, Some(recovery_code)) => {
+            let used = db::query(
+                "UPDATE totp_recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+            )
+            .bind(Utc::now())
+            .bind(user_id)
+            .bind(crate::totp::hash_recovery_code(recovery_code))
+            .execute(&mut *tx)
+            .await?;
+            used.rows_affected() == 1
+        }
+        (None, None) => false,
+    };
+
+    if !verified {
+        // Challenges allow only a handful of guesses before the password step must be repeated
+        if attempts + 1 >= MAX_LOGIN_CHALLENGE_ATTEMPTS {
+            db::query("DELETE FROM login_challenges WHERE id = ?")
+                .bind(challenge_id)
+                .execute(&mut *tx)
+                .await?;
+        } else {
+            db::query("UPDATE login_challenges SET attempts = attempts + 1 WHERE id = ?")
+                .bind(challenge_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        record_failed_login(&mut tx, &config, user_id).await?;
+        tx.commit().await?;
+        return Err(AppError::unauthorized(
+            "invalid_two_factor_code",
+            "Invalid two-factor code",
+        ));
+    }
+
+    db::query("DELETE FROM login_challenges WHERE id = ?")
+        .bind(challenge_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let username: String = db::query("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?
+        .get("username");
+
+    clear_failed_logins(&mut tx, user_id).await?;
+    let token = create_session(&mut *tx, user_id).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(LoginResponse {
+        token: Some(token),
+        user_id,
+        username,
+        two_factor_required: false,
+        challenge_token: None,
+    }))
+}
+
+pub async fn get_two_factor_status(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<TwoFactorStatusResponse>, AppError> {
+    let row = db::query(
+        r#"
+        SELECT
+            EXISTS(SELECT 1 FROM user_totp WHERE user_id = ? AND enabled = TRUE) as enabled,
+            (SELECT COUNT(*) FROM totp_recovery_codes WHERE user_id = ? AND used_at IS NULL) as recovery_codes_remaining
+        "#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await?;
+
+    Ok(Json(TwoFactorStatusResponse {
+        enabled: row.get("enabled"),
+        recovery_codes_remaining: row.get("recovery_codes_remaining"),
+    }))
+}
+
+pub async fn enroll_two_factor(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    Extension(user_id): Extension<i64>,
+) -> Result<Json<TwoFactorEnrollResponse>, AppError> {
+    let enabled = db::query("SELECT 1 FROM user_totp WHERE user_id = ? AND enabled = TRUE")
+        .bind(user_id)
+        .fetch_optional(pool.as_ref())
+        .await?;
+
+    if enabled.is_some() {
+        return Err(AppError::conflict(
+            "two_factor_enabled",
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    // Restarting enrollment replaces any unconfirmed secret
+    let secret = crate::totp::generate_secret();
+    db::query(
+        r#"
+        INSERT INTO user_totp (user_id, secret, enabled, created_at) VALUES (?, ?, FALSE, ?)
+        ON CONFLICT(user_id) DO UPDATE SET
+            secret = excluded.secret,
+            last_used_step = NULL,
+            created_at = excluded.created_at
+        "#,
+    )
+    .bind(user_id)
+    .bind(&secret)
+    .bind(Utc::now())
+    .execute(pool.as_ref())
+    .await?;
+
+    let username: String = db::query("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("username");
+
+    Ok(Json(TwoFactorEnrollResponse {
+        provisioning_uri: crate::totp::provisioning_uri(&config.totp_issuer, &username, &secret),
+        secret,
+    }))
+}
+
+pub async fn confirm_two_factor(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<TwoFactorConfirmRequest>,
+) -> Result<Json<TwoFactorConfirmResponse>, AppError> {
+    let totp = db::query("SELECT secret, enabled FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(pool.as_ref())
+        .await?;
+
+    let Some(totp) = totp else {
+        return Err(AppError::bad_request(
+            "two_factor_not_enrolling",
+            "Two-factor enrollment has not been started",
+        ));
+    };
+
+    if totp.get::<bool, _>("enabled") {
+        return Err(AppError::conflict(
+            "two_factor_enabled",
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    let secret: String = totp.get("secret");
+    let Some(step) = crate::totp::verify(&secret, &payload.code, Utc::now().timestamp() as u64, None)
+    else {
+        return Err(AppError::bad_request(
+            "invalid_two_factor_code",
+            "Invalid two-factor code",
+        ));
+    };
+
+    let recovery_codes = crate::totp::generate_recovery_codes();
+    let now = Utc::now();
+
+    let mut tx = pool.begin().await?;
+
+    db::query(
+        "UPDATE user_totp SET enabled = TRUE, confirmed_at = ?, last_used_step = ? WHERE user_id = ?",
+    )
+    .bind(now)
+    .bind(step as i64)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    db::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for code in &recovery_codes {
+        db::query(
+            "INSERT INTO totp_recovery_codes (user_id, code_hash, created_at) VALUES (?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(crate::totp::hash_recovery_code(code))
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(TwoFactorConfirmResponse { recovery_codes }))
+}
+
+pub async fn disable_two_factor(
+    State(pool): State<DbPool>,
+    Extension(user_id): Extension<i64>,
+    Json(payload): Json<TwoFactorDisableRequest>,
+) -> Result<Json<TwoFactorStatusResponse>, AppError> {
+    let row = db::query(
+        r#"
+        SELECT u.password_hash, t.secret, t.last_used_step
+        FROM users u
+        JOIN user_totp t ON t.user_id = u.id AND t.enabled = TRUE
+        WHERE u.id = ?
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool.as_ref())
+    .await?;
+
+    let Some(row) = row else {
+        return Err(AppError::bad_request(
+            "two_factor_not_enabled",
+            "Two-factor authentication is not enabled",
+        ));
+    };
+
+    let password_hash: String = row.get("password_hash");
+    let secret: String = row.get("secret");
+    let last_used_step: Option<i64> = row.get("last_used_step");
+
+    // Require both factors so a stolen session alone can't strip 2FA
+    let password_ok = verify_password(&payload.password, &password_hash).unwrap_or(false);
+    let step = crate::totp::verify(
+        &secret,
+        &payload.code,
+        Utc::now().timestamp() as u64,
+        last_used_step.map(|s| s as u64),
+    );
+
+    let invalid =
+        || AppError::unauthorized("invalid_credentials", "Invalid password or two-factor code");
+    let step = match step {
+        Some(step) if password_ok => step,
+        _ => return Err(invalid()),
+    };
+
+    let mut tx = pool.begin().await?;
+
+    if !claim_totp_step(&mut tx, user_id, step).await? {
+        return Err(invalid());
+    }
+
+    for statement in [
+        "DELETE FROM user_totp WHERE user_id = ?",
+        "DELETE FROM totp_recovery_codes WHERE user_id = ?",
+        "DELETE FROM login_challenges WHERE user_id = ?",
+    ] {
+        db::query(statement).bind(user_id).execute(&mut *tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(TwoFactorStatusResponse {
+        enabled: false,
+        recovery_codes_remaining: 0,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventBus, LocalBus, PostgresBus};
+    use crate::store::{MemoryStore, SqlStore, PREKEY_INSERT_BATCH};
+
+    fn sql_store(pool: &DbPool) -> Arc<dyn Store> {
+        Arc::new(SqlStore::new(pool.clone()))
+    }
+
+    // A fresh SQLite database, plus a Postgres one when TEST_DATABASE_URL is
+    // set, so each test runs on both backends
+    async fn test_pools() -> Vec<DbPool> {
+        let mut pools = vec![db::test_pool().await];
+        pools.extend(db::test_postgres_pool().await);
+        pools
+    }
+
+    // Every store implementation, each starting out empty
+    async fn test_stores() -> Vec<Arc<dyn Store>> {
+        let mut stores: Vec<Arc<dyn Store>> = vec![Arc::new(MemoryStore::new())];
+        stores.extend(test_pools().await.iter().map(sql_store));
+        stores
+    }
+
+    // A bus for server instances sharing `pool`: LISTEN/NOTIFY on its own
+    // channel for Postgres, and an in-process one standing in for SQLite
+    fn test_bus(pool: &DbPool) -> Arc<dyn EventBus> {
+        match pool.as_ref() {
+            db::Db::Postgres(pg_pool) => {
+                let channel = format!("test_{}", uuid::Uuid::new_v4().simple());
+                Arc::new(PostgresBus::on_channel(pg_pool.clone(), &channel))
+            }
+            db::Db::Sqlite(_) => Arc::new(LocalBus::new()),
+        }
+    }
+
+    // Make inserts into `table` matching `condition` fail, standing in for a
+    // full disk or I/O error partway through a handler
+    async fn inject_insert_failure(pool: &DbPool, table: &str, condition: &str) {
+        let statements = match pool.backend() {
+            db::Backend::Sqlite => vec![format!(
+                "CREATE TRIGGER fail_{table} BEFORE INSERT ON {table} WHEN {condition} \
+                 BEGIN SELECT RAISE(ABORT, 'injected failure'); END"
+            )],
+            db::Backend::Postgres => vec![
+                format!(
+                    "CREATE FUNCTION fail_{table}() RETURNS trigger LANGUAGE plpgsql \
+                     AS $$ BEGIN RAISE EXCEPTION 'injected failure'; END $$"
+                ),
+                format!(
+                    "CREATE TRIGGER fail_{table} BEFORE INSERT ON {table} FOR EACH ROW \
+                     WHEN ({condition}) EXECUTE FUNCTION fail_{table}()"
+                ),
+            ],
+        };
+        for statement in statements {
+            db::query(statement).execute(pool.as_ref()).await.unwrap();
+        }
+    }
+
+    async fn clear_insert_failure(pool: &DbPool, table: &str) {
+        let statement = match pool.backend() {
+            db::Backend::Sqlite => format!("DROP TRIGGER fail_{table}"),
+            db::Backend::Postgres => format!("DROP TRIGGER fail_{table} ON {table}"),
+        };
+        db::query(statement).execute(pool.as_ref()).await.unwrap();
+    }
+
+    async fn count(pool: &DbPool, sql: &str) -> i64 {
+        db::query(sql)
+            .fetch_one(pool.as_ref())
+            .await
+            .unwrap()
+            .get::<i64, _>(0)
+    }
+
+    async fn insert_user(pool: &DbPool, username: &str) -> i64 {
+        db::query("INSERT INTO users (username, password_hash, created_at) VALUES (?, 'x', ?) RETURNING id")
+            .bind(username)
+            .bind(Utc::now())
+            .fetch_one(pool.as_ref())
+            .await
+            .unwrap()
+            .get::<i64, _>("id")
+    }
+
+    fn key_upload(identity_key: &str, prekeys: usize) -> Json<UploadKeysRequest> {
+        Json(UploadKeysRequest {
+            key_bundle: KeyBundle {
+                identity_key: identity_key.to_string(),
+                signed_prekey: format!("{identity_key}-spk"),
+                signed_prekey_signature: format!("{identity_key}-sig"),
+                one_time_prekeys: (0..prekeys).map(|i| format!("{identity_key}-{i}")).collect(),
+            },
+        })
+    }
+
+    fn signup(username: &str) -> Json<CreateAccountRequest> {
+        Json(CreateAccountRequest {
+            username: username.to_string(),
+            password: "correct horse battery staple".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn create_account_leaves_no_user_when_session_fails() {
+        for pool in test_pools().await {
+            let config = Arc::new(Config::from_env());
+            inject_insert_failure(&pool, "sessions", "TRUE").await;
+
+            let result = create_account(
+                State(sql_store(&pool)),
+                State(config.clone()),
+                signup("alice"),
+            )
+            .await;
+            assert_eq!(
+                result.unwrap_err().status(),
+                StatusCode::INTERNAL_SERVER_ERROR
+            );
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM users").await, 0);
+
+            // The name wasn't taken by the failed attempt
+            clear_insert_failure(&pool, "sessions").await;
+            let created = create_account(State(sql_store(&pool)), State(config), signup("alice"))
+                .await
+                .unwrap();
+            assert_eq!(created.username, "alice");
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM sessions").await, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_key_upload_stores_nothing_when_prekeys_fail() {
+        for pool in test_pools().await {
+            let events = EventHub::new();
+            let mut receiver = events.subscribe();
+            let user_id = insert_user(&pool, "alice").await;
+            inject_insert_failure(&pool, "one_time_prekeys", "TRUE").await;
+
+            let result = upload_keys(
+                State(sql_store(&pool)),
+                State(events),
+                Extension(user_id),
+                key_upload("first", 5),
+            )
+            .await;
+            assert_eq!(
+                result.unwrap_err().status(),
+                StatusCode::INTERNAL_SERVER_ERROR
+            );
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM user_keys").await, 0);
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn key_upload_keeps_previous_bundle_when_a_later_batch_fails() {
+        for pool in test_pools().await {
+            let events = EventHub::new();
+            let user_id = insert_user(&pool, "alice").await;
+            let uploaded = upload_keys(
+                State(sql_store(&pool)),
+                State(events.clone()),
+                Extension(user_id),
+                key_upload("old", 3),
+            )
+            .await
+            .unwrap();
+            assert!(uploaded.success);
+
+            // Fails in the second batch, after the old prekeys were deleted and
+            // the first batch of new ones inserted
+            let failing_key_id = PREKEY_INSERT_BATCH + 10;
+            inject_insert_failure(
+                &pool,
+                "one_time_prekeys",
+                &format!("NEW.key_id = {failing_key_id}"),
+            )
+            .await;
+            let result = upload_keys(
+                State(sql_store(&pool)),
+                State(events),
+                Extension(user_id),
+                key_upload("new", PREKEY_INSERT_BATCH * 2),
+            )
+            .await;
+            assert_eq!(
+                result.unwrap_err().status(),
+                StatusCode::INTERNAL_SERVER_ERROR
+            );
+
+            let identity_key: String = db::query("SELECT identity_key FROM user_keys")
+                .fetch_one(pool.as_ref())
+                .await
+                .unwrap()
+                .get(0);
+            assert_eq!(identity_key, "old");
+            let prekeys: Vec<String> =
+                db::query("SELECT public_key FROM one_time_prekeys ORDER BY key_id")
+                    .fetch_all(pool.as_ref())
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|row| row.get(0))
+                    .collect();
+            assert_eq!(prekeys, ["old-0", "old-1", "old-2"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn key_upload_stores_prekeys_across_batches_in_order() {
+        for pool in test_pools().await {
+            let events = EventHub::new();
+            let mut receiver = events.subscribe();
+            let user_id = insert_user(&pool, "alice").await;
+            let uploaded = PREKEY_INSERT_BATCH * 2 + 7;
+
+            let response = upload_keys(
+                State(sql_store(&pool)),
+                State(events),
+                Extension(user_id),
+                key_upload("keys", uploaded),
+            )
+            .await
+            .unwrap();
+            assert!(response.success);
+
+            let rows = db::query("SELECT key_id, public_key FROM one_time_prekeys ORDER BY key_id")
+                .fetch_all(pool.as_ref())
+                .await
+                .unwrap();
+            assert_eq!(rows.len(), uploaded);
+            for (i, row) in rows.iter().enumerate() {
+                assert_eq!(row.get::<i64, _>("key_id"), i as i64);
+                assert_eq!(row.get::<String, _>("public_key"), format!("keys-{i}"));
+            }
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(Event::KeysUpdated { user_id: id }) if id == user_id
+            ));
+        }
+    }
+
+    async fn account(store: &Arc<dyn Store>, username: &str) -> i64 {
+        let config = Arc::new(Config::from_env());
+        create_account(State(store.clone()), State(config), signup(username))
+            .await
+            .unwrap()
+            .user_id
+    }
+
+    async fn two_factor_login(
+        pool: &DbPool,
+        user_id: i64,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+    ) -> Result<Json<LoginResponse>, AppError> {
+        let challenge_token = generate_token();
+        db::query(
+            "INSERT INTO login_challenges (user_id, token_hash, expires_at, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(hash_token(&challenge_token))
+        .bind(Utc::now() + chrono::Duration::minutes(LOGIN_CHALLENGE_MINUTES))
+        .bind(Utc::now())
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+
+        complete_two_factor_login(
+            State(pool.clone()),
+            State(Arc::new(Config::from_env())),
+            Json(TwoFactorLoginRequest {
+                challenge_token,
+                code: code.map(str::to_string),
+                recovery_code: recovery_code.map(str::to_string),
+            }),
+        )
+        .await
+    }
+
+    fn with_user(
+        username: &str,
+    ) -> axum::extract::Query<std::collections::HashMap<String, String>> {
+        axum::extract::Query([("with_user".to_string(), username.to_string())].into())
+    }
+
+    #[tokio::test]
+    async fn create_account_rejects_a_taken_username_in_any_case() {
+        for store in test_stores().await {
+            let config = Arc::new(Config::from_env());
+            account(&store, "alice").await;
+
+            let result = create_account(State(store), State(config), signup("ALICE")).await;
+            let error = result.unwrap_err();
+            assert_eq!(error.status(), StatusCode::CONFLICT);
+            assert_eq!(error.code(), "username_taken");
+        }
+    }
+
+    #[tokio::test]
+    async fn sent_messages_reach_both_sides_and_can_be_marked_read() {
+        for store in test_stores().await {
+            let config = Arc::new(Config::from_env());
+            let events = EventHub::new();
+            let mut receiver = events.subscribe();
+            let alice = account(&store, "alice").await;
+            let bob = account(&store, "bob").await;
+
+            for content in ["hi", "are you there?"] {
+                let sent = send_message(
+                    State(store.clone()),
+                    State(config.clone()),
+                    State(events.clone()),
+                    Extension(alice),
+                    Json(SendMessageRequest {
+                        to_username: "Bob".to_string(),
+                        content: content.to_string(),
+                        encrypted: Some(false),
+                    }),
+                )
+                .await
+                .unwrap();
+                assert!(sent.message_id > 0);
+            }
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(Event::MessageCreated { from_user_id, to_user_id, .. })
+                    if from_user_id == alice && to_user_id == bob
+            ));
+
+            let received = get_filtered_messages(
+                State(store.clone()),
+                State(config.clone()),
+                Extension(bob),
+                with_user("alice"),
+            )
+            .await
+            .unwrap();
+            let contents: Vec<&str> = received.iter().map(|m| m.content.as_str()).collect();
+            assert_eq!(contents, ["are you there?", "hi"]);
+            assert_eq!(received[0].from_username, "alice");
+
+            let conversations = get_conversations(State(store.clone()), Extension(bob))
+                .await
+                .unwrap();
+            assert_eq!(conversations.len(), 1);
+            assert_eq!(conversations[0].username, "alice");
+            assert_eq!(conversations[0].last_message, "are you there?");
+            assert_eq!(conversations[0].unread_count, 2);
+
+            let marked = mark_messages_read(
+                State(store.clone()),
+                State(config.clone()),
+                State(events.clone()),
+                Extension(bob),
+                with_user("alice"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(marked["marked_read"], 2);
+            let marked_again = mark_messages_read(
+                State(store),
+                State(config),
+                State(events),
+                Extension(bob),
+                with_user("alice"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(marked_again["marked_read"], 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn renamed_and_deleted_usernames_redirect_and_are_held() {
+        for store in test_stores().await {
+            let config = Arc::new(Config::from_env());
+            let alice = account(&store, "alice").await;
+            let bob = account(&store, "bob").await;
+            let rename = |user_id: i64, new_username: &str| {
+                update_username(
+                    State(store.clone()),
+                    State(config.clone()),
+                    Extension(user_id),
+                    Json(UpdateUsernameRequest {
+                        new_username: new_username.to_string(),
+                    }),
+                )
+            };
+
+            assert_eq!(rename(alice, "alicia").await.unwrap().username, "alicia");
+            assert_eq!(
+                store.resolve_user_id(&config, "Alice").await.unwrap(),
+                Some(alice)
+            );
+            let held = rename(bob, "alice").await.unwrap_err();
+            assert_eq!(held.code(), "username_held");
+            let taken = rename(bob, "ALICIA").await.unwrap_err();
+            assert_eq!(taken.code(), "username_taken");
+            // Alice can take her old name back
+            assert_eq!(rename(alice, "alice").await.unwrap().username, "alice");
+
+            store.delete_user(&config, bob).await.unwrap();
+            assert_eq!(store.resolve_user_id(&config, "bob").await.unwrap(), None);
+            assert!(store.find_login("bob").await.unwrap().is_none());
+            assert!(store.is_username_held(&config, "bob", None).await.unwrap());
+            assert!(!store
+                .is_username_held(&config, "bob", Some(bob))
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_logins_lock_until_a_sign_in_and_password_changes_sign_out_elsewhere() {
+        for store in test_stores().await {
+            let config = Arc::new(Config {
+                login_lockout_threshold: 2,
+                ..Config::from_env()
+            });
+            let created =
+                create_account(State(store.clone()), State(config.clone()), signup("alice"))
+                    .await
+                    .unwrap();
+            let alice = created.user_id;
+
+            store.record_failed_login(&config, alice).await.unwrap();
+            let login = store.find_login("ALICE").await.unwrap().unwrap();
+            assert_eq!(login.user_id, alice);
+            assert!(login.locked_until.is_none());
+            store.record_failed_login(&config, alice).await.unwrap();
+            assert!(store
+                .credentials(alice)
+                .await
+                .unwrap()
+                .locked_until
+                .is_some());
+
+            let other = store.sign_in(alice).await.unwrap();
+            assert!(store
+                .credentials(alice)
+                .await
+                .unwrap()
+                .locked_until
+                .is_none());
+
+            let session = store.find_session(&created.token).await.unwrap().unwrap();
+            let changed = change_password(
+                State(store.clone()),
+                State(config),
+                Extension(session),
+                Json(ChangePasswordRequest {
+                    current_password: "correct horse battery staple".to_string(),
+                    new_password: "a different horse entirely".to_string(),
+                }),
+            )
+            .await
+            .unwrap();
+            assert_eq!(changed.revoked_sessions, 1);
+            assert!(store.find_session(&other).await.unwrap().is_none());
+            assert!(store.find_session(&created.token).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn locked_accounts_answer_like_a_wrong_password() {
+        for store in test_stores().await {
+            let config = Arc::new(Config {
+                login_lockout_threshold: 1,
+                ..Config::from_env()
+            });
+            let alice =
+                create_account(State(store.clone()), State(config.clone()), signup("alice"))
+                    .await
+                    .unwrap()
+                    .user_id;
+            let log_in = |username: &str, password: &str| {
+                login(
+                    State(store.clone()),
+                    State(config.clone()),
+                    Json(LoginRequest {
+                        username: username.to_string(),
+                        password: password.to_string(),
+                    }),
+                )
+            };
+
+            let wrong = log_in("alice", "wrong password").await.unwrap_err();
+            assert_eq!(wrong.code(), "invalid_credentials");
+            // Locked now: the right password gets the same answer as a wrong
+            // one or an unknown username
+            let locked = log_in("alice", "correct horse battery staple")
+                .await
+                .unwrap_err();
+            assert_eq!(locked.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(locked.code(), "invalid_credentials");
+            let unknown = log_in("nobody", "wrong password").await.unwrap_err();
+            assert_eq!(unknown.code(), "invalid_credentials");
+
+            // Signing in elsewhere, say with the lock run out, lifts it
+            store.sign_in(alice).await.unwrap();
+            let signed_in = log_in("alice", "correct horse battery staple")
+                .await
+                .unwrap();
+            assert!(signed_in.token.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn two_factor_accounts_need_a_fresh_code_to_log_in_or_be_deleted() {
+        let memory = MemoryStore::new();
+        let store: Arc<dyn Store> = Arc::new(memory.clone());
+        let alice = account(&store, "alice").await;
+        let secret = crate::totp::generate_secret();
+        memory.enable_totp(alice, &secret);
+        let mut accounts = vec![(store, alice, secret)];
+        for pool in test_pools().await {
+            let store = sql_store(&pool);
+            let alice = account(&store, "alice").await;
+            let secret = crate::totp::generate_secret();
+            db::query("INSERT INTO user_totp (user_id, secret, enabled, created_at) VALUES (?, ?, TRUE, ?)")
+                .bind(alice)
+                .bind(&secret)
+                .bind(Utc::now())
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+            accounts.push((store, alice, secret));
+        }
+
+        for (store, alice, secret) in accounts {
+            let config = Arc::new(Config::from_env());
+            let logged_in = login(
+                State(store.clone()),
+                State(config.clone()),
+                Json(LoginRequest {
+                    username: "alice".to_string(),
+                    password: "correct horse battery staple".to_string(),
+                }),
+            )
+            .await
+            .unwrap();
+            assert!(logged_in.two_factor_required);
+            assert!(logged_in.token.is_none());
+            assert!(logged_in.challenge_token.is_some());
+
+            let delete = |code: Option<String>| {
+                delete_account(
+                    State(store.clone()),
+                    State(config.clone()),
+                    Extension(alice),
+                    Json(DeleteAccountRequest {
+                        password: "correct horse battery staple".to_string(),
+                        code,
+                    }),
+                )
+            };
+            let missing = delete(None).await.unwrap_err();
+            assert_eq!(missing.code(), "invalid_two_factor_code");
+
+            // A code already spent, say on the login, can't be used again
+            let now = Utc::now().timestamp() as u64;
+            assert!(store.claim_totp_step(alice, now / 30).await.unwrap());
+            let spent = delete(Some(crate::totp::code_at(&secret, now))).await;
+            assert_eq!(spent.unwrap_err().code(), "invalid_two_factor_code");
+
+            let deleted = delete(Some(crate::totp::code_at(&secret, now + 30)))
+                .await
+                .unwrap();
+            assert_eq!(deleted["deleted"], true);
+            assert!(store.find_login("alice").await.unwrap().is_none());
+            assert!(store.totp(alice).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn password_changes_revoke_api_tokens_and_pending_two_factor_logins() {
+        for pool in test_pools().await {
+            let store = sql_store(&pool);
+            let config = Arc::new(Config::from_env());
+            let created =
+                create_account(State(store.clone()), State(config.clone()), signup("alice"))
+                    .await
+                    .unwrap();
+            issue_api_token(
+                &pool,
+                created.user_id,
+                CreateApiTokenRequest {
+                    description: "CI".to_string(),
+                    scopes: vec!["messages:read".to_string()],
+                    expires_in_days: None,
+                },
+            )
+            .await
+            .unwrap();
+            db::query("INSERT INTO login_challenges (user_id, token_hash, expires_at, created_at) VALUES (?, 'challenge', ?, ?)")
+                .bind(created.user_id)
+                .bind(Utc::now() + chrono::Duration::minutes(5))
+                .bind(Utc::now())
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+
+            let session = store.find_session(&created.token).await.unwrap().unwrap();
+            let changed = change_password(
+                State(store.clone()),
+                State(config),
+                Extension(session),
+                Json(ChangePasswordRequest {
+                    current_password: "correct horse battery staple".to_string(),
+                    new_password: "a different horse entirely".to_string(),
+                }),
+            )
+            .await
+            .unwrap();
+            assert_eq!(changed.revoked_sessions, 0);
+
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM api_tokens").await, 0);
+            assert_eq!(
+                count(&pool, "SELECT COUNT(*) FROM login_challenges").await,
+                0
+            );
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM sessions").await, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn password_resets_sign_out_everywhere_and_revoke_api_tokens() {
+        for pool in test_pools().await {
+            let store = sql_store(&pool);
+            let config = Arc::new(Config::from_env());
+            let user_id = account(&store, "alice").await;
+            db::query(
+                "UPDATE users SET email = 'alice@example.com', email_verified_at = ? WHERE id = ?",
+            )
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+            issue_api_token(
+                &pool,
+                user_id,
+                CreateApiTokenRequest {
+                    description: "CI".to_string(),
+                    scopes: vec!["messages:read".to_string()],
+                    expires_in_days: None,
+                },
+            )
+            .await
+            .unwrap();
+            let token =
+                email::issue_token(&pool, user_id, email::PURPOSE_RESET, "alice@example.com")
+                    .await
+                    .unwrap();
+
+            let reset = confirm_password_reset(
+                State(pool.clone()),
+                State(config),
+                Json(PasswordResetConfirmRequest {
+                    token,
+                    new_password: "a different horse entirely".to_string(),
+                }),
+            )
+            .await
+            .unwrap();
+            assert_eq!(reset.revoked_sessions, 1);
+
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM sessions").await, 0);
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM api_tokens").await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn sending_to_an_unknown_user_is_not_found() {
+        for store in test_stores().await {
+            let alice = account(&store, "alice").await;
+
+            let result = send_message(
+                State(store),
+                State(Arc::new(Config::from_env())),
+                State(EventHub::new()),
+                Extension(alice),
+                Json(SendMessageRequest {
+                    to_username: "nobody".to_string(),
+                    content: "hello?".to_string(),
+                    encrypted: None,
+                }),
+            )
+            .await;
+            assert_eq!(result.unwrap_err().code(), "user_not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn each_key_fetch_uses_up_one_prekey() {
+        for store in test_stores().await {
+            let config = Arc::new(Config::from_env());
+            let alice = account(&store, "alice").await;
+            let uploaded = upload_keys(
+                State(store.clone()),
+                State(EventHub::new()),
+                Extension(alice),
+                key_upload("alice", 2),
+            )
+            .await
+            .unwrap();
+            assert!(uploaded.success);
+
+            let mut handed_out = Vec::new();
+            for _ in 0..3 {
+                let keys = get_keys(
+                    State(store.clone()),
+                    State(config.clone()),
+                    axum::extract::Path("alice".to_string()),
+                )
+                .await
+                .unwrap();
+                assert_eq!(keys.key_bundle.identity_key, "alice");
+                handed_out.push(keys.0.key_bundle.one_time_prekeys);
+            }
+            assert_eq!(handed_out[0], ["alice-0", "alice-1"]);
+            assert_eq!(handed_out[1], ["alice-1"]);
+            assert!(handed_out[2].is_empty());
+
+            let missing = get_keys(
+                State(store),
+                State(config),
+                axum::extract::Path("bob".to_string()),
+            )
+            .await;
+            assert_eq!(missing.unwrap_err().code(), "user_not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_key_fetches_never_share_a_prekey() {
+        for store in test_stores().await {
+            let alice = account(&store, "alice").await;
+            store
+                .replace_key_bundle(alice, &key_upload("alice", 8).key_bundle)
+                .await
+                .unwrap();
+
+            let mut fetches = tokio::task::JoinSet::new();
+            for _ in 0..8 {
+                let store = store.clone();
+                fetches.spawn(async move { store.claim_key_bundle(alice, 1).await });
+            }
+            let mut claimed = Vec::new();
+            while let Some(bundle) = fetches.join_next().await {
+                claimed.extend(bundle.unwrap().unwrap().unwrap().one_time_prekeys);
+            }
+            claimed.sort();
+            claimed.dedup();
+            assert_eq!(claimed.len(), 8);
+        }
+    }
+
+    #[tokio::test]
+    async fn deleting_an_account_or_bot_erases_its_exports() {
+        for pool in test_pools().await {
+            let config = Arc::new(Config::from_env());
+            let alice = insert_user(&pool, "alice").await;
+            let created = create_bot(
+                State(pool.clone()),
+                State(config.clone()),
+                Extension(alice),
+                Json(CreateBotRequest {
+                    username: "alicebot".to_string(),
+                    display_name: None,
+                    webhook_url: None,
+                }),
+            )
+            .await
+            .unwrap();
+            assert_eq!(created.bot.username, "alicebot");
+            let bot = sql_store(&pool)
+                .resolve_user_id(&config, "alicebot")
+                .await
+                .unwrap()
+                .unwrap();
+            for user_id in [alice, bot] {
+                db::query(
+                    "INSERT INTO export_jobs (user_id, status, created_at, archive) VALUES (?, ?, ?, ?)",
+                )
+                .bind(user_id)
+                .bind(export::STATUS_COMPLETE)
+                .bind(Utc::now())
+                .bind(b"zip".to_vec())
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+            }
+
+            let deleted = delete_bot(
+                State(pool.clone()),
+                State(config.clone()),
+                Extension(alice),
+                axum::extract::Path("alicebot".to_string()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(deleted["deleted"], true);
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM export_jobs").await, 1);
+
+            sql_store(&pool).delete_user(&config, alice).await.unwrap();
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM export_jobs").await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_sent_through_one_instance_wake_long_polls_on_another() {
+        for pool in test_pools().await {
+            let store = sql_store(&pool);
+            let config = Arc::new(Config::from_env());
+            let shutdown = Shutdown::new();
+            let bus = test_bus(&pool);
+            let first = EventHub::connect(bus.clone(), &shutdown).await.unwrap();
+            let second = EventHub::connect(bus, &shutdown).await.unwrap();
+            let mut second_local = second.subscribe_local();
+            let alice = account(&store, "alice").await;
+            let bob = account(&store, "bob").await;
+
+            let poll = tokio::spawn(get_updates(
+                State(store.clone()),
+                State(second.clone()),
+                State(shutdown.clone()),
+                Extension(bob),
+                axum::extract::Query(UpdatesQuery {
+                    after: None,
+                    timeout: Some(30),
+                }),
+            ));
+            // Let the poll start waiting before anything is sent
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+            let sent = send_message(
+                State(store),
+                State(config),
+                State(first),
+                Extension(alice),
+                Json(SendMessageRequest {
+                    to_username: "bob".to_string(),
+                    content: "over here".to_string(),
+                    encrypted: Some(false),
+                }),
+            )
+            .await
+            .unwrap();
+
+            let updates = tokio::time::timeout(std::time::Duration::from_secs(10), poll)
+                .await
+                .expect("long poll was not woken")
+                .unwrap()
+                .unwrap();
+            assert_eq!(updates.next_after, sent.message_id);
+            assert_eq!(updates.messages[0].content, "over here");
+
+            // Background work for the message stays on the instance it was sent through
+            assert!(matches!(
+                second_local.try_recv(),
+                Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+            ));
+
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+            assert!(shutdown.stop_background(deadline).await);
+            // Listeners have given their connections back
+            tokio::time::timeout(std::time::Duration::from_secs(5), db::close(&pool))
+                .await
+                .expect("pool did not close");
+        }
+    }
+
+    async fn search(
+        pool: &DbPool,
+        user_id: i64,
+        q: &str,
+        limit: i64,
+        offset: i64,
+    ) -> UserSearchResponse {
+        search_users(
+            State(pool.clone()),
+            Extension(user_id),
+            axum::extract::Query(UserSearchQuery {
+                q: q.to_string(),
+                limit: Some(limit),
+                offset: Some(offset),
+            }),
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    fn usernames(response: &UserSearchResponse) -> Vec<&str> {
+        response
+            .results
+            .iter()
+            .map(|r| r.username.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn search_ranks_prefixes_before_misspellings_and_skips_hidden_accounts() {
+        for pool in test_pools().await {
+            let me = insert_user(&pool, "alina").await;
+            for username in [
+                "Alicia", "alice", "alison", "bob", "alxandra", "malice", "ali_100%",
+            ] {
+                insert_user(&pool, username).await;
+            }
+            db::query("UPDATE users SET display_name = 'Alice Bobson' WHERE username = 'bob'")
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+            db::query("UPDATE users SET discoverable = FALSE WHERE username = 'alison'")
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+
+            // Username prefixes, then display name words, then misspellings,
+            // each in username order ignoring case; the caller is left out
+            let found = search(&pool, me, "ali", 10, 0).await;
+            assert_eq!(
+                usernames(&found),
+                ["ali_100%", "alice", "Alicia", "bob", "alxandra", "malice"]
+            );
+            assert_eq!(found.next_offset, None);
+
+            // Misspellings needn't start with the same letter
+            let exact = search(&pool, me, "ALICE", 10, 0).await;
+            assert_eq!(usernames(&exact), ["alice", "bob", "Alicia", "malice"]);
+            let typo = search(&pool, me, "xlicia", 10, 0).await;
+            assert_eq!(usernames(&typo), ["Alicia"]);
+
+            // LIKE wildcards in the query are taken literally
+            let literal = search(&pool, me, "ali_1", 10, 0).await;
+            assert_eq!(usernames(&literal), ["ali_100%"]);
+            assert!(search(&pool, me, "%", 10, 0).await.results.is_empty());
+
+            let first_page = search(&pool, me, "ali", 2, 0).await;
+            assert_eq!(usernames(&first_page), ["ali_100%", "alice"]);
+            assert_eq!(first_page.next_offset, Some(2));
+            let last_page = search(&pool, me, "ali", 2, 4).await;
+            assert_eq!(usernames(&last_page), ["alxandra", "malice"]);
+            assert_eq!(last_page.next_offset, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_ranks_every_candidate_not_just_the_first_batch() {
+        for pool in test_pools().await {
+            let me = insert_user(&pool, "me").await;
+            for i in 0..SEARCH_BATCH + 100 {
+                insert_user(&pool, &format!("a{i:03}")).await;
+            }
+            insert_user(&pool, "azbz").await;
+
+            // Every account contains a piece of "abz", but only the last one
+            // is close enough to match
+            let found = search(&pool, me, "abz", 10, 0).await;
+            assert_eq!(usernames(&found), ["azbz"]);
+
+            let all = search(&pool, me, "a", 20, MAX_SEARCH_RESULTS as i64 - 20).await;
+            assert_eq!(all.results.len(), 20);
+            assert_eq!(all.next_offset, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn two_factor_and_recovery_codes_are_accepted_once() {
+        for pool in test_pools().await {
+            let user_id = account(&sql_store(&pool), "alice").await;
+            let secret = crate::totp::generate_secret();
+            db::query("INSERT INTO user_totp (user_id, secret, enabled, created_at) VALUES (?, ?, TRUE, ?)")
+                .bind(user_id)
+                .bind(&secret)
+                .bind(Utc::now())
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+            db::query(
+                "INSERT INTO totp_recovery_codes (user_id, code_hash, created_at) VALUES (?, ?, ?)",
+            )
+            .bind(user_id)
+            .bind(crate::totp::hash_recovery_code("abcde-fghjk"))
+            .bind(Utc::now())
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+
+            let code = crate::totp::code_at(&secret, Utc::now().timestamp() as u64);
+            let login = two_factor_login(&pool, user_id, Some(&code), None)
+                .await
+                .unwrap();
+            assert!(login.token.is_some());
+            let replayed = two_factor_login(&pool, user_id, Some(&code), None).await;
+            assert_eq!(replayed.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+
+            // A code spent on login can't also be used to turn 2FA off
+            let disabled = disable_two_factor(
+                State(pool.clone()),
+                Extension(user_id),
+                Json(TwoFactorDisableRequest {
+                    password: "correct horse battery staple".to_string(),
+                    code,
+                }),
+            )
+            .await;
+            assert_eq!(disabled.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM user_totp").await, 1);
+
+            let recovered = two_factor_login(&pool, user_id, None, Some("ABCDE FGHJK")).await;
+            assert!(recovered.unwrap().token.is_some());
+            let reused = two_factor_login(&pool, user_id, None, Some("abcde-fghjk")).await;
+            assert_eq!(reused.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
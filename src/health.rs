@@ -0,0 +1,123 @@
+// Liveness and readiness probes. Liveness only says the process is serving
+// requests; readiness checks the things it needs to do useful work, so an
+// orchestrator can stop routing traffic to an instance that can't write.
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::models::{CheckResult, LivenessResponse, ReadinessChecks, ReadinessResponse};
+use axum::{extract::State, http::StatusCode, Json};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// A locked database makes SQLite wait out its busy timeout; report it as a
+// failure well before a probe would give up on us
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn live() -> Json<LivenessResponse> {
+    Json(LivenessResponse {
+        status: "ok".to_string(),
+    })
+}
+
+/// Runs every check and answers 503 if any of them failed.
+pub async fn ready(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let (database, migrations, disk) = tokio::join!(
+        run_check(check_database(&pool)),
+        run_check(check_migrations(&pool)),
+        run_check(check_disk(config.min_free_disk_mb)),
+    );
+    let checks = ReadinessChecks {
+        database,
+        migrations,
+        disk,
+    };
+
+    let healthy = [&checks.database, &checks.migrations, &checks.disk]
+        .iter()
+        .all(|check| check.error.is_none());
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadinessResponse {
+            status: if healthy { "ok" } else { "fail" }.to_string(),
+            checks,
+        }),
+    )
+}
+
+async fn run_check<F>(check: F) -> CheckResult
+where
+    F: Future<Output = Result<Option<String>, String>>,
+{
+    let start = Instant::now();
+    let outcome = tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())));
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    match outcome {
+        Ok(detail) => CheckResult {
+            status: "ok".to_string(),
+            duration_ms,
+            detail,
+            error: None,
+        },
+        Err(error) => CheckResult {
+            status: "fail".to_string(),
+            duration_ms,
+            detail: None,
+            error: Some(error),
+        },
+    }
+}
+
+// Takes the write lock and rewrites the schema version inside a transaction
+// that is then rolled back, so a locked or read-only database fails here
+// rather than on the next message sent
+async fn check_database(pool: &DbPool) -> Result<Option<String>, String> {
+    let mut tx = pool.begin_immediate().await.map_err(|e| e.to_string())?;
+    let version = db::schema_version(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    let rewrite = match pool.backend() {
+        db::Backend::Sqlite => db::query(format!("PRAGMA user_version = {}", version)),
+        db::Backend::Postgres => db::query("UPDATE schema_version SET version = ?").bind(version),
+    };
+    rewrite.execute(&mut *tx).await.map_err(|e| e.to_string())?;
+    tx.rollback().await.map_err(|e| e.to_string())?;
+    Ok(None)
+}
+
+async fn check_migrations(pool: &DbPool) -> Result<Option<String>, String> {
+    let version = db::schema_version(pool.as_ref())
+        .await
+        .map_err(|e| e.to_string())?;
+    if version != db::SCHEMA_VERSION {
+        return Err(format!(
+            "database is at schema version {}, this server expects {}",
+            version,
+            db::SCHEMA_VERSION
+        ));
+    }
+    Ok(Some(format!("schema version {}", version)))
+}
+
+async fn check_disk(min_free_mb: u64) -> Result<Option<String>, String> {
+    let available = fs4::available_space(db::data_dir()).map_err(|e| e.to_string())?;
+    let available_mb = available / (1024 * 1024);
+    if available_mb < min_free_mb {
+        return Err(format!(
+            "{} MB free on the data volume, below the minimum of {} MB",
+            available_mb, min_free_mb
+        ));
+    }
+    Ok(Some(format!("{} MB free", available_mb)))
+}
@@ -1,14 +1,39 @@
 mod auth;
+mod avatar;
+mod backup;
+mod bots;
+mod config;
 mod db;
+mod email;
+mod error;
+mod events;
+mod export;
 mod handlers;
+mod health;
+mod metrics;
 mod models;
+mod outbound;
+mod push;
+mod rate_limit;
+mod search;
+mod shutdown;
+mod state;
+mod store;
+mod totp;
+mod users;
+mod validation;
+mod webhooks;
 
 use axum::{
+    http::HeaderName,
     middleware,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
+use std::future::IntoFuture;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -23,59 +48,469 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // `migchat-server backup` and `migchat-server restore` run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(backup::run_command(&args).await);
+    }
+
+    // Read before touching the database, so a bad setting stops startup first
+    let config = Arc::new(config::Config::from_env());
+
     // Initialize database
     let pool = db::init_db().await.expect("Failed to initialize database");
     tracing::info!("Database initialized successfully");
 
+    if let Err(e) = export::remove_legacy_archives(&pool).await {
+        tracing::error!("Failed to remove legacy export archives: {}", e);
+    }
+
+    // Pick up exports interrupted by the last shutdown
+    if let Err(e) = export::resume_pending(&pool).await {
+        tracing::error!("Failed to resume export jobs: {}", e);
+    }
+
+    let shutdown = shutdown::Shutdown::new();
+    // Instances sharing a PostgreSQL database pass events to each other, so
+    // a message wakes long polls whichever instance it was sent through
+    let events = match pool.as_ref() {
+        db::Db::Postgres(pg_pool) => {
+            let bus = Arc::new(events::PostgresBus::new(pg_pool.clone()));
+            events::EventHub::connect(bus, &shutdown)
+                .await
+                .expect("Failed to connect to the event bus")
+        }
+        db::Db::Sqlite(_) => events::EventHub::new(),
+    };
+    let webhook_queue = webhooks::DeliveryQueue::default();
+    let mailer = email::Mailer::from_config(&config).expect("Invalid email configuration");
+    let state = state::AppState {
+        pool: pool.clone(),
+        store: Arc::new(store::SqlStore::new(pool.clone())),
+        config: config.clone(),
+        events: events.clone(),
+        webhook_queue: webhook_queue.clone(),
+        mailer: mailer.clone(),
+        shutdown: shutdown.clone(),
+    };
+
+    bots::spawn_webhook_dispatcher(
+        pool.clone(),
+        events.clone(),
+        webhook_queue.clone(),
+        shutdown.clone(),
+    );
+    email::spawn_digests(pool.clone(), config.clone(), mailer, shutdown.clone());
+    backup::spawn(pool.clone(), config.clone(), shutdown.clone());
+    webhooks::spawn(
+        pool.clone(),
+        config.clone(),
+        events.clone(),
+        webhook_queue,
+        shutdown.clone(),
+    );
+
+    // Push notifications go through a relay when one is configured, and
+    // straight to device endpoints otherwise
+    match &config.push_gateway_url {
+        Some(url) => push::spawn(
+            pool.clone(),
+            config.clone(),
+            events,
+            push::RelayGateway::new(url.clone()),
+            shutdown.clone(),
+        ),
+        None => {
+            let vapid = push::Vapid::from_config(&config)
+                .transpose()
+                .expect("Invalid VAPID configuration");
+            push::spawn(
+                pool.clone(),
+                config.clone(),
+                events,
+                push::DirectGateway::new(vapid, config.allow_private_endpoints),
+                shutdown.clone(),
+            );
+        }
+    }
+
+    // Per-route rate limits. Routes that work without a session are limited
+    // by client address, authenticated ones by user. Limits are counted per
+    // machine, not across machines sharing a database.
+    let ip_limiter = |limit| {
+        Arc::new(rate_limit::IpRateLimiter::new(limit, config.trust_proxy_headers))
+    };
+    let create_account_limiter = ip_limiter(config.rate_limit_create_account);
+    // Shared by both login steps so the second can't be used to bypass the first
+    let login_limiter = ip_limiter(config.rate_limit_login);
+    let get_keys_limiter = ip_limiter(config.rate_limit_get_keys);
+    // Shared by both reset steps, like login
+    let password_reset_limiter = ip_limiter(config.rate_limit_password_reset);
+    let send_message_limiter = Arc::new(rate_limit::RateLimiter::from_limit(
+        config.rate_limit_send_message,
+    ));
+    // Directory search is cheap to call and useful for scraping, so it gets its own budget
+    let search_limiter = Arc::new(rate_limit::RateLimiter::from_limit(config.rate_limit_search));
+    // Incoming webhooks post messages without a session, so they share the
+    // message budget per client address instead
+    let incoming_webhook_limiter = ip_limiter(config.rate_limit_send_message);
+
     // Setup CORS
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([HeaderName::from_static(error::REQUEST_ID_HEADER)]);
 
     // Build our application with routes
     let app = Router::new()
         .route("/health", get(handlers::health_check))
-        .route("/api/account/create", post(handlers::create_account))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route(
+            "/api/account/create",
+            post(handlers::create_account).route_layer(middleware::from_fn_with_state(
+                create_account_limiter,
+                rate_limit::limit_by_ip,
+            )),
+        )
+        .route(
+            "/api/account",
+            delete(handlers::delete_account).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/export",
+            post(handlers::request_export).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/export/:id",
+            get(handlers::get_export_status).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/export/:id/download",
+            get(handlers::download_export).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/login",
+            post(handlers::login).route_layer(middleware::from_fn_with_state(
+                login_limiter.clone(),
+                rate_limit::limit_by_ip,
+            )),
+        )
+        .route(
+            "/api/account/login/2fa",
+            post(handlers::complete_two_factor_login).route_layer(
+                middleware::from_fn_with_state(login_limiter, rate_limit::limit_by_ip),
+            ),
+        )
+        .route(
+            "/api/account/2fa",
+            get(handlers::get_two_factor_status).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/2fa/enroll",
+            post(handlers::enroll_two_factor).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/2fa/confirm",
+            post(handlers::confirm_two_factor).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/2fa/disable",
+            post(handlers::disable_two_factor).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
         .route(
             "/api/account/update-username",
             post(handlers::update_username).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
+                state.clone(),
                 auth::auth_middleware,
             )),
         )
         .route(
-            "/api/messages/send",
-            post(handlers::send_message).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
+            "/api/account/password",
+            post(handlers::change_password).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/account/email",
+            get(handlers::get_email)
+                .put(handlers::set_email)
+                .delete(handlers::remove_email)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route("/api/account/email/verify", get(handlers::verify_email))
+        .route(
+            "/api/account/password-reset",
+            post(handlers::request_password_reset).route_layer(middleware::from_fn_with_state(
+                password_reset_limiter.clone(),
+                rate_limit::limit_by_ip,
+            )),
+        )
+        .route(
+            "/api/account/password-reset/confirm",
+            post(handlers::confirm_password_reset).route_layer(
+                middleware::from_fn_with_state(password_reset_limiter, rate_limit::limit_by_ip),
+            ),
+        )
+        .route(
+            "/api/account/profile",
+            patch(handlers::update_profile).route_layer(middleware::from_fn_with_state(
+                state.clone(),
                 auth::auth_middleware,
             )),
         )
+        .route(
+            "/api/account/settings",
+            get(handlers::get_account_settings)
+                .patch(handlers::update_account_settings)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/users/search",
+            get(handlers::search_users)
+                .route_layer(middleware::from_fn_with_state(
+                    search_limiter,
+                    rate_limit::limit_by_user,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route("/api/users/:username", get(handlers::get_user_profile))
+        .route("/api/users/:username/avatar", get(handlers::get_user_avatar))
+        .route(
+            "/api/users/:username/avatar/thumbnail",
+            get(handlers::get_user_avatar_thumbnail),
+        )
+        .route(
+            "/api/messages/send",
+            post(handlers::send_message)
+                .route_layer(middleware::from_fn_with_state(
+                    send_message_limiter,
+                    rate_limit::limit_by_user,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    (state.clone(), auth::Scope::SendMessages),
+                    auth::scoped_auth_middleware,
+                )),
+        )
         .route(
             "/api/messages",
             get(handlers::get_messages).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
-                auth::auth_middleware,
+                (state.clone(), auth::Scope::ReadMessages),
+                auth::scoped_auth_middleware,
             )),
         )
         .route(
             "/api/messages/filtered",
             get(handlers::get_filtered_messages).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
-                auth::auth_middleware,
+                (state.clone(), auth::Scope::ReadMessages),
+                auth::scoped_auth_middleware,
             )),
         )
         .route(
             "/api/conversations",
             get(handlers::get_conversations).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
-                auth::auth_middleware,
+                (state.clone(), auth::Scope::ReadMessages),
+                auth::scoped_auth_middleware,
             )),
         )
+        .route(
+            "/api/conversations/:username/mute",
+            put(handlers::mute_conversation)
+                .delete(handlers::unmute_conversation)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
         .route(
             "/api/messages/mark-read",
             post(handlers::mark_messages_read).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
+                (state.clone(), auth::Scope::ReadMessages),
+                auth::scoped_auth_middleware,
+            )),
+        )
+        // Personal access tokens can only be managed from a session
+        .route(
+            "/api/tokens",
+            get(handlers::list_api_tokens)
+                .post(handlers::create_api_token)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/tokens/:id",
+            delete(handlers::revoke_api_token).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        // Bots are managed by their owner's session
+        .route(
+            "/api/bots",
+            get(handlers::list_bots)
+                .post(handlers::create_bot)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/bots/:username",
+            patch(handlers::update_bot)
+                .delete(handlers::delete_bot)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/bots/:username/tokens",
+            get(handlers::list_bot_tokens)
+                .post(handlers::create_bot_token)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/bots/:username/tokens/:id",
+            delete(handlers::revoke_bot_token).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/webhooks",
+            get(handlers::list_webhooks)
+                .post(handlers::create_webhook)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/webhooks/:id",
+            delete(handlers::delete_webhook).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/webhooks/:id/ping",
+            post(handlers::ping_webhook).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/webhooks/:id/deliveries",
+            get(handlers::get_webhook_deliveries).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/integrations/incoming",
+            get(handlers::list_incoming_webhooks)
+                .post(handlers::create_incoming_webhook)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/integrations/incoming/:id",
+            delete(handlers::delete_incoming_webhook).route_layer(
+                middleware::from_fn_with_state(state.clone(), auth::auth_middleware),
+            ),
+        )
+        .route(
+            "/api/integrations/incoming/:id/regenerate",
+            post(handlers::regenerate_incoming_webhook).route_layer(
+                middleware::from_fn_with_state(state.clone(), auth::auth_middleware),
+            ),
+        )
+        .route(
+            "/api/hooks/:token",
+            post(handlers::post_incoming_webhook).route_layer(middleware::from_fn_with_state(
+                incoming_webhook_limiter,
+                rate_limit::limit_by_ip,
+            )),
+        )
+        .route(
+            "/api/updates",
+            get(handlers::get_updates).route_layer(middleware::from_fn_with_state(
+                (state.clone(), auth::Scope::ReadMessages),
+                auth::scoped_auth_middleware,
+            )),
+        )
+        // Push notification devices
+        .route("/api/push/vapid-key", get(handlers::get_vapid_key))
+        .route(
+            "/api/push/devices",
+            get(handlers::list_push_devices)
+                .post(handlers::register_push_device)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::auth_middleware,
+                )),
+        )
+        .route(
+            "/api/push/devices/:id",
+            delete(handlers::remove_push_device).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/notifications",
+            get(handlers::get_notifications).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::auth_middleware,
+            )),
+        )
+        .route(
+            "/api/notifications/mark-read",
+            post(handlers::mark_notifications_read).route_layer(middleware::from_fn_with_state(
+                state.clone(),
                 auth::auth_middleware,
             )),
         )
@@ -83,13 +518,22 @@ async fn main() {
         .route(
             "/api/keys/upload",
             post(handlers::upload_keys).route_layer(middleware::from_fn_with_state(
-                pool.clone(),
-                auth::auth_middleware,
+                (state.clone(), auth::Scope::ManageKeys),
+                auth::scoped_auth_middleware,
             )),
         )
-        .route("/api/keys/:username", get(handlers::get_keys))
+        .route(
+            "/api/keys/:username",
+            get(handlers::get_keys).route_layer(middleware::from_fn_with_state(
+                get_keys_limiter,
+                rate_limit::limit_by_ip,
+            )),
+        )
+        .layer(middleware::from_fn(error::rejections_as_json))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(error::assign_request_id))
         .layer(cors)
-        .with_state(pool);
+        .with_state(state);
 
     // Get port from environment variable or use default
     let port = std::env::var("PORT")
@@ -104,7 +548,36 @@ async fn main() {
         .await
         .expect("Failed to bind to address");
 
-    axum::serve(listener, app)
-        .await
-        .expect("Failed to start server");
+    // Peer addresses are needed for per-client rate limits
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown::signal(shutdown.clone()))
+        .into_future(),
+    );
+
+    // The server can finish draining before `requested` is polled, in which
+    // case its handle must not be awaited again
+    let drained = tokio::select! {
+        result = &mut server => {
+            result.expect("Server task panicked").expect("Failed to start server");
+            true
+        }
+        _ = shutdown.requested() => false,
+    };
+
+    // One deadline covers draining connections and flushing background jobs
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_secs(config.shutdown_timeout_secs);
+    if !drained && tokio::time::timeout_at(deadline, server).await.is_err() {
+        tracing::warn!("Dropping connections still open at the shutdown deadline");
+    }
+    if !shutdown.stop_background(deadline).await {
+        tracing::warn!("Dropping background jobs still running at the shutdown deadline");
+    }
+
+    db::close(&pool).await;
+    tracing::info!("Shutdown complete");
 }
@@ -0,0 +1,205 @@
+// Prometheus metrics. Counters and histograms updated as requests are served
+// live in a process-wide registry; figures read from the database are
+// collected when /metrics is scraped.
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::error::AppError;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric names are unique");
+    collector
+}
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("migchat_http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "migchat_http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["method", "route"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+static MESSAGES_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("migchat_messages_sent_total", "Messages stored, by how they were sent"),
+            &["source"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+static REALTIME_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "migchat_realtime_connections",
+            "Open long-poll requests waiting for messages",
+        )
+        .expect("valid metric"),
+    )
+});
+
+// Upper bounds for the per-user count of unused one-time prekeys
+const PREKEY_BUCKETS: [f64; 8] = [0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0];
+
+pub fn message_sent(via_integration: bool) {
+    let source = if via_integration { "integration" } else { "user" };
+    MESSAGES_SENT.with_label_values(&[source]).inc();
+}
+
+/// Counts an open real-time connection for as long as it is held.
+pub struct ConnectionGuard(());
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        REALTIME_CONNECTIONS.inc();
+        ConnectionGuard(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        REALTIME_CONNECTIONS.dec();
+    }
+}
+
+/// Records the count and latency of every request. Routes are labelled by
+/// their pattern (`/api/users/:username`) to keep the number of series bounded.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = request.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    HTTP_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+/// Serves the metrics in the Prometheus text format. When METRICS_TOKEN is
+/// set, scrapers must send it as a bearer token.
+pub async fn metrics_handler(
+    State(pool): State<DbPool>,
+    State(config): State<Arc<Config>>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Some(token) = &config.metrics_token {
+        let expected = format!("Bearer {}", token);
+        let authorized = headers
+            .get(header::AUTHORIZATION)
+            .is_some_and(|value| value.as_bytes() == expected.as_bytes());
+        if !authorized {
+            return AppError::unauthorized("invalid_token", "Missing or invalid bearer token")
+                .into_response();
+        }
+    }
+
+    let mut families = REGISTRY.gather();
+    match collect_database_metrics(&pool).await {
+        Ok(registry) => families.extend(registry.gather()),
+        Err(e) => tracing::error!("Failed to collect database metrics: {}", e),
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&families, &mut body) {
+        return AppError::internal("Failed to encode metrics", e).into_response();
+    }
+
+    ([(header::CONTENT_TYPE, encoder.format_type().to_string())], body).into_response()
+}
+
+// Built fresh on each scrape, since these are snapshots of current state
+async fn collect_database_metrics(pool: &DbPool) -> Result<Registry, Box<dyn std::error::Error>> {
+    let registry = Registry::new();
+
+    let sessions = IntGauge::new("migchat_active_sessions", "Sessions that have not been revoked")?;
+    registry.register(Box::new(sessions.clone()))?;
+    let count: i64 = db::query("SELECT COUNT(*) as count FROM sessions")
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("count");
+    sessions.set(count);
+
+    let connections = IntGaugeVec::new(
+        Opts::new("migchat_db_pool_connections", "Database pool connections by state"),
+        &["state"],
+    )?;
+    registry.register(Box::new(connections.clone()))?;
+    let idle = pool.num_idle() as i64;
+    connections.with_label_values(&["idle"]).set(idle);
+    connections
+        .with_label_values(&["in_use"])
+        .set(i64::from(pool.size()) - idle);
+
+    let max_connections = IntGauge::new(
+        "migchat_db_pool_max_connections",
+        "Largest number of connections the pool will open",
+    )?;
+    registry.register(Box::new(max_connections.clone()))?;
+    max_connections.set(i64::from(pool.max_connections()));
+
+    let prekeys = Histogram::with_opts(
+        HistogramOpts::new(
+            "migchat_one_time_prekeys_remaining",
+            "Unused one-time prekeys per user with a key bundle",
+        )
+        .buckets(PREKEY_BUCKETS.to_vec()),
+    )?;
+    registry.register(Box::new(prekeys.clone()))?;
+    let rows = db::query(
+        r#"
+        SELECT COUNT(p.id) as remaining
+        FROM user_keys k
+        LEFT JOIN one_time_prekeys p ON p.user_id = k.user_id AND p.used = FALSE
+        GROUP BY k.user_id
+        "#,
+    )
+    .fetch_all(pool.as_ref())
+    .await?;
+    for row in rows {
+        prekeys.observe(row.get::<i64, _>("remaining") as f64);
+    }
+
+    Ok(registry)
+}
@@ -1,50 +1,50 @@
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
-pub struct User {
-    pub id: i64,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateAccountRequest {
     pub username: String,
-    pub password_hash: String,
-    pub created_at: DateTime<Utc>,
+    pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
-pub struct Session {
-    pub id: i64,
-    pub user_id: i64,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateAccountResponse {
     pub token: String,
-    pub created_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
-pub struct Message {
-    pub id: i64,
-    pub from_user_id: i64,
-    pub to_user_id: i64,
-    pub content: String,
-    pub created_at: DateTime<Utc>,
-    pub read_at: Option<DateTime<Utc>>,
+    pub user_id: i64,
+    pub username: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct CreateAccountRequest {
+pub struct LoginRequest {
     pub username: String,
     pub password: String,
 }
 
+/// When `two_factor_required` is set, `token` is absent and `challenge_token`
+/// must be exchanged for a session along with a TOTP or recovery code.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct CreateAccountResponse {
-    pub token: String,
+pub struct LoginResponse {
+    pub token: Option<String>,
     pub user_id: i64,
     pub username: String,
+    pub two_factor_required: bool,
+    pub challenge_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SendMessageRequest {
     pub to_username: String,
     pub content: String,
+    /// Whether `content` is end-to-end encrypted (the default). Only
+    /// unencrypted messages have their text shown in push notifications.
+    pub encrypted: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,22 +57,67 @@ pub struct SendMessageResponse {
 pub struct MessageResponse {
     pub id: i64,
     pub from_username: String,
+    pub from_display_name: Option<String>,
+    pub from_is_bot: bool,
     pub to_username: String,
+    pub to_display_name: Option<String>,
+    pub to_is_bot: bool,
     pub content: String,
+    pub encrypted: bool,
+    /// Name of the incoming webhook that posted the message, if any
+    pub via_integration: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConversationResponse {
     pub username: String,
+    pub display_name: Option<String>,
+    pub is_bot: bool,
     pub last_message: String,
     pub last_message_time: DateTime<Utc>,
     pub unread_count: i64,
+    /// Push notifications for this conversation are muted
+    pub muted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LivenessResponse {
+    pub status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    /// "ok" when every check passed, otherwise "fail"
+    pub status: String,
+    pub checks: ReadinessChecks,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessChecks {
+    pub database: CheckResult,
+    pub migrations: CheckResult,
+    pub disk: CheckResult,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub status: String,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
     pub error: String,
+    /// Stable identifier of the kind of error, for clients to match on
+    pub code: String,
+    /// Identifies the request in the server's logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,6 +131,341 @@ pub struct UpdateUsernameResponse {
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePasswordResponse {
+    pub revoked_sessions: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+    /// Required when two-factor authentication is enabled
+    pub code: Option<String>,
+}
+
+// Two-factor authentication models
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorStatusResponse {
+    pub enabled: bool,
+    pub recovery_codes_remaining: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorEnrollResponse {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorConfirmRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorConfirmResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorDisableRequest {
+    pub password: String,
+    pub code: String,
+}
+
+// Profile models
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProfileResponse {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub has_avatar: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Fields left out are unchanged; an empty string clears the field.
+/// `avatar` is a base64-encoded PNG, JPEG, GIF or WebP image.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar: Option<String>,
+}
+
+// User directory models
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSearchResult {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub has_avatar: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSearchResponse {
+    pub results: Vec<UserSearchResult>,
+    pub next_offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountSettingsResponse {
+    pub discoverable: bool,
+    pub email_digests: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateAccountSettingsRequest {
+    pub discoverable: Option<bool>,
+    pub email_digests: Option<bool>,
+}
+
+// Email models
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailResponse {
+    pub email: Option<String>,
+    pub verified: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetEmailRequest {
+    pub email: String,
+    /// Current password, since the address can be used to reset it
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailTokenQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetConfirmRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotificationResponse {
+    pub id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportJobResponse {
+    pub id: i64,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub download_url: Option<String>,
+}
+
+// Personal access token models
+/// `scopes` are any of `messages:read`, `messages:send` and `keys:manage`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub description: String,
+    pub scopes: Vec<String>,
+    /// Days until the token stops working; omit for a token that never expires
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenResponse {
+    pub id: i64,
+    pub description: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The token itself is only ever returned here, at creation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiTokenResponse {
+    pub token: String,
+    #[serde(flatten)]
+    pub api_token: ApiTokenResponse,
+}
+
+// Bot models
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateBotRequest {
+    pub username: String,
+    pub display_name: Option<String>,
+    /// Incoming messages are POSTed here as they arrive
+    pub webhook_url: Option<String>,
+}
+
+/// Fields left out are unchanged; an empty string clears the field.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateBotRequest {
+    pub display_name: Option<String>,
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BotResponse {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub webhook_url: Option<String>,
+    /// Key for verifying webhook signatures, only returned when `webhook_url`
+    /// is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// `token` has the `messages:read` and `messages:send` scopes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateBotResponse {
+    pub bot: BotResponse,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdatesQuery {
+    /// Only return messages with a larger id
+    pub after: Option<i64>,
+    /// Seconds to wait for a new message when there is none yet
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdatesResponse {
+    pub messages: Vec<MessageResponse>,
+    /// Pass as `after` on the next request
+    pub next_after: i64,
+}
+
+// Webhook models
+/// `events` are any of `message.created`, `message.read` and `keys.updated`.
+/// `global` webhooks receive events for every user and need an admin account.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub events: Vec<String>,
+    pub global: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookResponse {
+    pub id: i64,
+    pub url: String,
+    pub events: Vec<String>,
+    pub global: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The signing secret is only ever returned here, at creation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateWebhookResponse {
+    pub secret: String,
+    #[serde(flatten)]
+    pub webhook: WebhookResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDeliveryResponse {
+    pub id: i64,
+    pub event: String,
+    pub status: String,
+    pub attempts: i64,
+    pub last_status_code: Option<i64>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+// Incoming webhook models
+/// Messages posted to the hook are sent as the creator to `with_username`,
+/// labelled with `name`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateIncomingWebhookRequest {
+    pub with_username: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IncomingWebhookResponse {
+    pub id: i64,
+    pub name: String,
+    pub with_username: String,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The URL embeds the secret, so it is only ever returned here, at creation
+/// or when the secret is regenerated.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateIncomingWebhookResponse {
+    pub url: String,
+    #[serde(flatten)]
+    pub webhook: IncomingWebhookResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IncomingWebhookPayload {
+    pub text: String,
+}
+
+// Push notification models
+/// `kind` is `unifiedpush` or `webpush`. Web Push devices also pass the
+/// subscription's `p256dh` and `auth` keys.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterPushDeviceRequest {
+    pub kind: String,
+    pub endpoint: String,
+    pub p256dh: Option<String>,
+    pub auth: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushDeviceResponse {
+    pub id: i64,
+    pub kind: String,
+    pub endpoint: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VapidKeyResponse {
+    pub public_key: String,
+}
+
+/// Omit `duration_minutes` to mute until unmuted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MuteConversationRequest {
+    pub duration_minutes: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MuteResponse {
+    pub username: String,
+    pub muted: bool,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
 // E2E Encryption models
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KeyBundle {
@@ -110,21 +490,3 @@ pub struct GetKeysResponse {
     pub key_bundle: KeyBundle,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct UserKey {
-    pub user_id: i64,
-    pub identity_key: String,
-    pub signed_prekey: String,
-    pub signed_prekey_signature: String,
-    pub created_at: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct OneTimePreKey {
-    pub id: i64,
-    pub user_id: i64,
-    pub key_id: i64,
-    pub public_key: String,
-    pub used: bool,
-    pub created_at: String,
-}
@@ -0,0 +1,192 @@
+// HTTP requests to URLs users supply: webhooks, bot webhooks and push device
+// endpoints. Such a URL could point the server at itself or at the private
+// network it runs in (169.254.169.254, *.internal, ...), so unless
+// ALLOW_PRIVATE_ENDPOINTS is set every address a request connects to must be
+// public. Hostnames are checked as they are resolved for each connection, so
+// a name that later starts resolving to a private address is still refused.
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_REDIRECTS: usize = 5;
+const NOT_PUBLIC: &str = "Endpoint address is not public";
+
+/// A client for user-supplied URLs.
+#[derive(Clone)]
+pub struct OutboundClient {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+impl OutboundClient {
+    pub fn new(timeout: Duration, allow_private: bool) -> Self {
+        let mut builder = reqwest::Client::builder().timeout(timeout);
+        if !allow_private {
+            builder = builder
+                .dns_resolver(Arc::new(PublicResolver))
+                // A proxy would resolve names itself, out of our sight
+                .no_proxy()
+                .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                    if attempt.previous().len() >= MAX_REDIRECTS {
+                        attempt.error("too many redirects")
+                    } else if let Err(e) = check_host(attempt.url()) {
+                        attempt.error(e)
+                    } else {
+                        attempt.follow()
+                    }
+                }));
+        }
+
+        OutboundClient {
+            client: builder.build().expect("Failed to build HTTP client"),
+            allow_private,
+        }
+    }
+
+    /// Start a POST to `url`, or say why it may not be contacted. Literal
+    /// addresses never reach the resolver, so they are checked here.
+    pub fn post(&self, url: &str) -> Result<reqwest::RequestBuilder, String> {
+        let url = parse(url)?;
+        if !self.allow_private {
+            check_host(&url)?;
+        }
+        Ok(self.client.post(url))
+    }
+}
+
+/// Check a URL as it is registered: it must be an absolute http(s) URL, and
+/// a literal address in it must be public. Hostnames are checked when used.
+pub fn validate_url(url: &str, allow_private: bool) -> Result<(), String> {
+    let url = parse(url)?;
+    if !allow_private {
+        check_host(&url)?;
+    }
+    Ok(())
+}
+
+fn parse(url: &str) -> Result<reqwest::Url, String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {
+            Ok(parsed)
+        }
+        _ => Err("URL must be an absolute http or https URL".to_string()),
+    }
+}
+
+fn check_host(url: &reqwest::Url) -> Result<(), String> {
+    let host = url.host_str().unwrap_or_default();
+    // IPv6 literals keep their brackets in host_str
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    match literal.parse::<IpAddr>() {
+        Ok(ip) if !is_public(ip) => Err(NOT_PUBLIC.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to loopback,
+/// private, link-local, unique-local and other special-purpose ranges.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // Link-local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+// Resolves names as usual but only hands back public addresses
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(NOT_PUBLIC.into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_pass() {
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fdaa::3",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn registration_rejects_literal_private_addresses() {
+        assert!(validate_url("https://example.com/hook", false).is_ok());
+        assert!(validate_url("ftp://example.com/hook", false).is_err());
+        assert!(validate_url("http://169.254.169.254/latest", false).is_err());
+        assert!(validate_url("http://[::1]:8080/", false).is_err());
+        assert!(validate_url("http://[::1]:8080/", true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn names_resolving_to_private_addresses_are_refused() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = OutboundClient::new(Duration::from_secs(5), false);
+
+        assert!(client.post(&format!("http://127.0.0.1:{port}/")).is_err());
+        let request = client.post(&format!("http://localhost:{port}/")).unwrap();
+        assert!(request.send().await.is_err());
+
+        // Nothing reached the listener
+        let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+}
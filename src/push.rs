@@ -0,0 +1,702 @@
+// Push notifications for clients that can't keep a connection open. New
+// messages are collected per recipient for a short window, then announced
+// once to each of the recipient's devices through a PushGateway.
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::events::{self, Event, EventHub};
+use crate::outbound::OutboundClient;
+use crate::shutdown::Shutdown;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use p256::ecdsa::signature::Signer;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+pub const KIND_UNIFIED_PUSH: &str = "unifiedpush";
+pub const KIND_WEB_PUSH: &str = "webpush";
+
+pub const DEVICE_KINDS: [&str; 2] = [KIND_UNIFIED_PUSH, KIND_WEB_PUSH];
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+// Web Push services keep undelivered notifications this long
+const PUSH_TTL_SECS: u64 = 24 * 60 * 60;
+const VAPID_TOKEN_SECS: i64 = 12 * 60 * 60;
+// Keeps payloads well under the 4 KB Web Push limit
+const MAX_PREVIEW_CHARS: usize = 200;
+const MAX_SENDERS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct PushDevice {
+    pub id: i64,
+    pub kind: String,
+    pub endpoint: String,
+    pub p256dh: Option<String>,
+    pub auth: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// The endpoint no longer exists; the device should be forgotten
+    Gone,
+    Failed(String),
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::Gone => write!(f, "endpoint is gone"),
+            PushError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for PushError {
+    fn from(e: reqwest::Error) -> Self {
+        PushError::Failed(e.to_string())
+    }
+}
+
+/// Something that can get a notification to a device.
+pub trait PushGateway: Send + Sync + 'static {
+    fn deliver(
+        &self,
+        device: &PushDevice,
+        notification: &Value,
+    ) -> impl Future<Output = Result<(), PushError>> + Send;
+}
+
+/// Posts straight to device endpoints. UnifiedPush endpoints receive the JSON
+/// as-is; Web Push endpoints receive it encrypted (RFC 8291) and signed with
+/// the server's VAPID key (RFC 8292). Endpoints come from clients, so like
+/// webhooks they must be public unless `allow_private` is set.
+pub struct DirectGateway {
+    client: OutboundClient,
+    vapid: Option<Vapid>,
+}
+
+impl DirectGateway {
+    pub fn new(vapid: Option<Vapid>, allow_private: bool) -> Self {
+        DirectGateway {
+            client: OutboundClient::new(PUSH_TIMEOUT, allow_private),
+            vapid,
+        }
+    }
+}
+
+impl PushGateway for DirectGateway {
+    async fn deliver(&self, device: &PushDevice, notification: &Value) -> Result<(), PushError> {
+        let body = notification.to_string().into_bytes();
+        let request = if device.kind == KIND_WEB_PUSH {
+            let vapid = self
+                .vapid
+                .as_ref()
+                .ok_or_else(|| PushError::Failed("VAPID_PRIVATE_KEY is not set".to_string()))?;
+            let (Some(p256dh), Some(auth)) = (&device.p256dh, &device.auth) else {
+                return Err(PushError::Gone);
+            };
+            let endpoint = reqwest::Url::parse(&device.endpoint)
+                .map_err(|e| PushError::Failed(e.to_string()))?;
+            let encrypted = encrypt_web_push(&body, p256dh, auth).map_err(PushError::Failed)?;
+            self.client
+                .post(&device.endpoint)
+                .map_err(PushError::Failed)?
+                .header("Authorization", vapid.authorization(&endpoint))
+                .header("Content-Encoding", "aes128gcm")
+                .header("Content-Type", "application/octet-stream")
+                .header("TTL", PUSH_TTL_SECS.to_string())
+                .header("Urgency", "high")
+                .body(encrypted)
+        } else {
+            self.client
+                .post(&device.endpoint)
+                .map_err(PushError::Failed)?
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        check_response(request.send().await?)
+    }
+}
+
+/// Hands every notification, with the device it is for, to a gateway at
+/// PUSH_GATEWAY_URL that takes care of reaching the device. The gateway
+/// answers `410 Gone` for devices that should be forgotten.
+pub struct RelayGateway {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl RelayGateway {
+    pub fn new(url: String) -> Self {
+        RelayGateway {
+            client: http_client(),
+            url,
+        }
+    }
+}
+
+impl PushGateway for RelayGateway {
+    async fn deliver(&self, device: &PushDevice, notification: &Value) -> Result<(), PushError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "device": {
+                    "kind": device.kind,
+                    "endpoint": device.endpoint,
+                    "p256dh": device.p256dh,
+                    "auth": device.auth,
+                },
+                "notification": notification,
+            }))
+            .send()
+            .await?;
+        check_response(response)
+    }
+}
+
+fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(PUSH_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+fn check_response(response: reqwest::Response) -> Result<(), PushError> {
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => Err(PushError::Gone),
+        status => Err(PushError::Failed(format!("endpoint returned {}", status))),
+    }
+}
+
+/// The server's VAPID identity, used to sign Web Push requests.
+pub struct Vapid {
+    key: p256::ecdsa::SigningKey,
+    subject: String,
+}
+
+impl Vapid {
+    /// Reads VAPID_PRIVATE_KEY; `None` when it isn't set.
+    pub fn from_config(config: &Config) -> Option<Result<Self, String>> {
+        let encoded = config.vapid_private_key.as_ref()?;
+        Some(
+            decode_base64(encoded)
+                .and_then(|bytes| {
+                    p256::ecdsa::SigningKey::from_slice(&bytes).map_err(|e| e.to_string())
+                })
+                .map(|key| Vapid {
+                    key,
+                    subject: config.vapid_subject.clone(),
+                })
+                .map_err(|e| format!("Invalid VAPID_PRIVATE_KEY: {}", e)),
+        )
+    }
+
+    /// Uncompressed public key, URL-safe base64; clients pass it as the
+    /// `applicationServerKey` when subscribing.
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.key.verifying_key().to_encoded_point(false).as_bytes())
+    }
+
+    fn authorization(&self, endpoint: &reqwest::Url) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "aud": endpoint.origin().ascii_serialization(),
+                "exp": Utc::now().timestamp() + VAPID_TOKEN_SECS,
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let signing_input = format!("{}.{}", header, claims);
+        let signature: p256::ecdsa::Signature = self.key.sign(signing_input.as_bytes());
+        format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            self.public_key()
+        )
+    }
+}
+
+// Subscription keys come from browsers with or without padding
+fn decode_base64(value: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|e| e.to_string())
+}
+
+/// Checks a device registration before it is stored.
+pub fn validate_device(
+    kind: &str,
+    endpoint: &str,
+    p256dh: Option<&str>,
+    auth: Option<&str>,
+    allow_private: bool,
+) -> Result<(), String> {
+    if !DEVICE_KINDS.contains(&kind) {
+        return Err(format!("unknown device kind: {}", kind));
+    }
+    crate::outbound::validate_url(endpoint, allow_private)
+        .map_err(|e| format!("Invalid endpoint: {}", e))?;
+    if kind == KIND_WEB_PUSH {
+        let p256dh = p256dh
+            .and_then(|key| decode_base64(key).ok())
+            .ok_or("Web Push devices need a base64 p256dh key")?;
+        p256::PublicKey::from_sec1_bytes(&p256dh).map_err(|_| "Invalid p256dh key".to_string())?;
+        let auth = auth
+            .and_then(|secret| decode_base64(secret).ok())
+            .ok_or("Web Push devices need a base64 auth secret")?;
+        if auth.len() != 16 {
+            return Err("Invalid auth secret".to_string());
+        }
+    }
+    Ok(())
+}
+
+// RFC 8291 message encryption with the aes128gcm content coding (RFC 8188),
+// as a single record
+fn encrypt_web_push(payload: &[u8], p256dh: &str, auth: &str) -> Result<Vec<u8>, String> {
+    use rand::RngCore;
+
+    let as_secret = p256::SecretKey::random(&mut rand::rngs::OsRng);
+    let mut salt = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    encrypt_web_push_with(payload, p256dh, auth, &as_secret, salt)
+}
+
+// The encryption itself, given the server's one-off key pair and the salt
+fn encrypt_web_push_with(
+    payload: &[u8],
+    p256dh: &str,
+    auth: &str,
+    as_secret: &p256::SecretKey,
+    salt: [u8; 16],
+) -> Result<Vec<u8>, String> {
+    use aes_gcm::aead::{Aead, KeyInit};
+
+    let ua_public_bytes = decode_base64(p256dh)?;
+    let ua_public = p256::PublicKey::from_sec1_bytes(&ua_public_bytes).map_err(|e| e.to_string())?;
+    let auth_secret = decode_base64(auth)?;
+
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let shared = p256::ecdh::diffie_hellman(as_secret.to_nonzero_scalar(), ua_public.as_affine());
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public_bytes);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    hkdf::Hkdf::<Sha256>::new(Some(&auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|e| e.to_string())?;
+
+    let prk = hkdf::Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .and_then(|_| prk.expand(b"Content-Encoding: nonce\0", &mut nonce))
+        .map_err(|e| e.to_string())?;
+
+    // A trailing 0x02 marks the last (and only) record
+    let mut plaintext = payload.to_vec();
+    plaintext.push(2);
+    let ciphertext = aes_gcm::Aes128Gcm::new(&cek.into())
+        .encrypt(&nonce.into(), plaintext.as_slice())
+        .map_err(|e| e.to_string())?;
+
+    let record_size = (ciphertext.len() as u32).max(4096);
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&record_size.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// Start the task that turns new messages into push notifications. On
+/// shutdown, batches still waiting out their window are sent right away.
+pub fn spawn<G: PushGateway>(
+    pool: DbPool,
+    config: Arc<Config>,
+    events: EventHub,
+    gateway: G,
+    shutdown: Shutdown,
+) {
+    // Notifications go out from the node a message was sent through, but
+    // reads count wherever they happen
+    let mut receiver = events.subscribe_local();
+    let mut reads = events.subscribe();
+    let gateway = Arc::new(gateway);
+    let window = Duration::from_secs(config.push_coalesce_secs);
+
+    shutdown.clone().spawn(async move {
+        // Unannounced (message id, sender id) pairs per recipient, and when
+        // each recipient's batch is due
+        let mut pending: HashMap<i64, (Instant, Vec<(i64, i64)>)> = HashMap::new();
+
+        loop {
+            let next_due = pending.values().map(|(due, _)| *due).min();
+            let sleep = async {
+                match next_due {
+                    Some(due) => tokio::time::sleep_until(due).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                received = events::next_event(&mut receiver, &shutdown) => match received {
+                    Ok(Event::MessageCreated {
+                        message_id,
+                        from_user_id,
+                        to_user_id,
+                    }) => {
+                        pending
+                            .entry(to_user_id)
+                            .or_insert_with(|| (Instant::now() + window, Vec::new()))
+                            .1
+                            .push((message_id, from_user_id));
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Push dispatcher skipped {} events", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                // Reading a conversation elsewhere, through any node, makes
+                // its notification moot
+                read = reads.recv() => {
+                    if let Ok(Event::MessagesRead { reader_id, sender_id, .. }) = read {
+                        if let Some((_, messages)) = pending.get_mut(&reader_id) {
+                            messages.retain(|(_, from)| *from != sender_id);
+                            if messages.is_empty() {
+                                pending.remove(&reader_id);
+                            }
+                        }
+                    }
+                }
+                _ = sleep => {
+                    let now = Instant::now();
+                    let due: Vec<i64> = pending
+                        .iter()
+                        .filter(|(_, (due, _))| *due <= now)
+                        .map(|(user_id, _)| *user_id)
+                        .collect();
+                    for user_id in due {
+                        if let Some((_, messages)) = pending.remove(&user_id) {
+                            let message_ids = messages.into_iter().map(|(id, _)| id).collect();
+                            shutdown.spawn(notify(pool.clone(), gateway.clone(), user_id, message_ids));
+                        }
+                    }
+                }
+            }
+        }
+
+        for (user_id, (_, messages)) in pending {
+            let message_ids = messages.into_iter().map(|(id, _)| id).collect();
+            shutdown.spawn(notify(pool.clone(), gateway.clone(), user_id, message_ids));
+        }
+    });
+}
+
+async fn notify<G: PushGateway>(pool: DbPool, gateway: Arc<G>, user_id: i64, message_ids: Vec<i64>) {
+    let result = async {
+        let devices = devices(&pool, user_id).await?;
+        if devices.is_empty() {
+            return Ok(());
+        }
+        let Some(notification) = build_notification(&pool, user_id, &message_ids).await? else {
+            return Ok(());
+        };
+
+        for device in devices {
+            match gateway.deliver(&device, &notification).await {
+                Ok(()) => {}
+                Err(PushError::Gone) => {
+                    db::query("DELETE FROM push_devices WHERE id = ?")
+                        .bind(device.id)
+                        .execute(pool.as_ref())
+                        .await?;
+                }
+                Err(e) => tracing::warn!("Push to device {} failed: {}", device.id, e),
+            }
+        }
+        Ok::<_, sqlx::Error>(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::error!("Failed to send push notifications to user {}: {}", user_id, e);
+    }
+}
+
+async fn devices(pool: &DbPool, user_id: i64) -> Result<Vec<PushDevice>, sqlx::Error> {
+    let rows =
+        db::query("SELECT id, kind, endpoint, p256dh, auth FROM push_devices WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(pool.as_ref())
+            .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| PushDevice {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            endpoint: row.get("endpoint"),
+            p256dh: row.get("p256dh"),
+            auth: row.get("auth"),
+        })
+        .collect())
+}
+
+// Summarizes the messages that are still unread and not from a muted
+// conversation. Message text is only included for unencrypted messages;
+// end-to-end encrypted ones are announced without their content.
+async fn build_notification(
+    pool: &DbPool,
+    user_id: i64,
+    message_ids: &[i64],
+) -> Result<Option<Value>, sqlx::Error> {
+    let placeholders = vec!["?"; message_ids.len()].join(", ");
+    let sql = format!(
+        r#"
+        SELECT
+            m.id,
+            m.content,
+            m.encrypted,
+            m.via_integration,
+            m.created_at,
+            from_user.username as from_username,
+            from_user.display_name as from_display_name
+        FROM messages m
+        JOIN users from_user ON m.from_user_id = from_user.id
+        WHERE m.id IN ({})
+          AND m.to_user_id = ?
+          AND m.read_at IS NULL
+          AND NOT EXISTS (
+              SELECT 1 FROM conversation_mutes cm
+              WHERE cm.user_id = m.to_user_id
+                AND cm.partner_id = m.from_user_id
+                AND (cm.muted_until IS NULL OR cm.muted_until > ?)
+          )
+        ORDER BY m.id
+        "#,
+        placeholders
+    );
+
+    let mut query = db::query(&sql);
+    for id in message_ids {
+        query = query.bind(id);
+    }
+    let rows = query
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(pool.as_ref())
+        .await?;
+
+    let Some(latest) = rows.last() else {
+        return Ok(None);
+    };
+
+    let mut senders: Vec<String> = Vec::new();
+    for row in &rows {
+        let username: String = row.get("from_username");
+        if !senders.contains(&username) && senders.len() < MAX_SENDERS {
+            senders.push(username);
+        }
+    }
+
+    let preview = (!latest.get::<bool, _>("encrypted")).then(|| {
+        latest
+            .get::<String, _>("content")
+            .chars()
+            .take(MAX_PREVIEW_CHARS)
+            .collect::<String>()
+    });
+
+    Ok(Some(json!({
+        "type": "messages",
+        "count": rows.len(),
+        "senders": senders,
+        "latest": {
+            "id": latest.get::<i64, _>("id"),
+            "from_username": latest.get::<String, _>("from_username"),
+            "from_display_name": latest.get::<Option<String>, _>("from_display_name"),
+            "via_integration": latest.get::<Option<String>, _>("via_integration"),
+            "created_at": latest.get::<DateTime<Utc>, _>("created_at"),
+            "preview": preview,
+        },
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use std::future::IntoFuture;
+    use tokio::sync::mpsc;
+
+    // RFC 8291 Appendix A
+    #[test]
+    fn web_push_encryption_matches_rfc_8291_example() {
+        let as_private = decode_base64("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw").unwrap();
+        let as_secret = p256::SecretKey::from_slice(&as_private).unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(as_secret.public_key().to_encoded_point(false).as_bytes()),
+            "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"
+        );
+        let salt: [u8; 16] = decode_base64("DGv6ra1nlYgDCS1FRnbzlw")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let body = encrypt_web_push_with(
+            b"When I grow up, I want to be a watermelon",
+            "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+            "BTBZMqHH6r4Tts7J_aSIgg",
+            &as_secret,
+            salt,
+        )
+        .unwrap();
+
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+        );
+    }
+
+    #[test]
+    fn device_endpoints_must_be_public() {
+        let check = |endpoint, allow_private| {
+            validate_device(KIND_UNIFIED_PUSH, endpoint, None, None, allow_private)
+        };
+        assert!(check("https://push.example.com/up", false).is_ok());
+        assert!(check("http://10.0.0.1/up", false).is_err());
+        assert!(check("http://[fdaa::2]/up", false).is_err());
+        assert!(check("http://10.0.0.1/up", true).is_ok());
+    }
+
+    async fn insert_user(pool: &DbPool, username: &str) -> i64 {
+        db::query(
+            "INSERT INTO users (username, password_hash, created_at) VALUES (?, 'x', ?) RETURNING id",
+        )
+        .bind(username)
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id")
+    }
+
+    async fn send(
+        pool: &DbPool,
+        events: &EventHub,
+        from: i64,
+        to: i64,
+        content: &str,
+        encrypted: bool,
+    ) -> i64 {
+        let message_id = db::query(
+            "INSERT INTO messages (from_user_id, to_user_id, content, encrypted, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(content)
+        .bind(encrypted)
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id");
+        events.publish(Event::MessageCreated {
+            message_id,
+            from_user_id: from,
+            to_user_id: to,
+        });
+        message_id
+    }
+
+    #[tokio::test]
+    async fn bursts_become_one_notification_without_muted_or_encrypted_text() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/push",
+            axum::routing::post(move |body: String| async move {
+                sender.send(body).unwrap();
+                StatusCode::CREATED
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/push", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, app).into_future());
+
+        let pool = db::test_pool().await;
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let carol = insert_user(&pool, "carol").await;
+        db::query(
+            "INSERT INTO push_devices (user_id, kind, endpoint, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(bob)
+        .bind(KIND_UNIFIED_PUSH)
+        .bind(&endpoint)
+        .bind(Utc::now())
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+        db::query(
+            "INSERT INTO conversation_mutes (user_id, partner_id, created_at) VALUES (?, ?, ?)",
+        )
+        .bind(bob)
+        .bind(carol)
+        .bind(Utc::now())
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+
+        let config = Arc::new(Config {
+            push_coalesce_secs: 1,
+            ..Config::from_env()
+        });
+        let events = EventHub::new();
+        let shutdown = Shutdown::new();
+        spawn(
+            pool.clone(),
+            config,
+            events.clone(),
+            DirectGateway::new(None, true),
+            shutdown.clone(),
+        );
+
+        send(&pool, &events, alice, bob, "hello bob", false).await;
+        send(&pool, &events, carol, bob, "muted", false).await;
+        let latest = send(&pool, &events, alice, bob, "ciphertext", true).await;
+
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let notification: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(notification["count"], 2);
+        assert_eq!(notification["senders"], json!(["alice"]));
+        assert_eq!(notification["latest"]["id"], latest);
+        assert_eq!(notification["latest"]["preview"], Value::Null);
+        assert!(!body.contains("hello bob") && !body.contains("ciphertext"));
+
+        // The whole burst went out as that one notification
+        let more = tokio::time::timeout(Duration::from_millis(1500), received.recv()).await;
+        assert!(more.is_err());
+
+        shutdown
+            .stop_background(Instant::now() + Duration::from_secs(5))
+            .await;
+    }
+}
@@ -0,0 +1,221 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+
+// Buckets that have refilled completely carry no state worth keeping, so they
+// are dropped once the map grows past this size.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A per-route limit of `requests` per `period`, configured as
+/// `requests/seconds` (e.g. `30/60`).
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(requests: u32, period_secs: u64) -> Self {
+        RateLimit {
+            requests,
+            period: Duration::from_secs(period_secs),
+        }
+    }
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit (expected requests/seconds): {}", s);
+        let (requests, secs) = s.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let secs: u64 = secs.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || secs == 0 {
+            return Err(invalid());
+        }
+        Ok(RateLimit::new(requests, secs))
+    }
+}
+
+/// In-memory token bucket limiter: each key may burst up to `capacity`
+/// requests, and tokens refill continuously over `period`. Buckets aren't
+/// shared, so every machine in front of one database keeps its own.
+pub struct RateLimiter<K> {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        RateLimiter {
+            capacity: capacity as f64,
+            refill_per_sec: capacity as f64 / period.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_limit(limit: RateLimit) -> Self {
+        Self::new(limit.requests, limit.period)
+    }
+
+    /// Take a token for `key`, or return how long until one is available.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            let (capacity, rate) = (self.capacity, self.refill_per_sec);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.refill_per_sec;
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+// Middleware limiting authenticated requests per user; must run after auth_middleware
+pub async fn limit_by_user(
+    State(limiter): State<Arc<RateLimiter<i64>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(&user_id) = request.extensions().get::<i64>() else {
+        return AppError::unauthorized("unauthorized", "Authentication required").into_response();
+    };
+
+    match limiter.check(user_id) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => AppError::rate_limited(retry_after).into_response(),
+    }
+}
+
+/// Limiter keyed by client address, for routes that don't require a session.
+pub struct IpRateLimiter {
+    // Keyed by `address_key`; `None` is the bucket shared by requests whose
+    // address is unknown
+    limiter: RateLimiter<Option<IpAddr>>,
+    trust_proxy_headers: bool,
+}
+
+impl IpRateLimiter {
+    pub fn new(limit: RateLimit, trust_proxy_headers: bool) -> Self {
+        IpRateLimiter {
+            limiter: RateLimiter::from_limit(limit),
+            trust_proxy_headers,
+        }
+    }
+}
+
+/// The client's address: the peer address, or behind a trusted reverse proxy
+/// the address it reports. Proxy headers are only honoured when configured,
+/// since clients can set them to anything.
+pub fn client_ip(request: &Request, trust_proxy_headers: bool) -> Option<IpAddr> {
+    if trust_proxy_headers {
+        let header_ip = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                // The last X-Forwarded-For entry is the one our proxy appended
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok())
+        };
+        if let Some(ip) = header_ip("fly-client-ip").or_else(|| header_ip("x-forwarded-for")) {
+            return Some(ip);
+        }
+    }
+
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+// IPv6 clients are usually given a whole /64, so they're limited per /64
+// rather than per address. IPv4-mapped addresses count as the IPv4 address.
+fn address_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// Middleware limiting requests per client address
+pub async fn limit_by_ip(
+    State(limiter): State<Arc<IpRateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = client_ip(&request, limiter.trust_proxy_headers).map(address_key);
+
+    match limiter.limiter.check(key) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => AppError::rate_limited(retry_after).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_clients_share_a_bucket_per_64() {
+        let key = |ip: &str| address_key(ip.parse().unwrap());
+
+        assert_eq!(key("203.0.113.7"), key("::ffff:203.0.113.7"));
+        assert_ne!(key("203.0.113.7"), key("203.0.113.8"));
+        assert_eq!(
+            key("2001:db8:1:2::1"),
+            key("2001:db8:1:2:ffff:ffff:ffff:ffff")
+        );
+        assert_eq!(
+            key("2001:db8:1:2::1"),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+    }
+
+    #[test]
+    fn requests_without_an_address_share_one_bucket() {
+        let limiter = RateLimiter::<Option<IpAddr>>::new(2, Duration::from_secs(60));
+        assert!(limiter.check(None).is_ok());
+        assert!(limiter.check(None).is_ok());
+        assert!(limiter.check(None).is_err());
+        assert!(limiter.check(Some("203.0.113.7".parse().unwrap())).is_ok());
+    }
+}
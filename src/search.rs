@@ -0,0 +1,130 @@
+// Ranking for the user directory search. Lower ranks sort first.
+const RANK_EXACT: u8 = 0;
+const RANK_USERNAME_PREFIX: u8 = 1;
+const RANK_DISPLAY_NAME_PREFIX: u8 = 2;
+const RANK_FUZZY: u8 = 3;
+
+// Typo tolerance kicks in only once the query is long enough to be meaningful
+const MIN_FUZZY_QUERY_CHARS: usize = 3;
+
+/// LIKE patterns (with `ESCAPE '\'`) for the lowercased username or display
+/// name, at least one of which every match satisfies. Lets the database narrow
+/// the candidates before `rank` without losing any.
+///
+/// Short queries only match prefixes. Longer ones are split into one piece
+/// more than the edits `rank` tolerates; the edits can't touch all of the
+/// pieces, so a match contains at least one of them unchanged.
+pub fn candidate_patterns(query: &str) -> Vec<String> {
+    let query: Vec<char> = query.to_lowercase().chars().collect();
+    if query.len() < MIN_FUZZY_QUERY_CHARS {
+        let prefix = like_escape(&query);
+        return vec![format!("{prefix}%"), format!("% {prefix}%")];
+    }
+
+    let pieces = max_distance(query.len()) + 1;
+    (0..pieces)
+        .map(|i| {
+            let piece = &query[i * query.len() / pieces..(i + 1) * query.len() / pieces];
+            format!("%{}%", like_escape(piece))
+        })
+        .collect()
+}
+
+fn like_escape(chars: &[char]) -> String {
+    let mut escaped = String::with_capacity(chars.len());
+    for &c in chars {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Edits tolerated between a query and a misspelled match
+fn max_distance(query_len: usize) -> usize {
+    if query_len >= 6 {
+        2
+    } else {
+        1
+    }
+}
+
+pub struct Candidate {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub has_avatar: bool,
+}
+
+/// Score candidates against `query`, dropping non-matches and sorting the rest
+/// by rank, then edit distance, then username ignoring case.
+pub fn rank(query: &str, candidates: Vec<Candidate>) -> Vec<Candidate> {
+    let query = query.to_lowercase();
+    let mut scored: Vec<((u8, usize, String), Candidate)> = candidates
+        .into_iter()
+        .filter_map(|c| {
+            score(&query, &c)
+                .map(|(rank, distance)| ((rank, distance, c.username.to_lowercase()), c))
+        })
+        .collect();
+
+    scored.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.username.cmp(&b.1.username)));
+    scored.into_iter().map(|(_, c)| c).collect()
+}
+
+fn score(query: &str, candidate: &Candidate) -> Option<(u8, usize)> {
+    let username = candidate.username.to_lowercase();
+    let display_words: Vec<String> = candidate
+        .display_name
+        .as_deref()
+        .unwrap_or("")
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+
+    if username == query {
+        return Some((RANK_EXACT, 0));
+    }
+    if username.starts_with(query) {
+        return Some((RANK_USERNAME_PREFIX, 0));
+    }
+    if display_words.iter().any(|w| w.starts_with(query)) {
+        return Some((RANK_DISPLAY_NAME_PREFIX, 0));
+    }
+
+    let query_len = query.chars().count();
+    if query_len < MIN_FUZZY_QUERY_CHARS {
+        return None;
+    }
+    std::iter::once(&username)
+        .chain(display_words.iter())
+        .map(|word| prefix_distance(query, word))
+        .min()
+        .filter(|&d| d <= max_distance(query_len))
+        .map(|d| (RANK_FUZZY, d))
+}
+
+// Edit distance between `query` and the closest-length prefix of `word`, so a
+// typo in the first few characters still finds "alexandra" from "alx".
+fn prefix_distance(query: &str, word: &str) -> usize {
+    let query_len = query.chars().count();
+    let word: Vec<char> = word.chars().collect();
+    (query_len.saturating_sub(1)..=query_len + 1)
+        .filter(|&len| len <= word.len())
+        .map(|len| levenshtein(query, &word[..len]))
+        .min()
+        .unwrap_or_else(|| levenshtein(query, &word))
+}
+
+fn levenshtein(a: &str, b: &[char]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
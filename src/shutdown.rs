@@ -0,0 +1,80 @@
+// Graceful shutdown. On SIGTERM or SIGINT the server stops accepting
+// connections and long polls return early; once HTTP has drained, background
+// tasks handle the events already published and exit, and the database is
+// closed. Anything still running at the deadline is dropped.
+use std::future::Future;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Shutdown state shared by the server, handlers and background tasks.
+/// Cloning shares the same state.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: CancellationToken,
+    stopping: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves once shutdown has begun and connections are draining.
+    pub async fn requested(&self) {
+        self.requested.cancelled().await
+    }
+
+    /// Resolves once background tasks should finish up and exit.
+    pub async fn stopping(&self) {
+        self.stopping.cancelled().await
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.is_cancelled()
+    }
+
+    /// Run `task` in the background; shutdown waits for it until the deadline.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    /// Tell background tasks to stop and wait for them. Returns false if some
+    /// were still running at `deadline`.
+    pub async fn stop_background(&self, deadline: Instant) -> bool {
+        self.stopping.cancel();
+        self.tasks.close();
+        tokio::time::timeout_at(deadline, self.tasks.wait()).await.is_ok()
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, then start shutting down.
+pub async fn signal(shutdown: Shutdown) {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+
+    tracing::info!("Shutting down, draining connections");
+    shutdown.requested.cancel();
+}
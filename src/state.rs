@@ -0,0 +1,65 @@
+use crate::config::Config;
+use crate::email::Mailer;
+use crate::db::DbPool;
+use crate::events::EventHub;
+use crate::shutdown::Shutdown;
+use crate::store::Store;
+use crate::webhooks::DeliveryQueue;
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+// Shared router state. Handlers extract only the parts they need,
+// e.g. `State<DbPool>`, `State<Arc<dyn Store>>`, `State<Arc<Config>>` or
+// `State<EventHub>`.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: DbPool,
+    pub store: Arc<dyn Store>,
+    pub config: Arc<Config>,
+    pub events: EventHub,
+    pub webhook_queue: DeliveryQueue,
+    pub mailer: Mailer,
+    pub shutdown: Shutdown,
+}
+
+impl FromRef<AppState> for DbPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Store> {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for EventHub {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl FromRef<AppState> for DeliveryQueue {
+    fn from_ref(state: &AppState) -> Self {
+        state.webhook_queue.clone()
+    }
+}
+
+impl FromRef<AppState> for Mailer {
+    fn from_ref(state: &AppState) -> Self {
+        state.mailer.clone()
+    }
+}
+
+impl FromRef<AppState> for Shutdown {
+    fn from_ref(state: &AppState) -> Self {
+        state.shutdown.clone()
+    }
+}
@@ -0,0 +1,196 @@
+// Storage for the core of the server: accounts (including their login,
+// two-factor check and erasure), sessions, messages and end-to-end keys. The
+// handlers for these take only `Store`, never the pool, so they run against
+// `MemoryStore` in tests as well.
+//
+// Everything else queries the pool directly, using `Store` at most to resolve
+// usernames and deliver messages, and is tested against the database:
+// profiles, avatars, mutes, bots, webhooks, incoming webhooks, push,
+// notifications, exports, search, two-factor setup and the second login step,
+// and password resets. The latter change an account in the same transaction
+// as their own tables, using the helpers in `users`.
+use crate::auth::CurrentSession;
+use crate::config::Config;
+use crate::models::{ConversationResponse, KeyBundle, MessageResponse, SendMessageResponse};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+#[cfg(test)]
+mod memory;
+mod sql;
+
+#[cfg(test)]
+pub use memory::MemoryStore;
+pub use sql::SqlStore;
+#[cfg(test)]
+pub(crate) use sql::PREKEY_INSERT_BATCH;
+
+pub type Result<T> = std::result::Result<T, sqlx::Error>;
+
+/// What a password is checked against.
+pub struct Credentials {
+    pub user_id: i64,
+    pub username: String,
+    pub password_hash: String,
+    /// Set while failed logins keep the account locked
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// The secret of an account with two-factor authentication turned on.
+pub struct Totp {
+    pub secret: String,
+    /// Step of the last code accepted; it and earlier ones can't be used again
+    pub last_used_step: Option<u64>,
+}
+
+/// How a rename turned out.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rename {
+    /// Renamed at the given time
+    Renamed(DateTime<Utc>),
+    /// Another account has the name
+    Taken,
+    /// Another account gave the name up recently
+    Held,
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// The account that currently owns `username`, falling back to accounts
+    /// that gave it up within the redirect window so old links and contacts'
+    /// typed names keep working after a rename.
+    async fn resolve_user_id(&self, config: &Config, username: &str) -> Result<Option<i64>>;
+
+    /// Whether any account, deleted or not, has `username` (ignoring case).
+    async fn username_exists(&self, username: &str) -> Result<bool>;
+
+    /// Whether `username` was recently released by an account other than
+    /// `claimant` and is still being held to prevent squatting.
+    async fn is_username_held(
+        &self,
+        config: &Config,
+        username: &str,
+        claimant: Option<i64>,
+    ) -> Result<bool>;
+
+    /// Create an account together with its first session, returning the user
+    /// id and session token. `None` if the username was taken in the meantime.
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<(i64, String)>>;
+
+    /// The credentials of the person (not bot) who currently has `username`,
+    /// for logging in.
+    async fn find_login(&self, username: &str) -> Result<Option<Credentials>>;
+
+    async fn credentials(&self, user_id: i64) -> Result<Credentials>;
+
+    /// Count a failed login, locking the account past the configured
+    /// threshold.
+    async fn record_failed_login(&self, config: &Config, user_id: i64) -> Result<()>;
+
+    /// The account's two-factor secret, if two-factor authentication is on.
+    async fn totp(&self, user_id: i64) -> Result<Option<Totp>>;
+
+    /// Record that a code from `step` was accepted. False if one from that
+    /// step or a later one already was, so when two requests race with the
+    /// same code exactly one of them succeeds.
+    async fn claim_totp_step(&self, user_id: i64, step: u64) -> Result<bool>;
+
+    /// Give the account a new username. The old one keeps redirecting and is
+    /// held, and everyone the user has talked to is notified, unless only its
+    /// case changed.
+    async fn rename_user(&self, config: &Config, user_id: i64, username: &str) -> Result<Rename>;
+
+    /// Replace the password hash, end every session except `keep_session` and
+    /// revoke the account's personal access tokens and pending two-factor
+    /// logins. Returns how many sessions were ended.
+    async fn set_password(
+        &self,
+        user_id: i64,
+        password_hash: &str,
+        keep_session: i64,
+    ) -> Result<u64>;
+
+    /// Erase the account and the bots it owns, including their export
+    /// archives, leaving anonymized tombstones whose usernames are held but no
+    /// longer redirect.
+    async fn delete_user(&self, config: &Config, user_id: i64) -> Result<()>;
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Start a new session for `user_id`, returning its bearer token.
+    async fn create_session(&self, user_id: i64) -> Result<String>;
+
+    /// Start a session after a successful login, clearing the account's
+    /// failed logins and any lock along with it.
+    async fn sign_in(&self, user_id: i64) -> Result<String>;
+
+    /// Start a two-factor login, returning a challenge token that can be
+    /// exchanged for a session together with a code until `expires_at`.
+    async fn create_login_challenge(
+        &self,
+        user_id: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<String>;
+
+    async fn find_session(&self, token: &str) -> Result<Option<CurrentSession>>;
+}
+
+/// A message about to be stored.
+pub struct NewMessage<'a> {
+    pub from_user_id: i64,
+    pub to_user_id: i64,
+    pub content: &'a str,
+    pub encrypted: bool,
+    /// Name of the incoming webhook that posted the message, if any
+    pub via_integration: Option<&'a str>,
+}
+
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    async fn insert_message(&self, message: NewMessage<'_>) -> Result<SendMessageResponse>;
+
+    /// Everything `user_id` sent or received, newest first.
+    async fn messages_for(&self, user_id: i64) -> Result<Vec<MessageResponse>>;
+
+    /// The conversation between `user_id` and `other_id`, newest first.
+    async fn messages_between(&self, user_id: i64, other_id: i64) -> Result<Vec<MessageResponse>>;
+
+    /// Up to `limit` messages received by `user_id` with ids above `after`,
+    /// oldest first.
+    async fn messages_received_after(
+        &self,
+        user_id: i64,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<MessageResponse>>;
+
+    /// Mark unread messages from `sender_id` to `reader_id` as read,
+    /// returning how many there were.
+    async fn mark_read(&self, reader_id: i64, sender_id: i64) -> Result<u64>;
+
+    /// Everyone `user_id` has exchanged messages with, with the latest
+    /// message and unread count, most recently active first.
+    async fn conversations(&self, user_id: i64) -> Result<Vec<ConversationResponse>>;
+}
+
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    /// Replace the user's key bundle and one-time prekeys as a whole, or not
+    /// at all; a failure halfway would leave the user with keys nobody can
+    /// start a session against.
+    async fn replace_key_bundle(&self, user_id: i64, bundle: &KeyBundle) -> Result<()>;
+
+    /// The user's key bundle with up to `limit` unused one-time prekeys. The
+    /// first of them is marked used, as X3DH requires.
+    async fn claim_key_bundle(&self, user_id: i64, limit: usize) -> Result<Option<KeyBundle>>;
+}
+
+/// Every kind of storage the handlers need, as held in the router state.
+pub trait Store: UserStore + SessionStore + MessageStore + KeyStore {}
+
+impl<T: UserStore + SessionStore + MessageStore + KeyStore> Store for T {}
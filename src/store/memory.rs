@@ -0,0 +1,578 @@
+use super::{
+    Credentials, KeyStore, MessageStore, NewMessage, Rename, Result, SessionStore, Totp, UserStore,
+};
+use crate::auth::{generate_token, hash_token, CurrentSession};
+use crate::config::{Config, DeletedMessagePolicy};
+use crate::models::{ConversationResponse, KeyBundle, MessageResponse, SendMessageResponse};
+use crate::users;
+use crate::validation::normalize_username;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Keeps everything in memory, for tests that exercise handlers without a
+/// database. There are no bots, profiles, mutes or notifications here, so
+/// conversations are never muted and renames notify nobody. Two-factor
+/// authentication is turned on with `enable_totp`, and login challenges are
+/// recorded but can't be completed, since the second login step runs against
+/// the database. Cloning shares the same data.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    users: Vec<StoredUser>,
+    sessions: Vec<StoredSession>,
+    messages: Vec<StoredMessage>,
+    keys: HashMap<i64, StoredKeys>,
+    totp: HashMap<i64, StoredTotp>,
+    login_challenges: Vec<StoredChallenge>,
+    // Usernames accounts have given up, oldest first
+    renames: Vec<StoredRename>,
+    last_user_id: i64,
+    last_session_id: i64,
+    last_message_id: i64,
+}
+
+struct StoredUser {
+    id: i64,
+    username: String,
+    password_hash: String,
+    failed_logins: i64,
+    locked_until: Option<DateTime<Utc>>,
+    deleted: bool,
+}
+
+struct StoredRename {
+    user_id: i64,
+    old_username: String,
+    changed_at: DateTime<Utc>,
+}
+
+struct StoredSession {
+    id: i64,
+    user_id: i64,
+    token_hash: String,
+}
+
+struct StoredMessage {
+    id: i64,
+    from_user_id: i64,
+    to_user_id: i64,
+    content: String,
+    encrypted: bool,
+    via_integration: Option<String>,
+    created_at: DateTime<Utc>,
+    read: bool,
+}
+
+struct StoredTotp {
+    secret: String,
+    last_used_step: Option<u64>,
+}
+
+struct StoredChallenge {
+    user_id: i64,
+}
+
+struct StoredKeys {
+    identity_key: String,
+    signed_prekey: String,
+    signed_prekey_signature: String,
+    // Public key and whether it has been handed out, in upload order
+    one_time_prekeys: Vec<(String, bool)>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn on two-factor authentication for `user_id`, which only the
+    /// database-backed enrollment endpoints can do otherwise.
+    pub fn enable_totp(&self, user_id: i64, secret: &str) {
+        self.lock().totp.insert(
+            user_id,
+            StoredTotp {
+                secret: secret.to_string(),
+                last_used_step: None,
+            },
+        );
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("memory store poisoned")
+    }
+}
+
+impl Inner {
+    fn user(&self, user_id: i64) -> Option<&StoredUser> {
+        self.users.iter().find(|user| user.id == user_id)
+    }
+
+    fn user_mut(&mut self, user_id: i64) -> Result<&mut StoredUser> {
+        self.users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    fn is_held(&self, config: &Config, username: &str, claimant: Option<i64>) -> bool {
+        let cutoff = Utc::now() - Duration::days(config.username_hold_days);
+        self.renames.iter().any(|rename| {
+            rename.old_username.eq_ignore_ascii_case(username)
+                && rename.changed_at > cutoff
+                && Some(rename.user_id) != claimant
+        })
+    }
+
+    fn create_session(&mut self, user_id: i64) -> String {
+        let token = generate_token();
+        let id = next_id(&mut self.last_session_id);
+        self.sessions.push(StoredSession {
+            id,
+            user_id,
+            token_hash: hash_token(&token),
+        });
+        token
+    }
+
+    fn username(&self, user_id: i64) -> String {
+        self.users
+            .iter()
+            .find(|user| user.id == user_id)
+            .map(|user| user.username.clone())
+            .unwrap_or_default()
+    }
+
+    fn message_response(&self, message: &StoredMessage) -> MessageResponse {
+        MessageResponse {
+            id: message.id,
+            from_username: self.username(message.from_user_id),
+            from_display_name: None,
+            from_is_bot: false,
+            to_username: self.username(message.to_user_id),
+            to_display_name: None,
+            to_is_bot: false,
+            content: message.content.clone(),
+            encrypted: message.encrypted,
+            via_integration: message.via_integration.clone(),
+            created_at: message.created_at,
+        }
+    }
+
+    // Newest first, like the SQL queries
+    fn newest_first<'a>(
+        &self,
+        messages: impl Iterator<Item = &'a StoredMessage>,
+    ) -> Vec<MessageResponse> {
+        let mut messages: Vec<&StoredMessage> = messages.collect();
+        messages.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        messages
+            .into_iter()
+            .map(|message| self.message_response(message))
+            .collect()
+    }
+}
+
+// Ids start at 1 and are never reused, like SQLite's AUTOINCREMENT
+fn next_id(last: &mut i64) -> i64 {
+    *last += 1;
+    *last
+}
+
+fn credentials(user: &StoredUser) -> Credentials {
+    Credentials {
+        user_id: user.id,
+        username: user.username.clone(),
+        password_hash: user.password_hash.clone(),
+        locked_until: user.locked_until,
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn resolve_user_id(&self, config: &Config, username: &str) -> Result<Option<i64>> {
+        let username = normalize_username(username);
+        let inner = self.lock();
+        let current = inner
+            .users
+            .iter()
+            .find(|user| !user.deleted && user.username.eq_ignore_ascii_case(&username));
+        if let Some(user) = current {
+            return Ok(Some(user.id));
+        }
+
+        let cutoff = Utc::now() - Duration::days(config.username_redirect_days);
+        Ok(inner
+            .renames
+            .iter()
+            .rev()
+            .filter(|rename| {
+                rename.old_username.eq_ignore_ascii_case(&username) && rename.changed_at > cutoff
+            })
+            .find(|rename| inner.user(rename.user_id).is_some_and(|user| !user.deleted))
+            .map(|rename| rename.user_id))
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool> {
+        Ok(self
+            .lock()
+            .users
+            .iter()
+            .any(|user| user.username.eq_ignore_ascii_case(username)))
+    }
+
+    async fn is_username_held(
+        &self,
+        config: &Config,
+        username: &str,
+        claimant: Option<i64>,
+    ) -> Result<bool> {
+        Ok(self.lock().is_held(config, username, claimant))
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<(i64, String)>> {
+        let mut inner = self.lock();
+        if inner
+            .users
+            .iter()
+            .any(|user| user.username.eq_ignore_ascii_case(username))
+        {
+            return Ok(None);
+        }
+
+        let user_id = next_id(&mut inner.last_user_id);
+        inner.users.push(StoredUser {
+            id: user_id,
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+            failed_logins: 0,
+            locked_until: None,
+            deleted: false,
+        });
+        let token = inner.create_session(user_id);
+
+        Ok(Some((user_id, token)))
+    }
+
+    async fn find_login(&self, username: &str) -> Result<Option<Credentials>> {
+        let username = normalize_username(username);
+        Ok(self
+            .lock()
+            .users
+            .iter()
+            .find(|user| !user.deleted && user.username.eq_ignore_ascii_case(&username))
+            .map(credentials))
+    }
+
+    async fn credentials(&self, user_id: i64) -> Result<Credentials> {
+        self.lock()
+            .user(user_id)
+            .map(credentials)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn record_failed_login(&self, config: &Config, user_id: i64) -> Result<()> {
+        let mut inner = self.lock();
+        let user = inner.user_mut(user_id)?;
+        user.failed_logins += 1;
+        if let Some(locked_until) = users::lockout_until(config, user.failed_logins) {
+            user.locked_until = Some(locked_until);
+        }
+        Ok(())
+    }
+
+    async fn totp(&self, user_id: i64) -> Result<Option<Totp>> {
+        Ok(self.lock().totp.get(&user_id).map(|totp| Totp {
+            secret: totp.secret.clone(),
+            last_used_step: totp.last_used_step,
+        }))
+    }
+
+    async fn claim_totp_step(&self, user_id: i64, step: u64) -> Result<bool> {
+        let mut inner = self.lock();
+        let Some(totp) = inner.totp.get_mut(&user_id) else {
+            return Ok(false);
+        };
+        if totp.last_used_step.is_some_and(|last| last >= step) {
+            return Ok(false);
+        }
+        totp.last_used_step = Some(step);
+        Ok(true)
+    }
+
+    async fn rename_user(&self, config: &Config, user_id: i64, username: &str) -> Result<Rename> {
+        let mut inner = self.lock();
+        if inner
+            .users
+            .iter()
+            .any(|user| user.id != user_id && user.username.eq_ignore_ascii_case(username))
+        {
+            return Ok(Rename::Taken);
+        }
+        if inner.is_held(config, username, Some(user_id)) {
+            return Ok(Rename::Held);
+        }
+
+        let changed_at = Utc::now();
+        let user = inner.user_mut(user_id)?;
+        let old_username = std::mem::replace(&mut user.username, username.to_string());
+        // A change in case only keeps the same name, so there is nothing to
+        // hold or redirect
+        if !old_username.eq_ignore_ascii_case(username) {
+            inner.renames.push(StoredRename {
+                user_id,
+                old_username,
+                changed_at,
+            });
+        }
+        Ok(Rename::Renamed(changed_at))
+    }
+
+    async fn set_password(
+        &self,
+        user_id: i64,
+        password_hash: &str,
+        keep_session: i64,
+    ) -> Result<u64> {
+        let mut inner = self.lock();
+        inner.user_mut(user_id)?.password_hash = password_hash.to_string();
+        let before = inner.sessions.len();
+        inner
+            .sessions
+            .retain(|session| session.user_id != user_id || session.id == keep_session);
+        let revoked = (before - inner.sessions.len()) as u64;
+        inner
+            .login_challenges
+            .retain(|challenge| challenge.user_id != user_id);
+        Ok(revoked)
+    }
+
+    async fn delete_user(&self, config: &Config, user_id: i64) -> Result<()> {
+        let mut inner = self.lock();
+        let user = inner.user_mut(user_id)?;
+        let old_username = std::mem::replace(&mut user.username, users::deleted_username(user_id));
+        user.password_hash = String::new();
+        user.deleted = true;
+
+        inner.sessions.retain(|session| session.user_id != user_id);
+        inner
+            .login_challenges
+            .retain(|challenge| challenge.user_id != user_id);
+        inner.keys.remove(&user_id);
+        inner.totp.remove(&user_id);
+        if config.deleted_account_messages == DeletedMessagePolicy::Delete {
+            inner
+                .messages
+                .retain(|message| message.from_user_id != user_id);
+        }
+        // Held like a rename, but lookups skip deleted accounts
+        inner.renames.push(StoredRename {
+            user_id,
+            old_username,
+            changed_at: Utc::now(),
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn create_session(&self, user_id: i64) -> Result<String> {
+        Ok(self.lock().create_session(user_id))
+    }
+
+    async fn sign_in(&self, user_id: i64) -> Result<String> {
+        let mut inner = self.lock();
+        let user = inner.user_mut(user_id)?;
+        user.failed_logins = 0;
+        user.locked_until = None;
+        Ok(inner.create_session(user_id))
+    }
+
+    async fn create_login_challenge(
+        &self,
+        user_id: i64,
+        _expires_at: DateTime<Utc>,
+    ) -> Result<String> {
+        self.lock()
+            .login_challenges
+            .push(StoredChallenge { user_id });
+        Ok(generate_token())
+    }
+
+    async fn find_session(&self, token: &str) -> Result<Option<CurrentSession>> {
+        let token_hash = hash_token(token);
+        Ok(self
+            .lock()
+            .sessions
+            .iter()
+            .find(|session| session.token_hash == token_hash)
+            .map(|session| CurrentSession {
+                id: session.id,
+                user_id: session.user_id,
+            }))
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn insert_message(&self, message: NewMessage<'_>) -> Result<SendMessageResponse> {
+        let mut inner = self.lock();
+        let message_id = next_id(&mut inner.last_message_id);
+        let created_at = Utc::now();
+        inner.messages.push(StoredMessage {
+            id: message_id,
+            from_user_id: message.from_user_id,
+            to_user_id: message.to_user_id,
+            content: message.content.to_string(),
+            encrypted: message.encrypted,
+            via_integration: message.via_integration.map(str::to_string),
+            created_at,
+            read: false,
+        });
+        Ok(SendMessageResponse {
+            message_id,
+            created_at,
+        })
+    }
+
+    async fn messages_for(&self, user_id: i64) -> Result<Vec<MessageResponse>> {
+        let inner = self.lock();
+        Ok(inner.newest_first(
+            inner
+                .messages
+                .iter()
+                .filter(|m| m.from_user_id == user_id || m.to_user_id == user_id),
+        ))
+    }
+
+    async fn messages_between(&self, user_id: i64, other_id: i64) -> Result<Vec<MessageResponse>> {
+        let inner = self.lock();
+        Ok(inner.newest_first(inner.messages.iter().filter(|m| {
+            (m.from_user_id == user_id && m.to_user_id == other_id)
+                || (m.from_user_id == other_id && m.to_user_id == user_id)
+        })))
+    }
+
+    async fn messages_received_after(
+        &self,
+        user_id: i64,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<MessageResponse>> {
+        let inner = self.lock();
+        Ok(inner
+            .messages
+            .iter()
+            .filter(|m| m.to_user_id == user_id && m.id > after)
+            .take(limit.max(0) as usize)
+            .map(|m| inner.message_response(m))
+            .collect())
+    }
+
+    async fn mark_read(&self, reader_id: i64, sender_id: i64) -> Result<u64> {
+        let mut marked = 0;
+        for message in self.lock().messages.iter_mut() {
+            if message.from_user_id == sender_id && message.to_user_id == reader_id && !message.read
+            {
+                message.read = true;
+                marked += 1;
+            }
+        }
+        Ok(marked)
+    }
+
+    async fn conversations(&self, user_id: i64) -> Result<Vec<ConversationResponse>> {
+        let inner = self.lock();
+        // Latest message and unread count per partner
+        let mut latest: HashMap<i64, (&StoredMessage, i64)> = HashMap::new();
+        for message in &inner.messages {
+            let partner_id = if message.from_user_id == user_id {
+                message.to_user_id
+            } else if message.to_user_id == user_id {
+                message.from_user_id
+            } else {
+                continue;
+            };
+            let unread = i64::from(
+                message.to_user_id == user_id && message.from_user_id != user_id && !message.read,
+            );
+            let entry = latest.entry(partner_id).or_insert((message, 0));
+            if (message.created_at, message.id) > (entry.0.created_at, entry.0.id) {
+                entry.0 = message;
+            }
+            entry.1 += unread;
+        }
+
+        let mut latest: Vec<_> = latest.into_iter().collect();
+        latest.sort_by_key(|(_, (message, _))| std::cmp::Reverse(message.created_at));
+        let mut conversations = Vec::new();
+        for (partner_id, (message, unread_count)) in latest {
+            conversations.push(ConversationResponse {
+                username: inner.username(partner_id),
+                display_name: None,
+                is_bot: false,
+                last_message: message.content.clone(),
+                last_message_time: message.created_at,
+                unread_count,
+                muted: false,
+            });
+        }
+        Ok(conversations)
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemoryStore {
+    async fn replace_key_bundle(&self, user_id: i64, bundle: &KeyBundle) -> Result<()> {
+        self.lock().keys.insert(
+            user_id,
+            StoredKeys {
+                identity_key: bundle.identity_key.clone(),
+                signed_prekey: bundle.signed_prekey.clone(),
+                signed_prekey_signature: bundle.signed_prekey_signature.clone(),
+                one_time_prekeys: bundle
+                    .one_time_prekeys
+                    .iter()
+                    .map(|prekey| (prekey.clone(), false))
+                    .collect(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn claim_key_bundle(&self, user_id: i64, limit: usize) -> Result<Option<KeyBundle>> {
+        let mut inner = self.lock();
+        let Some(keys) = inner.keys.get_mut(&user_id) else {
+            return Ok(None);
+        };
+
+        let mut unused = keys
+            .one_time_prekeys
+            .iter_mut()
+            .filter(|(_, used)| !used)
+            .take(limit);
+        let mut one_time_prekeys = Vec::new();
+        if let Some((prekey, used)) = unused.next() {
+            *used = true;
+            one_time_prekeys.push(prekey.clone());
+        }
+        one_time_prekeys.extend(unused.map(|(prekey, _)| prekey.clone()));
+
+        Ok(Some(KeyBundle {
+            identity_key: keys.identity_key.clone(),
+            signed_prekey: keys.signed_prekey.clone(),
+            signed_prekey_signature: keys.signed_prekey_signature.clone(),
+            one_time_prekeys,
+        }))
+    }
+}
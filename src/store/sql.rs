@@ -0,0 +1,649 @@
+use super::{
+    Credentials, KeyStore, MessageStore, NewMessage, Rename, Result, SessionStore, Totp, UserStore,
+};
+use crate::auth::{self, CurrentSession};
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::models::{ConversationResponse, KeyBundle, MessageResponse, SendMessageResponse};
+use crate::users;
+use crate::validation::normalize_username;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+
+// Rows per INSERT when storing one-time prekeys, keeping each statement well
+// under either backend's limit on bound parameters
+pub(crate) const PREKEY_INSERT_BATCH: usize = 100;
+
+// Columns for `message_from_row`; callers add the WHERE clause
+const MESSAGE_SELECT: &str = r#"
+    SELECT
+        m.id,
+        m.content,
+        m.encrypted,
+        m.via_integration,
+        m.created_at,
+        from_user.username as from_username,
+        from_user.display_name as from_display_name,
+        from_user.is_bot as from_is_bot,
+        to_user.username as to_username,
+        to_user.display_name as to_display_name,
+        to_user.is_bot as to_is_bot
+    FROM messages m
+    JOIN users from_user ON m.from_user_id = from_user.id
+    JOIN users to_user ON m.to_user_id = to_user.id
+"#;
+
+/// The production store, backed by the server's database on either backend.
+#[derive(Clone)]
+pub struct SqlStore {
+    pool: DbPool,
+}
+
+impl SqlStore {
+    pub fn new(pool: DbPool) -> Self {
+        SqlStore { pool }
+    }
+}
+
+fn credentials_from_row(row: &db::Row) -> Credentials {
+    Credentials {
+        user_id: row.get("id"),
+        username: row.get("username"),
+        password_hash: row.get("password_hash"),
+        locked_until: row.get("locked_until"),
+    }
+}
+
+fn message_from_row(row: &db::Row) -> MessageResponse {
+    MessageResponse {
+        id: row.get("id"),
+        from_username: row.get("from_username"),
+        from_display_name: row.get("from_display_name"),
+        from_is_bot: row.get("from_is_bot"),
+        to_username: row.get("to_username"),
+        to_display_name: row.get("to_display_name"),
+        to_is_bot: row.get("to_is_bot"),
+        content: row.get("content"),
+        encrypted: row.get("encrypted"),
+        via_integration: row.get("via_integration"),
+        created_at: row.get("created_at"),
+    }
+}
+
+#[async_trait]
+impl UserStore for SqlStore {
+    async fn resolve_user_id(&self, config: &Config, username: &str) -> Result<Option<i64>> {
+        let username = normalize_username(username);
+
+        let current = db::query(
+            "SELECT id FROM users WHERE username = ? COLLATE NOCASE AND deleted_at IS NULL",
+        )
+        .bind(&username)
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+
+        if let Some(row) = current {
+            return Ok(Some(row.get("id")));
+        }
+
+        let cutoff = Utc::now() - Duration::days(config.username_redirect_days);
+        let previous = db::query(
+            r#"
+            SELECT h.user_id FROM username_history h
+            JOIN users u ON u.id = h.user_id
+            WHERE h.old_username = ? COLLATE NOCASE AND h.changed_at > ? AND u.deleted_at IS NULL
+            ORDER BY h.changed_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(&username)
+        .bind(cutoff)
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+
+        Ok(previous.map(|row| row.get("user_id")))
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool> {
+        let existing = db::query("SELECT id FROM users WHERE username = ? COLLATE NOCASE")
+            .bind(username)
+            .fetch_optional(self.pool.as_ref())
+            .await?;
+        Ok(existing.is_some())
+    }
+
+    async fn is_username_held(
+        &self,
+        config: &Config,
+        username: &str,
+        claimant: Option<i64>,
+    ) -> Result<bool> {
+        let mut conn = self.pool.acquire().await?;
+        users::is_username_held(&mut conn, config, username, claimant).await
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<(i64, String)>> {
+        // The user and their first session are created together, so a failure
+        // can't leave an account behind that nobody is signed in to
+        let mut tx = self.pool.begin().await?;
+
+        let result = db::query(
+            "INSERT INTO users (username, password_hash, created_at) VALUES (?, ?, ?) RETURNING id",
+        )
+        .bind(username)
+        .bind(password_hash)
+        .bind(Utc::now())
+        .fetch_one(&mut *tx)
+        .await;
+        let user_id = match result {
+            Ok(result) => result.get::<i64, _>("id"),
+            // A concurrent signup can win the race past the caller's check
+            Err(e) if db::is_unique_violation(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let token = auth::create_session(&mut *tx, user_id).await?;
+        tx.commit().await?;
+
+        Ok(Some((user_id, token)))
+    }
+
+    async fn find_login(&self, username: &str) -> Result<Option<Credentials>> {
+        // Bots have no password and can only authenticate with API tokens
+        let row = db::query(
+            "SELECT id, username, password_hash, locked_until FROM users WHERE username = ? COLLATE NOCASE AND deleted_at IS NULL AND is_bot = FALSE",
+        )
+        .bind(normalize_username(username))
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+
+        Ok(row.as_ref().map(credentials_from_row))
+    }
+
+    async fn credentials(&self, user_id: i64) -> Result<Credentials> {
+        let row =
+            db::query("SELECT id, username, password_hash, locked_until FROM users WHERE id = ?")
+                .bind(user_id)
+                .fetch_one(self.pool.as_ref())
+                .await?;
+
+        Ok(credentials_from_row(&row))
+    }
+
+    async fn record_failed_login(&self, config: &Config, user_id: i64) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        users::record_failed_login(&mut conn, config, user_id).await
+    }
+
+    async fn totp(&self, user_id: i64) -> Result<Option<Totp>> {
+        let row = db::query(
+            "SELECT secret, last_used_step FROM user_totp WHERE user_id = ? AND enabled = TRUE",
+        )
+        .bind(user_id)
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+
+        Ok(row.map(|row| Totp {
+            secret: row.get("secret"),
+            last_used_step: row
+                .get::<Option<i64>, _>("last_used_step")
+                .map(|s| s as u64),
+        }))
+    }
+
+    async fn claim_totp_step(&self, user_id: i64, step: u64) -> Result<bool> {
+        let mut conn = self.pool.acquire().await?;
+        users::claim_totp_step(&mut conn, user_id, step).await
+    }
+
+    async fn rename_user(&self, config: &Config, user_id: i64, username: &str) -> Result<Rename> {
+        let mut tx = self.pool.begin().await?;
+
+        let existing_user =
+            db::query("SELECT id FROM users WHERE username = ? COLLATE NOCASE AND id != ?")
+                .bind(username)
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if existing_user.is_some() {
+            return Ok(Rename::Taken);
+        }
+
+        if users::is_username_held(&mut tx, config, username, Some(user_id)).await? {
+            return Ok(Rename::Held);
+        }
+
+        let old_username: String = db::query("SELECT username FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?
+            .get("username");
+
+        let changed_at = Utc::now();
+        let result = db::query("UPDATE users SET username = ? WHERE id = ?")
+            .bind(username)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await;
+        match result {
+            Ok(_) => {}
+            // A concurrent rename or signup can win the race past the check
+            Err(e) if db::is_unique_violation(&e) => return Ok(Rename::Taken),
+            Err(e) => return Err(e),
+        }
+
+        // A change in case only keeps the same name, so there is nothing to
+        // hold or redirect
+        if !old_username.eq_ignore_ascii_case(username) {
+            db::query(
+                "INSERT INTO username_history (user_id, old_username, new_username, changed_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(user_id)
+            .bind(&old_username)
+            .bind(username)
+            .bind(changed_at)
+            .execute(&mut *tx)
+            .await?;
+
+            // Let everyone this user has talked to know about the new name
+            let payload = serde_json::json!({
+                "old_username": old_username,
+                "new_username": username,
+            });
+            db::query(
+                r#"
+                INSERT INTO notifications (user_id, kind, payload, created_at)
+                SELECT DISTINCT
+                    CASE WHEN from_user_id = ? THEN to_user_id ELSE from_user_id END,
+                    'username_changed',
+                    ?,
+                    ?
+                FROM messages
+                WHERE (from_user_id = ? OR to_user_id = ?) AND from_user_id != to_user_id
+                "#,
+            )
+            .bind(user_id)
+            .bind(payload.to_string())
+            .bind(changed_at)
+            .bind(user_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(Rename::Renamed(changed_at))
+    }
+
+    async fn set_password(
+        &self,
+        user_id: i64,
+        password_hash: &str,
+        keep_session: i64,
+    ) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+
+        db::query("UPDATE users SET password_hash = ? WHERE id = ?")
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let revoked = users::revoke_credentials(&mut tx, user_id, Some(keep_session)).await?;
+
+        tx.commit().await?;
+        Ok(revoked)
+    }
+
+    async fn delete_user(&self, config: &Config, user_id: i64) -> Result<()> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        // Bots can't outlive their owner
+        let mut accounts = db::query(
+            "SELECT id, username FROM users WHERE bot_owner_id = ? AND is_bot = TRUE AND deleted_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        accounts.push(
+            db::query("SELECT id, username FROM users WHERE id = ?")
+                .bind(user_id)
+                .fetch_one(&mut *tx)
+                .await?,
+        );
+        for account in accounts {
+            users::erase_account(
+                &mut tx,
+                config,
+                account.get("id"),
+                &account.get::<String, _>("username"),
+                &now,
+            )
+            .await?;
+        }
+
+        tx.commit().await
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqlStore {
+    async fn create_session(&self, user_id: i64) -> Result<String> {
+        auth::create_session(self.pool.as_ref(), user_id).await
+    }
+
+    async fn sign_in(&self, user_id: i64) -> Result<String> {
+        let mut tx = self.pool.begin().await?;
+        users::clear_failed_logins(&mut tx, user_id).await?;
+        let token = auth::create_session(&mut *tx, user_id).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn create_login_challenge(
+        &self,
+        user_id: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<String> {
+        let token = auth::generate_token();
+        db::query(
+            "INSERT INTO login_challenges (user_id, token_hash, expires_at, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(auth::hash_token(&token))
+        .bind(expires_at)
+        .bind(Utc::now())
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(token)
+    }
+
+    async fn find_session(&self, token: &str) -> Result<Option<CurrentSession>> {
+        match auth::get_session_from_token(&self.pool, token).await {
+            Ok(session) => Ok(Some(session)),
+            Err(sqlx::Error::RowNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for SqlStore {
+    async fn insert_message(&self, message: NewMessage<'_>) -> Result<SendMessageResponse> {
+        let created_at = Utc::now();
+        let result = db::query(
+            "INSERT INTO messages (from_user_id, to_user_id, content, encrypted, via_integration, created_at) VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(message.from_user_id)
+        .bind(message.to_user_id)
+        .bind(message.content)
+        .bind(message.encrypted)
+        .bind(message.via_integration)
+        .bind(created_at)
+        .fetch_one(self.pool.as_ref())
+        .await?;
+
+        Ok(SendMessageResponse {
+            message_id: result.get::<i64, _>("id"),
+            created_at,
+        })
+    }
+
+    async fn messages_for(&self, user_id: i64) -> Result<Vec<MessageResponse>> {
+        let rows = db::query(format!(
+            "{} WHERE m.to_user_id = ? OR m.from_user_id = ? ORDER BY m.created_at DESC",
+            MESSAGE_SELECT
+        ))
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_all(self.pool.as_ref())
+        .await?;
+
+        Ok(rows.iter().map(message_from_row).collect())
+    }
+
+    async fn messages_between(&self, user_id: i64, other_id: i64) -> Result<Vec<MessageResponse>> {
+        let rows = db::query(format!(
+            r#"{}
+            WHERE (m.from_user_id = ? AND m.to_user_id = ?)
+               OR (m.from_user_id = ? AND m.to_user_id = ?)
+            ORDER BY m.created_at DESC
+            "#,
+            MESSAGE_SELECT
+        ))
+        .bind(user_id)
+        .bind(other_id)
+        .bind(other_id)
+        .bind(user_id)
+        .fetch_all(self.pool.as_ref())
+        .await?;
+
+        Ok(rows.iter().map(message_from_row).collect())
+    }
+
+    async fn messages_received_after(
+        &self,
+        user_id: i64,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<MessageResponse>> {
+        let rows = db::query(format!(
+            "{} WHERE m.to_user_id = ? AND m.id > ? ORDER BY m.id LIMIT ?",
+            MESSAGE_SELECT
+        ))
+        .bind(user_id)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(self.pool.as_ref())
+        .await?;
+
+        Ok(rows.iter().map(message_from_row).collect())
+    }
+
+    async fn mark_read(&self, reader_id: i64, sender_id: i64) -> Result<u64> {
+        let result = db::query(
+            "UPDATE messages SET read_at = ? WHERE from_user_id = ? AND to_user_id = ? AND read_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(sender_id)
+        .bind(reader_id)
+        .execute(self.pool.as_ref())
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn conversations(&self, user_id: i64) -> Result<Vec<ConversationResponse>> {
+        // Number each conversation's messages newest first and keep the first
+        let rows = db::query(
+            r#"
+            WITH conversation_messages AS (
+                SELECT
+                    m.id,
+                    m.from_user_id,
+                    m.to_user_id,
+                    m.content,
+                    m.created_at,
+                    m.read_at,
+                    CASE WHEN m.from_user_id = ? THEN m.to_user_id ELSE m.from_user_id END as partner_id
+                FROM messages m
+                WHERE m.from_user_id = ? OR m.to_user_id = ?
+            ),
+            ranked AS (
+                SELECT
+                    cm.*,
+                    ROW_NUMBER() OVER (PARTITION BY cm.partner_id ORDER BY cm.created_at DESC, cm.id DESC) as position,
+                    COUNT(CASE WHEN cm.to_user_id = ? AND cm.from_user_id != ? AND cm.read_at IS NULL THEN 1 END)
+                        OVER (PARTITION BY cm.partner_id) as unread_count
+                FROM conversation_messages cm
+            )
+            SELECT
+                partner.username as other_username,
+                partner.display_name as other_display_name,
+                partner.is_bot as other_is_bot,
+                r.content as last_message,
+                r.created_at as last_message_time,
+                r.unread_count,
+                EXISTS(
+                    SELECT 1 FROM conversation_mutes mute
+                    WHERE mute.user_id = ?
+                      AND mute.partner_id = r.partner_id
+                      AND (mute.muted_until IS NULL OR mute.muted_until > ?)
+                ) as muted
+            FROM ranked r
+            JOIN users partner ON partner.id = r.partner_id
+            WHERE r.position = 1
+            ORDER BY r.created_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(self.pool.as_ref())
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| ConversationResponse {
+                username: row.get("other_username"),
+                display_name: row.get("other_display_name"),
+                is_bot: row.get("other_is_bot"),
+                last_message: row.get("last_message"),
+                last_message_time: row.get("last_message_time"),
+                unread_count: row.get("unread_count"),
+                muted: row.get("muted"),
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl KeyStore for SqlStore {
+    async fn replace_key_bundle(&self, user_id: i64, bundle: &KeyBundle) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let existing_keys = db::query("SELECT user_id FROM user_keys WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        if existing_keys.is_some() {
+            db::query(
+                "UPDATE user_keys SET identity_key = ?, signed_prekey = ?, signed_prekey_signature = ? WHERE user_id = ?",
+            )
+            .bind(&bundle.identity_key)
+            .bind(&bundle.signed_prekey)
+            .bind(&bundle.signed_prekey_signature)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+            db::query("DELETE FROM one_time_prekeys WHERE user_id = ?")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        } else {
+            db::query(
+                "INSERT INTO user_keys (user_id, identity_key, signed_prekey, signed_prekey_signature, created_at) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(user_id)
+            .bind(&bundle.identity_key)
+            .bind(&bundle.signed_prekey)
+            .bind(&bundle.signed_prekey_signature)
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // Key ids are the prekeys' positions in the uploaded list
+        let created_at = Utc::now();
+        for (batch, chunk) in bundle
+            .one_time_prekeys
+            .chunks(PREKEY_INSERT_BATCH)
+            .enumerate()
+        {
+            let mut query = db::query(format!(
+                "INSERT INTO one_time_prekeys (user_id, key_id, public_key, used, created_at) VALUES {}",
+                vec!["(?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+            ));
+            for (i, prekey) in chunk.iter().enumerate() {
+                query = query
+                    .bind(user_id)
+                    .bind((batch * PREKEY_INSERT_BATCH + i) as i64)
+                    .bind(prekey)
+                    .bind(false)
+                    .bind(created_at);
+            }
+            query.execute(&mut *tx).await?;
+        }
+
+        tx.commit().await
+    }
+
+    async fn claim_key_bundle(&self, user_id: i64, limit: usize) -> Result<Option<KeyBundle>> {
+        let keys = db::query(
+            "SELECT identity_key, signed_prekey, signed_prekey_signature FROM user_keys WHERE user_id = ?",
+        )
+        .bind(user_id)
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+
+        let Some(keys) = keys else {
+            return Ok(None);
+        };
+
+        // Marked used in the same statement that picks it, so concurrent
+        // fetches never hand out the same prekey. Postgres skips one another
+        // fetch is in the middle of claiming.
+        let skip_locked = match self.pool.backend() {
+            db::Backend::Sqlite => "",
+            db::Backend::Postgres => "FOR UPDATE SKIP LOCKED",
+        };
+        let claimed = db::query(format!(
+            r#"
+            UPDATE one_time_prekeys SET used = ?
+            WHERE id = (
+                SELECT id FROM one_time_prekeys WHERE user_id = ? AND used = ? ORDER BY id LIMIT 1 {}
+            ) AND used = ?
+            RETURNING public_key
+            "#,
+            skip_locked
+        ))
+        .bind(true)
+        .bind(user_id)
+        .bind(false)
+        .bind(false)
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+
+        let mut one_time_prekeys = Vec::new();
+        if let Some(claimed) = claimed {
+            one_time_prekeys.push(claimed.get("public_key"));
+            let rest = db::query(
+                "SELECT public_key FROM one_time_prekeys WHERE user_id = ? AND used = ? ORDER BY id LIMIT ?",
+            )
+            .bind(user_id)
+            .bind(false)
+            .bind(limit.saturating_sub(1) as i64)
+            .fetch_all(self.pool.as_ref())
+            .await?;
+            one_time_prekeys.extend(rest.iter().map(|row| row.get::<String, _>("public_key")));
+        }
+
+        Ok(Some(KeyBundle {
+            identity_key: keys.get("identity_key"),
+            signed_prekey: keys.get("signed_prekey"),
+            signed_prekey_signature: keys.get("signed_prekey_signature"),
+            one_time_prekeys,
+        }))
+    }
+}
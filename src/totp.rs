@@ -0,0 +1,189 @@
+// RFC 6238 time-based one-time passwords (HMAC-SHA1, 6 digits, 30s steps),
+// the defaults every authenticator app supports.
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+// Accept codes from one step either side to tolerate clock drift
+const ALLOWED_SKEW_STEPS: u64 = 1;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32: base32::Alphabet = base32::Alphabet::Rfc4648 { padding: false };
+
+/// Generate a new random secret, base32-encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let secret: [u8; SECRET_BYTES] = rand::thread_rng().gen();
+    base32::encode(BASE32, &secret)
+}
+
+/// The otpauth:// URI that clients render as a QR code for enrollment.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+    )
+}
+
+/// Check `code` against the secret at unix time `now`. Returns the matched
+/// time step, which callers store so the same code can't be replayed; steps
+/// at or before `last_used_step` are rejected.
+pub fn verify(secret: &str, code: &str, now: u64, last_used_step: Option<u64>) -> Option<u64> {
+    let key = base32::decode(BASE32, secret)?;
+    let code: u32 = code.trim().parse().ok()?;
+    let current = now / STEP_SECONDS;
+
+    (current.saturating_sub(ALLOWED_SKEW_STEPS)..=current + ALLOWED_SKEW_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| hotp(&key, *step) == code)
+}
+
+// RFC 4226 HOTP with dynamic truncation
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The code an authenticator app would show at unix time `now`.
+#[cfg(test)]
+pub fn code_at(secret: &str, now: u64) -> String {
+    let key = base32::decode(BASE32, secret).expect("valid base32 secret");
+    format!("{:06}", hotp(&key, now / STEP_SECONDS))
+}
+
+/// Generate recovery codes formatted as `xxxxx-xxxxx` for readability.
+pub fn generate_recovery_codes() -> Vec<String> {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+/// Recovery codes are high-entropy, so a fast hash is enough to keep them
+/// useless if the database leaks. Input is normalized so users can type them
+/// without the dash or in upper case.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The RFC 6238 Appendix B SHA-1 key, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    // Appendix B times with their 8-digit codes; ours are the last 6 digits
+    const RFC_VECTORS: [(u64, &str); 6] = [
+        (59, "94287082"),
+        (1111111109, "07081804"),
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+        (20000000000, "65353130"),
+    ];
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        let key = base32::decode(BASE32, RFC_SECRET).unwrap();
+        assert_eq!(key, b"12345678901234567890");
+
+        for (time, expected) in RFC_VECTORS {
+            let code = &expected[expected.len() - DIGITS as usize..];
+            assert_eq!(format!("{:06}", hotp(&key, time / STEP_SECONDS)), code);
+            assert_eq!(
+                verify(RFC_SECRET, code, time, None),
+                Some(time / STEP_SECONDS),
+                "at {time}"
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift_and_no_replays() {
+        let now = 1111111111;
+        let step = now / STEP_SECONDS;
+        let code = code_at(RFC_SECRET, now);
+
+        assert_eq!(
+            verify(RFC_SECRET, &code, now - STEP_SECONDS, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify(RFC_SECRET, &code, now + STEP_SECONDS, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify(RFC_SECRET, &code, now - 2 * STEP_SECONDS, None),
+            None
+        );
+        assert_eq!(
+            verify(RFC_SECRET, &code, now + 2 * STEP_SECONDS, None),
+            None
+        );
+
+        // Once a step is used, neither it nor earlier codes are accepted again
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(step - 1)), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(step)), None);
+        let earlier = code_at(RFC_SECRET, now - STEP_SECONDS);
+        assert_eq!(verify(RFC_SECRET, &earlier, now, Some(step)), None);
+
+        assert_eq!(verify(RFC_SECRET, "not a code", now, None), None);
+        assert_eq!(verify("not base32!", &code, now, None), None);
+    }
+
+    #[test]
+    fn recovery_codes_hash_the_same_however_they_are_typed() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(&code[5..6], "-");
+        }
+
+        let hash = hash_recovery_code("abcde-fghjk");
+        assert_eq!(hash_recovery_code("ABCDEFGHJK"), hash);
+        assert_eq!(hash_recovery_code(" abcde fghjk "), hash);
+        assert_ne!(hash_recovery_code("abcde-fghjm"), hash);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::config::{Config, DeletedMessagePolicy};
+use crate::db;
+use chrono::{DateTime, Duration, Utc};
+
+/// Whether `username` was recently released by an account other than
+/// `claimant` and is still being held to prevent squatting.
+pub async fn is_username_held(
+    conn: &mut db::Conn,
+    config: &Config,
+    username: &str,
+    claimant: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let cutoff = Utc::now() - Duration::days(config.username_hold_days);
+    let held = db::query(
+        r#"
+        SELECT 1 FROM username_history
+        WHERE old_username = ? COLLATE NOCASE AND changed_at > ? AND user_id IS DISTINCT FROM ?
+        LIMIT 1
+        "#,
+    )
+    .bind(username)
+    .bind(cutoff)
+    .bind(claimant)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(held.is_some())
+}
+
+/// Record that a TOTP code from `step` was accepted. The update only applies
+/// while no equal or later step has been used, so when two requests race with
+/// the same code exactly one of them succeeds.
+pub async fn claim_totp_step(
+    conn: &mut db::Conn,
+    user_id: i64,
+    step: u64,
+) -> Result<bool, sqlx::Error> {
+    let claimed = db::query(
+        "UPDATE user_totp SET last_used_step = ? WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)",
+    )
+    .bind(step as i64)
+    .bind(user_id)
+    .bind(step as i64)
+    .execute(conn)
+    .await?;
+    Ok(claimed.rows_affected() == 1)
+}
+
+/// Placeholder username given to deleted accounts. The `~` can't appear in a
+/// valid username, so it never collides with a real one.
+pub fn deleted_username(user_id: i64) -> String {
+    format!("~deleted-{}", user_id)
+}
+
+/// Count a failed password or two-factor attempt. Past the configured
+/// threshold the account is locked, for twice as long with each further
+/// failure.
+pub async fn record_failed_login(
+    conn: &mut db::Conn,
+    config: &Config,
+    user_id: i64,
+) -> Result<(), sqlx::Error> {
+    let failures: i64 = db::query(
+        "UPDATE users SET failed_login_count = failed_login_count + 1 WHERE id = ? RETURNING failed_login_count",
+    )
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await?
+    .get("failed_login_count");
+
+    if let Some(locked_until) = lockout_until(config, failures) {
+        db::query("UPDATE users SET locked_until = ? WHERE id = ?")
+            .bind(locked_until)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// When an account with `failures` failed logins in a row is locked until,
+/// if it is locked at all.
+pub fn lockout_until(config: &Config, failures: i64) -> Option<DateTime<Utc>> {
+    if config.login_lockout_threshold <= 0 || failures < config.login_lockout_threshold {
+        return None;
+    }
+    let doublings = (failures - config.login_lockout_threshold).min(30) as u32;
+    let minutes = config
+        .login_lockout_minutes
+        .saturating_mul(1 << doublings)
+        .min(config.login_lockout_max_minutes);
+    Some(Utc::now() + Duration::minutes(minutes))
+}
+
+/// Forget an account's failed login attempts and lift any lock, once a
+/// login has succeeded or the password was reset.
+pub async fn clear_failed_logins(conn: &mut db::Conn, user_id: i64) -> Result<(), sqlx::Error> {
+    db::query("UPDATE users SET failed_login_count = 0, locked_until = NULL WHERE id = ?")
+        .bind(user_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Sign an account out after its password changed: end every session but
+/// `keep_session`, revoke its personal access tokens and drop logins waiting
+/// on a two-factor code. Returns how many sessions were ended.
+pub async fn revoke_credentials(
+    conn: &mut db::Conn,
+    user_id: i64,
+    keep_session: Option<i64>,
+) -> Result<u64, sqlx::Error> {
+    let revoked = db::query("DELETE FROM sessions WHERE user_id = ? AND id IS DISTINCT FROM ?")
+        .bind(user_id)
+        .bind(keep_session)
+        .execute(&mut *conn)
+        .await?;
+    for statement in [
+        "DELETE FROM api_tokens WHERE user_id = ?",
+        "DELETE FROM login_challenges WHERE user_id = ?",
+    ] {
+        db::query(statement)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(revoked.rows_affected())
+}
+
+/// Remove an account's credentials and personal data and leave the users row
+/// behind as an anonymized tombstone. Used for account and bot deletion.
+pub async fn erase_account(
+    conn: &mut db::Conn,
+    config: &Config,
+    user_id: i64,
+    username: &str,
+    now: &DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    // Credentials, keys and personal data go away entirely
+    for statement in [
+        "DELETE FROM webhook_deliveries WHERE webhook_id IN (SELECT id FROM webhooks WHERE user_id = ?)",
+        "DELETE FROM webhooks WHERE user_id = ?",
+        // Both ends of a conversation lose the hooks that post into it
+        "DELETE FROM incoming_webhooks WHERE user_id = ?",
+        "DELETE FROM incoming_webhooks WHERE partner_id = ?",
+        "DELETE FROM push_devices WHERE user_id = ?",
+        "DELETE FROM conversation_mutes WHERE user_id = ?",
+        "DELETE FROM conversation_mutes WHERE partner_id = ?",
+        "DELETE FROM sessions WHERE user_id = ?",
+        "DELETE FROM api_tokens WHERE user_id = ?",
+        "DELETE FROM login_challenges WHERE user_id = ?",
+        "DELETE FROM user_totp WHERE user_id = ?",
+        "DELETE FROM totp_recovery_codes WHERE user_id = ?",
+        "DELETE FROM user_keys WHERE user_id = ?",
+        "DELETE FROM one_time_prekeys WHERE user_id = ?",
+        "DELETE FROM user_avatars WHERE user_id = ?",
+        "DELETE FROM notifications WHERE user_id = ?",
+        "DELETE FROM email_tokens WHERE user_id = ?",
+        // Export archives are stored on the job rows
+        "DELETE FROM export_jobs WHERE user_id = ?",
+    ] {
+        db::query(statement)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    if config.deleted_account_messages == DeletedMessagePolicy::Delete {
+        db::query("DELETE FROM messages WHERE from_user_id = ?")
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    // The users row stays as an anonymized tombstone so messages that
+    // reference it keep their foreign keys intact
+    let tombstone = deleted_username(user_id);
+    db::query(
+        r#"
+        UPDATE users SET
+            username = ?,
+            password_hash = '',
+            display_name = NULL,
+            bio = NULL,
+            discoverable = FALSE,
+            bot_webhook_url = NULL,
+            email = NULL,
+            email_verified_at = NULL,
+            deleted_at = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(&tombstone)
+    .bind(now)
+    .bind(user_id)
+    .execute(&mut *conn)
+    .await?;
+
+    // Recording the release holds the old name for the usual cooldown before
+    // anyone else can register it; lookups never redirect to deleted accounts
+    db::query(
+        "INSERT INTO username_history (user_id, old_username, new_username, changed_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(username)
+    .bind(&tombstone)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
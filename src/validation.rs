@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use unicode_normalization::UnicodeNormalization;
+
+pub const MIN_USERNAME_CHARS: usize = 3;
+pub const MAX_USERNAME_CHARS: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum UsernameError {
+    Empty,
+    TooShort,
+    TooLong,
+    InvalidCharacters,
+    InvalidStart,
+    Reserved,
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "Username cannot be empty"),
+            UsernameError::TooShort => write!(
+                f,
+                "Username must be at least {} characters",
+                MIN_USERNAME_CHARS
+            ),
+            UsernameError::TooLong => write!(
+                f,
+                "Username must be at most {} characters",
+                MAX_USERNAME_CHARS
+            ),
+            UsernameError::InvalidCharacters => write!(
+                f,
+                "Username may only contain letters, digits, '_', '.' and '-'"
+            ),
+            UsernameError::InvalidStart => {
+                write!(f, "Username must start with a letter or digit")
+            }
+            UsernameError::Reserved => write!(f, "Username is reserved"),
+        }
+    }
+}
+
+/// NFKC-normalize a username so visually identical forms (full-width
+/// letters, ligatures) compare equal. Used for both registration and lookups.
+pub fn normalize_username(raw: &str) -> String {
+    raw.trim().nfkc().collect()
+}
+
+/// Validate a requested username, returning the normalized form to store.
+///
+/// Only ASCII letters, digits, '_', '.' and '-' are allowed after
+/// normalization, which also rules out homoglyphs from other scripts.
+/// Uniqueness is case-insensitive and enforced by the schema.
+pub fn validate_username(raw: &str, reserved: &[String]) -> Result<String, UsernameError> {
+    let username = normalize_username(raw);
+    let len = username.chars().count();
+
+    if len == 0 {
+        return Err(UsernameError::Empty);
+    }
+    if len < MIN_USERNAME_CHARS {
+        return Err(UsernameError::TooShort);
+    }
+    if len > MAX_USERNAME_CHARS {
+        return Err(UsernameError::TooLong);
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err(UsernameError::InvalidCharacters);
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(UsernameError::InvalidStart);
+    }
+
+    let lowered = username.to_ascii_lowercase();
+    if reserved.contains(&lowered) {
+        return Err(UsernameError::Reserved);
+    }
+
+    Ok(username)
+}
+
+// bcrypt silently ignores everything past 72 bytes
+pub const MAX_PASSWORD_BYTES: usize = 72;
+
+static COMMON_PASSWORDS: LazyLock<HashSet<&'static str>> =
+    LazyLock::new(|| include_str!("common_passwords.txt").lines().collect());
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordError {
+    TooShort(usize),
+    TooLong,
+    Common,
+    SameAsUsername,
+}
+
+impl std::fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordError::TooShort(min) => {
+                write!(f, "Password must be at least {} characters", min)
+            }
+            PasswordError::TooLong => {
+                write!(f, "Password must be at most {} bytes", MAX_PASSWORD_BYTES)
+            }
+            PasswordError::Common => write!(f, "Password is too common"),
+            PasswordError::SameAsUsername => write!(f, "Password cannot be the username"),
+        }
+    }
+}
+
+/// Check a new password against the configured policy.
+pub fn validate_password(
+    password: &str,
+    username: &str,
+    min_length: usize,
+    check_common: bool,
+) -> Result<(), PasswordError> {
+    if password.chars().count() < min_length {
+        return Err(PasswordError::TooShort(min_length));
+    }
+    if password.len() > MAX_PASSWORD_BYTES {
+        return Err(PasswordError::TooLong);
+    }
+
+    let lowered = password.to_lowercase();
+    if check_common && COMMON_PASSWORDS.contains(lowered.as_str()) {
+        return Err(PasswordError::Common);
+    }
+    if lowered == username.to_lowercase() {
+        return Err(PasswordError::SameAsUsername);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_are_nfkc_normalized() {
+        // Full-width letters and ligatures fold to their plain forms
+        assert_eq!(normalize_username(" ａｌｉｃｅ "), "alice");
+        assert_eq!(normalize_username("ﬁsh"), "fish");
+        assert_eq!(validate_username("Ａｌｉｃｅ", &[]).unwrap(), "Alice");
+    }
+
+    #[test]
+    fn usernames_allow_only_ascii_letters_digits_and_separators() {
+        assert_eq!(validate_username("a.b-c_1", &[]).unwrap(), "a.b-c_1");
+        // The first letter is Cyrillic
+        assert_eq!(
+            validate_username("аlice", &[]),
+            Err(UsernameError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_username("al ice", &[]),
+            Err(UsernameError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_username("_alice", &[]),
+            Err(UsernameError::InvalidStart)
+        );
+        assert_eq!(validate_username("  ", &[]), Err(UsernameError::Empty));
+        assert_eq!(validate_username("al", &[]), Err(UsernameError::TooShort));
+        assert_eq!(
+            validate_username(&"a".repeat(MAX_USERNAME_CHARS + 1), &[]),
+            Err(UsernameError::TooLong)
+        );
+    }
+
+    #[test]
+    fn reserved_usernames_are_rejected_in_any_case() {
+        let reserved = vec!["admin".to_string()];
+        assert_eq!(
+            validate_username("Admin", &reserved),
+            Err(UsernameError::Reserved)
+        );
+        assert_eq!(
+            validate_username("ＡＤＭＩＮ", &reserved),
+            Err(UsernameError::Reserved)
+        );
+        assert!(validate_username("admins", &reserved).is_ok());
+    }
+}
@@ -0,0 +1,735 @@
+// Outgoing webhooks: events are turned into rows in webhook_deliveries, and a
+// background worker POSTs them, signed, retrying with exponential backoff.
+use crate::config::Config;
+use crate::db::{self, DbPool};
+use crate::events::{self, Event, EventHub};
+use crate::outbound::OutboundClient;
+use crate::shutdown::Shutdown;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+pub const EVENT_MESSAGE_CREATED: &str = "message.created";
+pub const EVENT_MESSAGE_READ: &str = "message.read";
+pub const EVENT_KEYS_UPDATED: &str = "keys.updated";
+/// Sent on request to check an endpoint; not subscribable
+pub const EVENT_PING: &str = "ping";
+
+pub const EVENT_TYPES: [&str; 3] = [EVENT_MESSAGE_CREATED, EVENT_MESSAGE_READ, EVENT_KEYS_UPDATED];
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_IN_FLIGHT: &str = "in_flight";
+pub const STATUS_DELIVERED: &str = "delivered";
+pub const STATUS_FAILED: &str = "failed";
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_BATCH: i64 = 20;
+// Retries become due without any new event arriving, so the queue is also
+// checked on a timer
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_BACKOFF_SECS: i64 = 3600;
+// A delivery still in flight after this long was claimed by a machine that
+// stopped mid-batch, and is sent again
+const LEASE_SECS: i64 = 60;
+const LOG_RETENTION_DAYS: i64 = 7;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+pub fn generate_secret() -> String {
+    format!("whsec_{}", crate::auth::generate_token())
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`, sent as `X-MigChat-Signature: sha256=...`.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Handle for waking the delivery worker when new deliveries are queued.
+#[derive(Clone, Default)]
+pub struct DeliveryQueue {
+    notify: Arc<Notify>,
+}
+
+impl DeliveryQueue {
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Start the task that queues deliveries for published events and the worker
+/// that sends them. Queued deliveries are kept in the database, so the worker
+/// only finishes its current batch on shutdown.
+pub fn spawn(
+    pool: DbPool,
+    config: Arc<Config>,
+    events: EventHub,
+    queue: DeliveryQueue,
+    shutdown: Shutdown,
+) {
+    let mut receiver = events.subscribe_local();
+    let enqueue_pool = pool.clone();
+    let enqueue_queue = queue.clone();
+    let enqueue_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        loop {
+            match events::next_event(&mut receiver, &enqueue_shutdown).await {
+                Ok(event) => match enqueue_event(&enqueue_pool, &event).await {
+                    Ok(0) => {}
+                    Ok(_) => enqueue_queue.wake(),
+                    Err(e) => tracing::error!("Failed to queue webhooks for {:?}: {}", event, e),
+                },
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::error!("Webhook queue skipped {} events", missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    shutdown.spawn(run_worker(pool, config, queue, shutdown.clone()));
+}
+
+/// Queue `payload` for every active webhook subscribed to `event` that belongs
+/// to one of `user_ids` or is global. Global webhooks get it without message
+/// content. Returns the number of deliveries queued.
+pub async fn enqueue(
+    pool: &DbPool,
+    event: &str,
+    user_ids: &[i64],
+    data: Value,
+) -> Result<u64, sqlx::Error> {
+    let hooks = db::query(
+        "SELECT id, user_id FROM webhooks WHERE active = TRUE AND (',' || events || ',') LIKE ('%,' || ? || ',%')",
+    )
+    .bind(event)
+    .fetch_all(pool.as_ref())
+    .await?;
+
+    // Admins see every conversation through a global webhook, but only who
+    // talked to whom; what was said stays with the people involved
+    let mut global_data = data.clone();
+    if let Some(fields) = global_data.as_object_mut() {
+        fields.remove("content");
+    }
+
+    let now = Utc::now();
+    let mut queued = 0;
+    for hook in hooks {
+        let owner: Option<i64> = hook.get("user_id");
+        let data = match owner {
+            Some(owner) if !user_ids.contains(&owner) => continue,
+            Some(_) => &data,
+            None => &global_data,
+        };
+        queue_delivery(pool, hook.get("id"), event, data, &now).await?;
+        queued += 1;
+    }
+
+    Ok(queued)
+}
+
+/// Queue a delivery to a single webhook regardless of its subscriptions.
+pub async fn queue_delivery(
+    pool: &DbPool,
+    webhook_id: i64,
+    event: &str,
+    data: &Value,
+    now: &DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let delivery_id = db::query(
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload, status, next_attempt_at, created_at) VALUES (?, ?, '', ?, ?, ?) RETURNING id",
+    )
+    .bind(webhook_id)
+    .bind(event)
+    .bind(STATUS_PENDING)
+    .bind(now)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await?
+    .get::<i64, _>("id");
+
+    // The payload names its own delivery id so receivers can de-duplicate retries
+    let payload = json!({
+        "id": delivery_id,
+        "event": event,
+        "created_at": now,
+        "data": data,
+    });
+    db::query("UPDATE webhook_deliveries SET payload = ? WHERE id = ?")
+        .bind(payload.to_string())
+        .bind(delivery_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(delivery_id)
+}
+
+async fn enqueue_event(pool: &DbPool, event: &Event) -> Result<u64, sqlx::Error> {
+    match *event {
+        Event::MessageCreated {
+            message_id,
+            from_user_id,
+            to_user_id,
+        } => {
+            let row = db::query(
+                r#"
+                SELECT
+                    m.id,
+                    m.content,
+                    m.encrypted,
+                    m.via_integration,
+                    m.created_at,
+                    from_user.username as from_username,
+                    to_user.username as to_username
+                FROM messages m
+                JOIN users from_user ON m.from_user_id = from_user.id
+                JOIN users to_user ON m.to_user_id = to_user.id
+                WHERE m.id = ?
+                "#,
+            )
+            .bind(message_id)
+            .fetch_one(pool.as_ref())
+            .await?;
+
+            let data = json!({
+                "id": row.get::<i64, _>("id"),
+                "from_username": row.get::<String, _>("from_username"),
+                "to_username": row.get::<String, _>("to_username"),
+                "content": row.get::<String, _>("content"),
+                "encrypted": row.get::<bool, _>("encrypted"),
+                "via_integration": row.get::<Option<String>, _>("via_integration"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at"),
+            });
+            enqueue(pool, EVENT_MESSAGE_CREATED, &[from_user_id, to_user_id], data).await
+        }
+        Event::MessagesRead {
+            reader_id,
+            sender_id,
+            count,
+        } => {
+            let data = json!({
+                "reader_username": username(pool, reader_id).await?,
+                "sender_username": username(pool, sender_id).await?,
+                "count": count,
+            });
+            enqueue(pool, EVENT_MESSAGE_READ, &[reader_id, sender_id], data).await
+        }
+        Event::KeysUpdated { user_id } => {
+            // Contacts care too: a changed identity key needs re-verifying
+            let mut user_ids: Vec<i64> = db::query(
+                r#"
+                SELECT DISTINCT CASE WHEN from_user_id = ? THEN to_user_id ELSE from_user_id END as partner_id
+                FROM messages
+                WHERE from_user_id = ? OR to_user_id = ?
+                "#,
+            )
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(pool.as_ref())
+            .await?
+            .iter()
+            .map(|row| row.get("partner_id"))
+            .collect();
+            user_ids.push(user_id);
+
+            let data = json!({ "username": username(pool, user_id).await? });
+            enqueue(pool, EVENT_KEYS_UPDATED, &user_ids, data).await
+        }
+    }
+}
+
+async fn username(pool: &DbPool, user_id: i64) -> Result<String, sqlx::Error> {
+    Ok(db::query("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await?
+        .get("username"))
+}
+
+async fn run_worker(pool: DbPool, config: Arc<Config>, queue: DeliveryQueue, shutdown: Shutdown) {
+    let client = OutboundClient::new(DELIVERY_TIMEOUT, config.allow_private_endpoints);
+    let mut last_prune: Option<Instant> = None;
+
+    loop {
+        match deliver_due(&pool, &config, &client).await {
+            // A full batch means more may be waiting
+            Ok(sent) if sent >= DELIVERY_BATCH as usize && !shutdown.is_stopping() => continue,
+            Ok(_) => {}
+            Err(e) => tracing::error!("Webhook delivery failed: {}", e),
+        }
+
+        if last_prune.is_none_or(|t| t.elapsed() >= PRUNE_INTERVAL) {
+            if let Err(e) = prune_log(&pool).await {
+                tracing::error!("Failed to prune webhook delivery log: {}", e);
+            }
+            last_prune = Some(Instant::now());
+        }
+
+        tokio::select! {
+            _ = queue.notify.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = shutdown.stopping() => return,
+        }
+    }
+}
+
+async fn deliver_due(
+    pool: &DbPool,
+    config: &Config,
+    client: &OutboundClient,
+) -> Result<usize, sqlx::Error> {
+    let due = claim_due(pool, Utc::now()).await?;
+
+    let count = due.len();
+    let mut tasks = tokio::task::JoinSet::new();
+    for row in due {
+        let (pool, client) = (pool.clone(), client.clone());
+        let (max_attempts, retry_base_secs) =
+            (config.webhook_max_attempts, config.webhook_retry_base_secs);
+        tasks.spawn(async move {
+            let delivery_id: i64 = row.get("id");
+            let webhook = db::query("SELECT url, secret FROM webhooks WHERE id = ?")
+                .bind(row.get::<i64, _>("webhook_id"))
+                .fetch_optional(pool.as_ref())
+                .await?;
+            // The webhook was deleted after the delivery was claimed
+            let Some(webhook) = webhook else {
+                db::query("DELETE FROM webhook_deliveries WHERE id = ?")
+                    .bind(delivery_id)
+                    .execute(pool.as_ref())
+                    .await?;
+                return Ok(());
+            };
+
+            let attempts: i64 = row.get::<i64, _>("attempts") + 1;
+            let outcome = post(
+                &client,
+                &webhook.get::<String, _>("url"),
+                &webhook.get::<String, _>("secret"),
+                delivery_id,
+                &row.get::<String, _>("event"),
+                &row.get::<String, _>("payload"),
+            )
+            .await;
+            record_attempt(&pool, delivery_id, attempts, outcome, max_attempts, retry_base_secs)
+                .await
+        });
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Failed to record webhook delivery: {}", e),
+            Err(e) => tracing::error!("Webhook delivery task failed: {}", e),
+        }
+    }
+
+    Ok(count)
+}
+
+// Marks up to a batch of due deliveries as in flight in a single statement,
+// so machines sharing the database never send the same one at once. Postgres
+// skips rows another machine is in the middle of claiming.
+async fn claim_due(pool: &DbPool, now: DateTime<Utc>) -> Result<Vec<db::Row>, sqlx::Error> {
+    let skip_locked = match pool.backend() {
+        db::Backend::Sqlite => "",
+        db::Backend::Postgres => "FOR UPDATE SKIP LOCKED",
+    };
+    db::query(format!(
+        r#"
+        UPDATE webhook_deliveries SET status = ?, locked_until = ?
+        WHERE id IN (
+            SELECT id FROM webhook_deliveries
+            WHERE (status = ? AND next_attempt_at <= ?) OR (status = ? AND locked_until <= ?)
+            ORDER BY next_attempt_at
+            LIMIT ?
+            {}
+        )
+        RETURNING id, webhook_id, event, payload, attempts
+        "#,
+        skip_locked
+    ))
+    .bind(STATUS_IN_FLIGHT)
+    .bind(now + chrono::Duration::seconds(LEASE_SECS))
+    .bind(STATUS_PENDING)
+    .bind(now)
+    .bind(STATUS_IN_FLIGHT)
+    .bind(now)
+    .bind(DELIVERY_BATCH)
+    .fetch_all(pool.as_ref())
+    .await
+}
+
+// Status code (if the endpoint answered) and error description on failure
+type AttemptOutcome = Result<u16, (Option<u16>, String)>;
+
+async fn post(
+    client: &OutboundClient,
+    url: &str,
+    secret: &str,
+    delivery_id: i64,
+    event: &str,
+    body: &str,
+) -> AttemptOutcome {
+    let timestamp = Utc::now().timestamp();
+    let response = client
+        .post(url)
+        .map_err(|e| (None, e))?
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-MigChat-Event", event)
+        .header("X-MigChat-Delivery", delivery_id.to_string())
+        .header("X-MigChat-Timestamp", timestamp.to_string())
+        .header(
+            "X-MigChat-Signature",
+            format!("sha256={}", sign(secret, timestamp, body)),
+        )
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err((Some(status.as_u16()), format!("Endpoint answered {}", status)))
+    }
+}
+
+async fn record_attempt(
+    pool: &DbPool,
+    delivery_id: i64,
+    attempts: i64,
+    outcome: AttemptOutcome,
+    max_attempts: i64,
+    retry_base_secs: i64,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    match outcome {
+        Ok(status_code) => {
+            db::query(
+                "UPDATE webhook_deliveries SET status = ?, attempts = ?, last_status_code = ?, last_error = NULL, next_attempt_at = NULL, locked_until = NULL, delivered_at = ? WHERE id = ?",
+            )
+            .bind(STATUS_DELIVERED)
+            .bind(attempts)
+            .bind(status_code as i64)
+            .bind(now)
+            .bind(delivery_id)
+            .execute(pool.as_ref())
+            .await?;
+        }
+        Err((status_code, error)) => {
+            // Waits double after each failure: base, 2x base, 4x base, ...
+            let (status, next_attempt_at) = if attempts >= max_attempts {
+                (STATUS_FAILED, None)
+            } else {
+                let doublings = (attempts - 1).min(30) as u32;
+                let delay = retry_base_secs
+                    .saturating_mul(1 << doublings)
+                    .min(MAX_BACKOFF_SECS);
+                let next = now + chrono::Duration::seconds(delay);
+                (STATUS_PENDING, Some(next))
+            };
+            db::query(
+                "UPDATE webhook_deliveries SET status = ?, attempts = ?, last_status_code = ?, last_error = ?, next_attempt_at = ?, locked_until = NULL WHERE id = ?",
+            )
+            .bind(status)
+            .bind(attempts)
+            .bind(status_code.map(i64::from))
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(delivery_id)
+            .execute(pool.as_ref())
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn prune_log(pool: &DbPool) -> Result<(), sqlx::Error> {
+    let cutoff = Utc::now() - chrono::Duration::days(LOG_RETENTION_DAYS);
+    db::query("DELETE FROM webhook_deliveries WHERE status IN (?, ?) AND created_at < ?")
+        .bind(STATUS_DELIVERED)
+        .bind(STATUS_FAILED)
+        .bind(cutoff)
+        .execute(pool.as_ref())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, StatusCode};
+    use std::future::IntoFuture;
+    use tokio::sync::mpsc;
+
+    const SECRET: &str = "whsec_test";
+
+    // An endpoint on a local port that answers every POST with `status` and
+    // passes on what it received
+    async fn endpoint(
+        status: StatusCode,
+    ) -> (String, mpsc::UnboundedReceiver<(HeaderMap, String)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |headers: HeaderMap, body: String| async move {
+                sender.send((headers, body)).unwrap();
+                status
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, app).into_future());
+        (url, receiver)
+    }
+
+    async fn webhook_with_delivery(pool: &DbPool, url: &str) -> i64 {
+        let webhook_id: i64 = db::query(
+            "INSERT INTO webhooks (url, secret, events, created_at) VALUES (?, ?, ?, ?) RETURNING id",
+        )
+        .bind(url)
+        .bind(SECRET)
+        .bind(EVENT_MESSAGE_CREATED)
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id");
+        queue_delivery(pool, webhook_id, EVENT_PING, &json!({}), &Utc::now())
+            .await
+            .unwrap()
+    }
+
+    async fn delivery(pool: &DbPool, delivery_id: i64) -> db::Row {
+        db::query("SELECT status, attempts, last_status_code, next_attempt_at FROM webhook_deliveries WHERE id = ?")
+            .bind(delivery_id)
+            .fetch_one(pool.as_ref())
+            .await
+            .unwrap()
+    }
+
+    fn test_config() -> Config {
+        Config {
+            webhook_max_attempts: 3,
+            webhook_retry_base_secs: 30,
+            ..Config::from_env()
+        }
+    }
+
+    fn local_client() -> OutboundClient {
+        OutboundClient::new(DELIVERY_TIMEOUT, true)
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let pool = db::test_pool().await;
+        let (url, mut received) = endpoint(StatusCode::NO_CONTENT).await;
+        let delivery_id = webhook_with_delivery(&pool, &url).await;
+
+        assert_eq!(
+            deliver_due(&pool, &test_config(), &local_client())
+                .await
+                .unwrap(),
+            1
+        );
+
+        let (headers, body) = received.recv().await.unwrap();
+        let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+        assert_eq!(header("x-migchat-event"), EVENT_PING);
+        assert_eq!(header("x-migchat-delivery"), delivery_id.to_string());
+
+        let signature = header("x-migchat-signature");
+        let signature = signature.strip_prefix("sha256=").unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", header("x-migchat-timestamp"), body).as_bytes());
+        let expected: Vec<u8> = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap())
+            .collect();
+        mac.verify_slice(&expected).unwrap();
+
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["id"], delivery_id);
+        assert_eq!(payload["event"], EVENT_PING);
+
+        let row = delivery(&pool, delivery_id).await;
+        assert_eq!(row.get::<String, _>("status"), STATUS_DELIVERED);
+        assert_eq!(row.get::<Option<i64>, _>("last_status_code"), Some(204));
+    }
+
+    #[tokio::test]
+    async fn failures_back_off_until_the_attempt_limit() {
+        let pool = db::test_pool().await;
+        let config = test_config();
+        let (url, mut received) = endpoint(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let delivery_id = webhook_with_delivery(&pool, &url).await;
+
+        // Waits double: 30s after the first failure, 60s after the second
+        for (attempt, delay) in [(1, 30), (2, 60)] {
+            let before = Utc::now();
+            deliver_due(&pool, &config, &local_client()).await.unwrap();
+            assert!(received.recv().await.is_some());
+
+            let row = delivery(&pool, delivery_id).await;
+            assert_eq!(row.get::<String, _>("status"), STATUS_PENDING);
+            assert_eq!(row.get::<i64, _>("attempts"), attempt);
+            assert_eq!(row.get::<Option<i64>, _>("last_status_code"), Some(500));
+            let next: DateTime<Utc> = row
+                .get::<Option<DateTime<Utc>>, _>("next_attempt_at")
+                .unwrap();
+            let wait = (next - before).num_seconds();
+            assert!((delay..=delay + 1).contains(&wait), "waited {wait}s");
+
+            // Not due yet
+            assert_eq!(
+                deliver_due(&pool, &config, &local_client()).await.unwrap(),
+                0
+            );
+            db::query("UPDATE webhook_deliveries SET next_attempt_at = ? WHERE id = ?")
+                .bind(Utc::now())
+                .bind(delivery_id)
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+        }
+
+        deliver_due(&pool, &config, &local_client()).await.unwrap();
+        let row = delivery(&pool, delivery_id).await;
+        assert_eq!(row.get::<String, _>("status"), STATUS_FAILED);
+        assert_eq!(row.get::<i64, _>("attempts"), 3);
+        assert_eq!(row.get::<Option<DateTime<Utc>>, _>("next_attempt_at"), None);
+    }
+
+    #[tokio::test]
+    async fn claimed_deliveries_are_not_claimed_again_until_the_lease_ends() {
+        let pool = db::test_pool().await;
+        let delivery_id = webhook_with_delivery(&pool, "http://127.0.0.1:9/hook").await;
+        let now = Utc::now();
+
+        let claimed = claim_due(&pool, now).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].get::<i64, _>("id"), delivery_id);
+        assert_eq!(
+            delivery(&pool, delivery_id)
+                .await
+                .get::<String, _>("status"),
+            STATUS_IN_FLIGHT
+        );
+        assert!(claim_due(&pool, now).await.unwrap().is_empty());
+
+        // The machine that claimed it stopped before recording an attempt
+        let later = now + chrono::Duration::seconds(LEASE_SECS + 1);
+        assert_eq!(claim_due(&pool, later).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deliveries_to_deleted_webhooks_are_dropped_without_holding_up_others() {
+        let pool = db::test_pool().await;
+        let (url, mut received) = endpoint(StatusCode::NO_CONTENT).await;
+        let kept_id = webhook_with_delivery(&pool, &url).await;
+        let dropped_id = webhook_with_delivery(&pool, &url).await;
+        // Deleted between being queued and being sent
+        db::query("PRAGMA foreign_keys = OFF")
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+        db::query("DELETE FROM webhooks WHERE id = (SELECT webhook_id FROM webhook_deliveries WHERE id = ?)")
+            .bind(dropped_id)
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+
+        deliver_due(&pool, &test_config(), &local_client())
+            .await
+            .unwrap();
+
+        assert!(received.recv().await.is_some());
+        assert_eq!(
+            delivery(&pool, kept_id).await.get::<String, _>("status"),
+            STATUS_DELIVERED
+        );
+        let dropped = db::query("SELECT 1 FROM webhook_deliveries WHERE id = ?")
+            .bind(dropped_id)
+            .fetch_optional(pool.as_ref())
+            .await
+            .unwrap();
+        assert!(dropped.is_none());
+    }
+
+    #[tokio::test]
+    async fn global_webhooks_get_messages_without_their_content() {
+        let pool = db::test_pool().await;
+        let user_id: i64 = db::query(
+            "INSERT INTO users (username, password_hash, created_at) VALUES ('alice', 'x', ?) RETURNING id",
+        )
+        .bind(Utc::now())
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap()
+        .get("id");
+        for owner in [Some(user_id), None] {
+            db::query(
+                "INSERT INTO webhooks (user_id, url, secret, events, created_at) VALUES (?, 'https://example.com/hook', ?, ?, ?)",
+            )
+            .bind(owner)
+            .bind(SECRET)
+            .bind(EVENT_MESSAGE_CREATED)
+            .bind(Utc::now())
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+        }
+
+        let data = json!({ "id": 1, "from_username": "alice", "content": "hello" });
+        let queued = enqueue(&pool, EVENT_MESSAGE_CREATED, &[user_id], data)
+            .await
+            .unwrap();
+        assert_eq!(queued, 2);
+
+        let rows = db::query(
+            "SELECT w.user_id, d.payload FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id",
+        )
+        .fetch_all(pool.as_ref())
+        .await
+        .unwrap();
+        for row in rows {
+            let payload: Value = serde_json::from_str(&row.get::<String, _>("payload")).unwrap();
+            assert_eq!(payload["data"]["from_username"], "alice");
+            match row.get::<Option<i64>, _>("user_id") {
+                Some(_) => assert_eq!(payload["data"]["content"], "hello"),
+                None => assert!(payload["data"].get("content").is_none()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn private_endpoints_are_not_contacted() {
+        let pool = db::test_pool().await;
+        let (url, mut received) = endpoint(StatusCode::NO_CONTENT).await;
+        let delivery_id = webhook_with_delivery(&pool, &url).await;
+        let client = OutboundClient::new(DELIVERY_TIMEOUT, false);
+
+        deliver_due(&pool, &test_config(), &client).await.unwrap();
+
+        let row = delivery(&pool, delivery_id).await;
+        assert_eq!(row.get::<String, _>("status"), STATUS_PENDING);
+        assert_eq!(row.get::<Option<i64>, _>("last_status_code"), None);
+        assert!(received.try_recv().is_err());
+    }
+}